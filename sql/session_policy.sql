@@ -0,0 +1,74 @@
+--
+-- # Session Policy Rules
+--
+-- The masking policy of a session can be selected by the context of the
+-- connection instead of the security label of the role. The rules are
+-- evaluated once, with the first query of the session, and again when they
+-- are modified.
+--
+-- A rule matches when all its non-NULL conditions are true. When several
+-- rules match, the rule with the lowest id wins.
+--
+
+CREATE TABLE anon.session_policy_rules (
+  id SERIAL PRIMARY KEY,
+  policy TEXT NOT NULL,
+  role REGROLE,
+  application_name TEXT,
+  client_addr CIDR,
+  database NAME
+);
+
+COMMENT ON TABLE anon.session_policy_rules
+IS 'Masking policies selected by the session user, the application_name pattern, the client address or the database';
+
+--
+-- Returns the policy of the first rule matching the current session
+--
+CREATE OR REPLACE FUNCTION anon.match_session_policy_rules()
+RETURNS TEXT AS
+$$
+  SELECT r.policy
+  FROM anon.session_policy_rules r
+  WHERE (r.role IS NULL OR r.role = session_user::pg_catalog.regrole)
+  AND (r.application_name IS NULL
+       OR pg_catalog.current_setting('application_name') LIKE r.application_name)
+  AND (r.client_addr IS NULL OR pg_catalog.inet_client_addr() <<= r.client_addr)
+  AND (r.database IS NULL OR r.database = pg_catalog.current_database())
+  ORDER BY r.id
+  LIMIT 1;
+$$
+  LANGUAGE SQL
+  STABLE
+  PARALLEL SAFE
+  SECURITY DEFINER
+  SET search_path=''
+;
+
+SECURITY LABEL FOR anon ON FUNCTION anon.match_session_policy_rules IS 'UNTRUSTED';
+
+--
+-- The policies resolved by the sessions are invalidated when the rules are
+-- modified
+--
+CREATE OR REPLACE FUNCTION anon.session_policy_rules_modified()
+RETURNS TRIGGER AS
+$$
+BEGIN
+  PERFORM anon.invalidate_session_policy_rules();
+  RETURN NULL;
+END;
+$$
+  LANGUAGE plpgsql
+  VOLATILE
+  PARALLEL UNSAFE
+  SECURITY DEFINER
+  SET search_path=''
+;
+
+REVOKE ALL ON FUNCTION anon.session_policy_rules_modified FROM PUBLIC;
+
+CREATE TRIGGER session_policy_rules_modified
+AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON anon.session_policy_rules
+FOR EACH STATEMENT
+EXECUTE FUNCTION anon.session_policy_rules_modified();
@@ -0,0 +1,159 @@
+--
+-- # Temporary Unmasking
+--
+-- During an incident, a superuser may reveal the authentic data of a table to
+-- a masked role for a limited period of time. The exemption is honored by the
+-- transparent dynamic masking engine until it expires. The exemptions and
+-- each access to the unmasked table are recorded in the
+-- `anon.unmasking_audit` table. The accesses are also copied in the server
+-- log.
+--
+-- The exemptions and the audit events are not visible to the masked roles.
+--
+
+CREATE TABLE anon.unmasking (
+  roleid REGROLE NOT NULL,
+  relid REGCLASS NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  reason TEXT NOT NULL,
+  granted_by NAME NOT NULL DEFAULT session_user,
+  granted_at TIMESTAMPTZ NOT NULL DEFAULT pg_catalog.now(),
+  PRIMARY KEY (roleid, relid)
+);
+
+COMMENT ON TABLE anon.unmasking
+IS 'Tables temporarily unmasked for a masked role';
+
+CREATE TABLE anon.unmasking_audit (
+  event_time TIMESTAMPTZ NOT NULL DEFAULT pg_catalog.clock_timestamp(),
+  event TEXT NOT NULL,
+  role_name NAME NOT NULL,
+  table_name TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  reason TEXT NOT NULL,
+  session_user_name NAME NOT NULL DEFAULT session_user,
+  query TEXT
+);
+
+COMMENT ON TABLE anon.unmasking_audit
+IS 'Temporary unmasking events: UNMASK when an exemption is granted, ACCESS when an unmasked table is read';
+
+--
+-- Reveal the authentic data of a table to a masked role until the exemption
+-- expires. Granting a new exemption for the same role and table replaces the
+-- previous one.
+--
+CREATE OR REPLACE FUNCTION anon.unmask_temporarily(
+  role REGROLE,
+  tablename REGCLASS,
+  duration INTERVAL,
+  reason TEXT
+)
+RETURNS TIMESTAMPTZ AS
+$$
+DECLARE
+  expiry TIMESTAMPTZ := pg_catalog.now() + duration;
+BEGIN
+  IF duration <= '0'::INTERVAL THEN
+    RAISE EXCEPTION 'The duration must be positive';
+  END IF;
+
+  IF pg_catalog.btrim(reason) = '' THEN
+    RAISE EXCEPTION 'A reason is required to unmask a table';
+  END IF;
+
+  -- The expired exemptions are removed
+  DELETE FROM anon.unmasking WHERE expires_at <= pg_catalog.now();
+
+  INSERT INTO anon.unmasking(roleid, relid, expires_at, reason)
+  VALUES (role, tablename, expiry, reason)
+  ON CONFLICT (roleid, relid) DO UPDATE
+  SET expires_at = EXCLUDED.expires_at,
+      reason = EXCLUDED.reason,
+      granted_by = EXCLUDED.granted_by,
+      granted_at = EXCLUDED.granted_at;
+
+  INSERT INTO anon.unmasking_audit(event, role_name, table_name, expires_at, reason)
+  VALUES ('UNMASK', role::TEXT, tablename::TEXT, expiry, reason);
+
+  -- The guard of the unmasked tables is not granted to PUBLIC
+  EXECUTE pg_catalog.format(
+    'GRANT EXECUTE ON FUNCTION anon.check_unmasking(pg_catalog.oid, pg_catalog.oid) TO %s',
+    role
+  );
+  EXECUTE pg_catalog.format(
+    'GRANT EXECUTE ON FUNCTION anon.log_unmasked_access(REGROLE, REGCLASS, TEXT) TO %s',
+    role
+  );
+
+  RETURN expiry;
+END
+$$
+  LANGUAGE plpgsql
+  VOLATILE
+  STRICT
+  PARALLEL UNSAFE -- because of INSERT
+  SECURITY DEFINER
+  SET search_path=''
+;
+
+REVOKE ALL ON FUNCTION anon.unmask_temporarily(REGROLE, REGCLASS, INTERVAL, TEXT) FROM PUBLIC;
+
+--
+-- The functions below are called by the masking engine on behalf of the
+-- masked roles
+--
+
+-- The valid exemption of the role, or of a role it belongs to, on the table.
+-- The guard of the unmasked tables is `anon.check_unmasking()`, it records
+-- each access with `anon.log_unmasked_access()`.
+--
+-- The session user can only read the exemptions of the roles it belongs to,
+-- the other exemptions are not visible.
+CREATE OR REPLACE FUNCTION anon.get_unmasking(
+  role REGROLE,
+  tablename REGCLASS
+)
+RETURNS TABLE (expires_at TIMESTAMPTZ, reason TEXT) AS
+$$
+  SELECT u.expires_at, u.reason
+  FROM anon.unmasking u
+  WHERE u.relid = tablename
+  AND u.expires_at > pg_catalog.now()
+  AND pg_catalog.pg_has_role(role, u.roleid, 'MEMBER')
+  AND pg_catalog.pg_has_role(session_user, role, 'MEMBER')
+  ORDER BY u.expires_at DESC
+  LIMIT 1;
+$$
+  LANGUAGE SQL
+  STABLE
+  STRICT
+  PARALLEL SAFE
+  SECURITY DEFINER
+  SET search_path=''
+;
+
+-- Record an access to an unmasked table
+CREATE OR REPLACE FUNCTION anon.log_unmasked_access(
+  role REGROLE,
+  tablename REGCLASS,
+  query TEXT
+)
+RETURNS VOID AS
+$$
+  INSERT INTO anon.unmasking_audit(event, role_name, table_name, expires_at, reason, query)
+  SELECT 'ACCESS', role::TEXT, tablename::TEXT, u.expires_at, u.reason, query
+  FROM anon.get_unmasking(role, tablename) u;
+$$
+  LANGUAGE SQL
+  VOLATILE
+  PARALLEL UNSAFE -- because of INSERT
+  SECURITY DEFINER
+  SET search_path=''
+;
+
+REVOKE ALL ON FUNCTION anon.log_unmasked_access(REGROLE, REGCLASS, TEXT) FROM PUBLIC;
+
+SECURITY LABEL FOR anon ON FUNCTION anon.unmask_temporarily IS 'UNTRUSTED';
+SECURITY LABEL FOR anon ON FUNCTION anon.get_unmasking IS 'UNTRUSTED';
+SECURITY LABEL FOR anon ON FUNCTION anon.log_unmasked_access IS 'UNTRUSTED';
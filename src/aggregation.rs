@@ -0,0 +1,229 @@
+///
+/// # Aggregation
+///
+/// The masked roles of an "aggregate-only" policy are not allowed to read
+/// the individual rows of the masked relations and of the relations with
+/// indirect identifiers. They can only run aggregated queries and the groups
+/// with less than k rows are suppressed.
+///
+use crate::compat;
+use crate::error;
+use crate::guc;
+use crate::input;
+use crate::masking;
+use crate::masking_rule;
+use crate::masking_rule::MaskingRule;
+use crate::utils;
+use pgrx::pg_sys::ffi::pg_guard_ffi_boundary;
+use pgrx::prelude::*;
+use pgrx::{is_a, void_mut_ptr};
+
+/// These aggregates return a statistic, not a collection of the values.
+///
+/// The aggregates that return one of the values of the group (`min`, `max`,
+/// `mode`, `percentile_disc`) are not allowed: they would reveal an authentic
+/// value, even in a group of k rows.
+///
+const ALLOWED_AGGREGATES: [&str; 16] = [
+    "avg",
+    "bool_and",
+    "bool_or",
+    "corr",
+    "count",
+    "covar_pop",
+    "covar_samp",
+    "every",
+    "percentile_cont",
+    "stddev",
+    "stddev_pop",
+    "stddev_samp",
+    "sum",
+    "var_pop",
+    "var_samp",
+    "variance",
+];
+
+/// Returns the minimum group size of an aggregate-only policy
+/// or None if the policy is not aggregate-only
+///
+/// The minimum group size is required: a policy declared without it would not
+/// suppress any group.
+///
+pub fn get_threshold(policy: &str) -> Option<i64> {
+    let option = masking::policy_option(guc::ANON_AGGREGATE_ONLY_POLICIES.get().unwrap(), policy)?;
+    if option.is_empty() {
+        error::invalid_parameter_value("anon.aggregate_only_policies", policy).ereport();
+        unreachable!()
+    }
+    match option.parse::<i64>() {
+        Ok(k) if k > 0 => Some(k),
+        _ => {
+            error::invalid_parameter_value("anon.aggregate_only_policies", option).ereport();
+            unreachable!()
+        }
+    }
+}
+
+/// Returns true if one of the columns of a relation is declared as an
+/// indirect identifier with the k_anonymity provider
+///
+pub fn has_indirect_identifiers(relid: pg_sys::Oid) -> bool {
+    let provider = guc::ANON_K_ANONYMITY_PROVIDER
+        .get()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    utils::get_column_numbers(relid)
+        .unwrap_or_default()
+        .into_iter()
+        .any(|attnum| {
+            masking::rule_on_column(relid, attnum, &provider).is_ok_and(|seclabel| {
+                masking_rule::parse(seclabel) == Ok(MaskingRule::IndirectIdentifier)
+            })
+        })
+}
+
+/// Refuse a query that is not aggregated and suppress the groups with less
+/// than k rows
+///
+pub unsafe fn restrict_to_aggregates(query: *mut pg_sys::Query, k: i64) {
+    let q = &mut *query;
+    let is_aggregated = q.commandType == pg_sys::CmdType::CMD_SELECT
+        && q.setOperations.is_null()
+        && (q.hasAggs || !q.groupClause.is_null() || !q.groupingSets.is_null())
+        && !q.hasWindowFuncs
+        && !q.hasTargetSRFs
+        && !pg_guard_ffi_boundary(|| {
+            compat::checkExprHasSubLink(q.targetList as *mut pg_sys::Node)
+        });
+    if !is_aggregated {
+        error::insufficient_privilege("role is restricted to aggregated queries".to_string())
+            .ereport();
+    }
+
+    let mut aggfnoid = pg_sys::InvalidOid;
+    aggregate_walker(
+        q.targetList as *mut pg_sys::Node,
+        &mut aggfnoid as *mut pg_sys::Oid as void_mut_ptr,
+    );
+    if aggfnoid != pg_sys::InvalidOid {
+        let funcname = utils::get_function_qualified_name(aggfnoid).unwrap_or_default();
+        error::insufficient_privilege(format!("aggregate function {funcname} is not allowed"))
+            .ereport();
+    }
+
+    // HAVING ... AND pg_catalog.count(*) >= k
+    let raw_expr = match input::parse_expression(&format!("pg_catalog.count(*) >= {k}")) {
+        Ok(raw_expr) => raw_expr,
+        Err(e) => {
+            error::internal(&e).ereport();
+            unreachable!()
+        }
+    };
+    let pstate = pg_sys::make_parsestate(std::ptr::null_mut());
+    let threshold = pg_sys::transformExpr(
+        pstate,
+        raw_expr.as_ptr(),
+        pg_sys::ParseExprKind::EXPR_KIND_HAVING,
+    );
+    pg_sys::assign_expr_collations(pstate, threshold);
+    pg_sys::free_parsestate(pstate);
+
+    q.havingQual = pg_sys::make_and_qual(q.havingQual, threshold);
+    q.hasAggs = true;
+}
+
+/// Search for an aggregate that is not allowed, its oid is stored in the
+/// context
+///
+#[pg_guard]
+unsafe extern "C-unwind" fn aggregate_walker(
+    node: *mut pg_sys::Node,
+    context_ptr: void_mut_ptr,
+) -> bool {
+    if node.is_null() {
+        return false;
+    }
+
+    if is_a(node, pg_sys::NodeTag::T_Aggref) {
+        let aggfnoid = (*(node as *mut pg_sys::Aggref)).aggfnoid;
+        if !is_allowed_aggregate(aggfnoid) {
+            *(context_ptr as *mut pg_sys::Oid) = aggfnoid;
+            return true;
+        }
+    }
+
+    pg_sys::expression_tree_walker(node, Some(aggregate_walker), context_ptr)
+}
+
+fn is_allowed_aggregate(aggfnoid: pg_sys::Oid) -> bool {
+    let pg_catalog = pg_sys::Oid::from(pg_sys::PG_CATALOG_NAMESPACE);
+    if unsafe { pg_sys::get_func_namespace(aggfnoid) } != pg_catalog {
+        return false;
+    }
+    let funcname_ptr = unsafe { pg_sys::get_func_name(aggfnoid) };
+    if funcname_ptr.is_null() {
+        return false;
+    }
+    let funcname = unsafe { std::ffi::CStr::from_ptr(funcname_ptr) };
+    ALLOWED_AGGREGATES.contains(&funcname.to_string_lossy().as_ref())
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::aggregation::*;
+    use crate::fixture;
+
+    #[pg_test]
+    fn test_get_threshold() {
+        assert_eq!(get_threshold("anon"), None);
+        Spi::run("SET anon.aggregate_only_policies TO 'anon:10, analytics:3'").unwrap();
+        assert_eq!(get_threshold("anon"), Some(10));
+        assert_eq!(get_threshold("analytics"), Some(3));
+    }
+
+    #[pg_test(error = "Anon: `analytics` is not a valid value for anon.aggregate_only_policies")]
+    fn test_get_threshold_missing() {
+        Spi::run("SET anon.aggregate_only_policies TO 'anon:10, analytics'").unwrap();
+        get_threshold("analytics");
+    }
+
+    #[pg_test(error = "Anon: `ten` is not a valid value for anon.aggregate_only_policies")]
+    fn test_get_threshold_invalid() {
+        Spi::run("SET anon.aggregate_only_policies TO 'anon:ten'").unwrap();
+        get_threshold("anon");
+    }
+
+    #[pg_test]
+    fn test_has_indirect_identifiers() {
+        let relid = fixture::create_table_person();
+        assert!(!has_indirect_identifiers(relid));
+        Spi::run("SECURITY LABEL FOR k_anonymity ON COLUMN person.firstname IS 'QUASI IDENTIFIER'")
+            .unwrap();
+        assert!(has_indirect_identifiers(relid));
+    }
+
+    #[pg_test]
+    fn test_is_allowed_aggregate() {
+        let oid = |f: &str| {
+            Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{f}'::REGPROCEDURE::OID"))
+                .unwrap()
+                .unwrap()
+        };
+        assert!(is_allowed_aggregate(oid("pg_catalog.count(\"any\")")));
+        assert!(is_allowed_aggregate(oid("pg_catalog.avg(INT)")));
+        assert!(!is_allowed_aggregate(oid("pg_catalog.min(INT)")));
+        assert!(!is_allowed_aggregate(oid("pg_catalog.max(TEXT)")));
+        assert!(!is_allowed_aggregate(oid(
+            "pg_catalog.array_agg(anynonarray)"
+        )));
+        assert!(!is_allowed_aggregate(oid(
+            "pg_catalog.string_agg(TEXT,TEXT)"
+        )));
+    }
+}
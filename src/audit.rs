@@ -0,0 +1,358 @@
+///
+/// # Audit
+///
+/// When `anon.audit_log` is enabled, each statement rewritten by the dynamic
+/// masking engine is reported in the server log as a single JSON line. The
+/// record describes which relations the masked role has read and which
+/// columns were exposed without a mask.
+///
+/// The records are reported with the `LOG_SERVER_ONLY` level: they are never
+/// sent to the client and, unlike a table, the server log is not affected
+/// when the transaction is read-only or rolled back.
+///
+/// The accesses to the temporarily unmasked relations are always reported,
+/// regardless of `anon.audit_log`. They are recorded in the
+/// `anon.unmasking_audit` table and the server log keeps a copy that the
+/// masked role can't roll back.
+///
+use crate::error;
+use crate::guc;
+use crate::unmasking;
+use crate::utils;
+use pgrx::pg_sys::elog::PgLogLevel;
+use pgrx::pg_sys::function_name;
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::PgSqlErrorCode::*;
+use pgrx::PostgresGucEnum;
+use std::ffi::CStr;
+
+/// The level of detail of the audit records, see `anon.audit_log`
+///
+#[derive(PostgresGucEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Verbosity {
+    Off,
+    /// The relations and the columns exposed without a mask
+    Terse,
+    /// Also the masked columns, the session user and the query text
+    Verbose,
+}
+
+/// Returns the verbosity of the audit records
+///
+pub fn verbosity() -> Verbosity {
+    guc::ANON_AUDIT_LOG.get()
+}
+
+/// How a masked role has accessed a relation
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Access {
+    /// The relation was replaced by its masking subquery
+    Masked,
+    /// The relation was written through, see `anon.write_through_policies`
+    WriteThrough,
+    /// The relation is temporarily unmasked, see `anon.unmask_temporarily()`
+    Unmasked,
+}
+
+impl Access {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Access::Masked => "masked",
+            Access::WriteThrough => "write_through",
+            Access::Unmasked => "unmasked",
+        }
+    }
+}
+
+/// A relation read by a masked role
+///
+#[derive(Clone, Debug)]
+pub struct Relation {
+    pub relid: pg_sys::Oid,
+    pub access: Access,
+    /// The sampling ratio, the write-through relations are not sampled
+    pub sampling: Option<String>,
+    pub masked_columns: Vec<String>,
+    pub unmasked_columns: Vec<String>,
+}
+
+/// Report a rewritten statement in the server log
+///
+/// Nothing is reported when the statement doesn't read any masked relation
+///
+/// * stmt is the statement (a Query or a raw utility statement)
+/// * query_id is the queryid of the statement, 0 if it is not computed
+/// * policy is the masking policy applied to the statement
+/// * relations are the masked relations read by the statement
+///
+pub fn report(stmt: *mut pg_sys::Node, query_id: u64, policy: &str, relations: &[Relation]) {
+    let verbosity = verbosity();
+    if verbosity == Verbosity::Off || relations.is_empty() {
+        return;
+    }
+    let line = record(verbosity, stmt, query_id, policy, relations);
+    ErrorReport::new(ERRCODE_SUCCESSFUL_COMPLETION, line, function_name!())
+        .report(PgLogLevel::LOG_SERVER_ONLY);
+}
+
+/// Report the execution of a statement reading a temporarily unmasked
+/// relation in the server log and in the `anon.unmasking_audit` table
+///
+/// The statement fails when the access can't be recorded, for instance in a
+/// read-only transaction
+///
+pub fn report_unmasked_access(
+    roleid: pg_sys::Oid,
+    relid: pg_sys::Oid,
+    exemption: &unmasking::Exemption,
+) {
+    let line = unmasked_access_record(roleid, relid, exemption);
+    ErrorReport::new(ERRCODE_SUCCESSFUL_COMPLETION, line, function_name!())
+        .report(PgLogLevel::LOG_SERVER_ONLY);
+
+    if let Err(e) = Spi::run_with_args(
+        "SELECT anon.log_unmasked_access($1, $2, $3)",
+        &[roleid.into(), relid.into(), debug_query_string().into()],
+    ) {
+        error::internal(&e.to_string()).ereport();
+    }
+}
+
+fn unmasked_access_record(
+    roleid: pg_sys::Oid,
+    relid: pg_sys::Oid,
+    exemption: &unmasking::Exemption,
+) -> String {
+    json_object(&[
+        ("event", json_string("unmasked_access")),
+        ("role", json_string(&role_name(roleid))),
+        (
+            "session_user",
+            json_string(&role_name(unsafe { pg_sys::GetSessionUserId() })),
+        ),
+        (
+            "relation",
+            json_string(&utils::get_relation_qualified_name(relid).unwrap_or_default()),
+        ),
+        ("expires_at", json_string(&exemption.expires_at)),
+        ("reason", json_string(&exemption.reason)),
+        ("query", json_nullable_string(debug_query_string())),
+    ])
+}
+
+/// Returns the JSON record of a rewritten statement
+///
+fn record(
+    verbosity: Verbosity,
+    stmt: *mut pg_sys::Node,
+    query_id: u64,
+    policy: &str,
+    relations: &[Relation],
+) -> String {
+    let mut fields = vec![
+        (
+            "role",
+            json_string(&role_name(unsafe { pg_sys::GetUserId() })),
+        ),
+        ("policy", json_string(policy)),
+        ("command", json_string(&command_name(stmt))),
+        // The queryid is signed, just like in pg_stat_statements
+        ("queryid", (query_id as i64).to_string()),
+    ];
+    if verbosity == Verbosity::Verbose {
+        fields.push((
+            "session_user",
+            json_string(&role_name(unsafe { pg_sys::GetSessionUserId() })),
+        ));
+        fields.push(("query", json_nullable_string(debug_query_string())));
+    }
+    let relations = relations
+        .iter()
+        .map(|r| relation_record(verbosity, r))
+        .collect::<Vec<String>>();
+    fields.push(("relations", format!("[{}]", relations.join(","))));
+    json_object(&fields)
+}
+
+fn relation_record(verbosity: Verbosity, relation: &Relation) -> String {
+    let mut fields = vec![
+        (
+            "relation",
+            json_string(&utils::get_relation_qualified_name(relation.relid).unwrap_or_default()),
+        ),
+        ("access", json_string(relation.access.as_str())),
+        ("sampling", json_nullable_string(relation.sampling.clone())),
+        ("unmasked_columns", json_array(&relation.unmasked_columns)),
+    ];
+    if verbosity == Verbosity::Verbose {
+        fields.push(("masked_columns", json_array(&relation.masked_columns)));
+    }
+    json_object(&fields)
+}
+
+fn role_name(roleid: pg_sys::Oid) -> String {
+    unsafe {
+        let name = pg_sys::GetUserNameFromId(roleid, true);
+        if name.is_null() {
+            return roleid.to_u32().to_string();
+        }
+        CStr::from_ptr(name).to_string_lossy().to_string()
+    }
+}
+
+/// Returns the command tag of a statement, e.g. `SELECT` or `DECLARE CURSOR`
+///
+fn command_name(stmt: *mut pg_sys::Node) -> String {
+    if stmt.is_null() {
+        return String::new();
+    }
+    unsafe {
+        let tag = pg_sys::GetCommandTagName(pg_sys::CreateCommandTag(stmt));
+        CStr::from_ptr(tag).to_string_lossy().to_string()
+    }
+}
+
+fn debug_query_string() -> Option<String> {
+    unsafe {
+        if pg_sys::debug_query_string.is_null() {
+            return None;
+        }
+        Some(
+            CStr::from_ptr(pg_sys::debug_query_string)
+                .to_string_lossy()
+                .to_string(),
+        )
+    }
+}
+
+//----------------------------------------------------------------------------
+// JSON
+//----------------------------------------------------------------------------
+
+fn json_object(fields: &[(&str, String)]) -> String {
+    let members = fields
+        .iter()
+        .map(|(key, value)| format!("{}:{value}", json_string(key)))
+        .collect::<Vec<String>>();
+    format!("{{{}}}", members.join(","))
+}
+
+fn json_array(values: &[String]) -> String {
+    let items = values
+        .iter()
+        .map(|v| json_string(v))
+        .collect::<Vec<String>>();
+    format!("[{}]", items.join(","))
+}
+
+fn json_nullable_string(value: Option<String>) -> String {
+    value.map_or("null".to_string(), |v| json_string(&v))
+}
+
+/// Returns a JSON string literal, see RFC 8259
+///
+fn json_string(value: &str) -> String {
+    let mut s = String::with_capacity(value.len() + 2);
+    s.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if (c as u32) < 0x20 => s.push_str(&format!("\\u{:04x}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::audit::*;
+    use crate::fixture;
+
+    #[pg_test]
+    fn test_json_string() {
+        assert_eq!(json_string("abc"), "\"abc\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("a\nb\u{1}"), "\"a\\nb\\u0001\"");
+    }
+
+    #[pg_test]
+    fn test_verbosity() {
+        assert_eq!(verbosity(), Verbosity::Off);
+        Spi::run("SET anon.audit_log TO 'terse'").unwrap();
+        assert_eq!(verbosity(), Verbosity::Terse);
+        Spi::run("SET anon.audit_log TO 'VERBOSE'").unwrap();
+        assert_eq!(verbosity(), Verbosity::Verbose);
+        Spi::run("SET anon.audit_log TO 'off'").unwrap();
+        assert_eq!(verbosity(), Verbosity::Off);
+    }
+
+    #[pg_test(error = "invalid value for parameter \"anon.audit_log\": \"loud\"")]
+    fn test_verbosity_invalid() {
+        Spi::run("SET anon.audit_log TO 'loud'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_record() {
+        let relid = fixture::create_table_person();
+        let relations = vec![Relation {
+            relid,
+            access: Access::Masked,
+            sampling: Some("SYSTEM(33)".to_string()),
+            masked_columns: vec!["lastname".to_string()],
+            unmasked_columns: vec!["firstname".to_string()],
+        }];
+        let terse = record(
+            Verbosity::Terse,
+            std::ptr::null_mut(),
+            42,
+            "anon",
+            &relations,
+        );
+        assert!(terse.starts_with("{\"role\":"));
+        assert!(terse.contains(",\"policy\":\"anon\",\"command\":\"\",\"queryid\":42,"));
+        assert!(terse.ends_with(
+            "\"relations\":[{\"relation\":\"public.person\",\"access\":\"masked\",\
+             \"sampling\":\"SYSTEM(33)\",\"unmasked_columns\":[\"firstname\"]}]}"
+        ));
+        assert!(!terse.contains("masked_columns\":[\"lastname"));
+
+        let verbose = record(
+            Verbosity::Verbose,
+            std::ptr::null_mut(),
+            u64::MAX,
+            "anon",
+            &relations,
+        );
+        assert!(verbose.contains("\"queryid\":-1,\"session_user\":"));
+        assert!(verbose.contains("\"masked_columns\":[\"lastname\"]"));
+    }
+
+    #[pg_test]
+    fn test_unmasked_access_record() {
+        let relid = fixture::create_table_person();
+        let batman = fixture::create_masked_role();
+        let exemption = unmasking::Exemption {
+            expires_at: "2031-01-01 00:00:00+00".to_string(),
+            reason: "incident #42".to_string(),
+        };
+        let line = unmasked_access_record(batman, relid, &exemption);
+        assert!(line.starts_with("{\"event\":\"unmasked_access\",\"role\":\"batman\","));
+        assert!(line.contains(
+            ",\"relation\":\"public.person\",\"expires_at\":\"2031-01-01 00:00:00+00\",\
+             \"reason\":\"incident #42\","
+        ));
+    }
+}
@@ -0,0 +1,758 @@
+///
+/// # Cache
+///
+/// Building a masking subquery requires reading the security labels of the
+/// relation and parsing its masking rules. Finding the masking policy of a
+/// role requires reading the labels of all the roles it belongs to. This is
+/// done once and the results are kept in the backend memory.
+///
+/// The entries are removed when the invalidation messages are received:
+///
+/// * the entries of a relation are removed when the relation is modified or
+///   relabeled
+/// * the masking subqueries and the forbidden functions are removed when a
+///   function is modified or relabeled
+/// * the masking policies and the limits of the roles are removed when a
+///   role is modified, granted or relabeled, and the cached plans are reset when a role is
+///   masked or unmasked
+/// * all the entries are removed when the database or a schema is relabeled
+///
+/// The temporary unmasking exemptions are stored in a table, there's no
+/// invalidation message for them. They are read once per transaction and
+/// read again after each command that modified the database, see
+/// `unmasking_exemption()`.
+///
+use crate::compat;
+use crate::guc;
+use crate::limits;
+use crate::masking;
+use crate::session;
+use crate::unmasking;
+use crate::walker;
+use c_str_macro::c_str;
+use pgrx::list::old_list::PgList;
+use pgrx::pg_sys::ffi::pg_guard_ffi_boundary;
+use pgrx::prelude::*;
+use pgrx::PgMemoryContexts;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A backend-local cache with its hit and miss counters
+///
+struct Cache<K, V> {
+    entries: HashMap<K, V>,
+    hits: i64,
+    misses: i64,
+}
+
+impl<K: Eq + Hash, V> Cache<K, V> {
+    fn new() -> Self {
+        Cache {
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn lookup(&mut self, key: &K) -> Option<&V> {
+        match self.entries.get(key) {
+            Some(value) => {
+                self.hits += 1;
+                Some(value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+}
+
+/// The masking subquery of a relation also depends on a few parameters
+///
+/// The unqualified functions of the masking rules are resolved with the
+/// active search path when the subquery is built, so it is part of the key.
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct SubqueryKey {
+    relid: pg_sys::Oid,
+    policy: String,
+    privacy_by_default: bool,
+    strict_mode: bool,
+    search_path: Vec<pg_sys::Oid>,
+}
+
+/// Each masking subquery is stored in its own memory context so that it can
+/// be released independently. When the relation is not masked, the query and
+/// the context are NULL.
+///
+struct SubqueryEntry {
+    context: pg_sys::MemoryContext,
+    query: *mut pg_sys::Query,
+}
+
+/// The masking rule of a column in a policy
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct RuleKey {
+    relid: pg_sys::Oid,
+    attnum: i16,
+    policy: String,
+}
+
+/// The masking policy of a role depends on the list of declared policies
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct RoleKey {
+    roleid: pg_sys::Oid,
+    policies: String,
+}
+
+/// The limits of a role in a policy
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct LimitKey {
+    roleid: pg_sys::Oid,
+    policy: String,
+}
+
+/// A function is forbidden by its label or by `anon.forbidden_functions`
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct FunctionKey {
+    funcid: pg_sys::Oid,
+    policy: String,
+    denylist: String,
+}
+
+/// The masked relation read by the pre-parsed body of a SQL function
+/// depends on the relations that are masked
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct SqlFunctionKey {
+    funcid: pg_sys::Oid,
+    policy: String,
+    privacy_by_default: bool,
+}
+
+/// The temporary unmasking exemption of a role on a relation
+///
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct UnmaskingKey {
+    roleid: pg_sys::Oid,
+    relid: pg_sys::Oid,
+}
+
+static mut SUBQUERIES: Option<Cache<SubqueryKey, SubqueryEntry>> = None;
+static mut RULES: Option<Cache<RuleKey, Option<String>>> = None;
+static mut ROLES: Option<Cache<RoleKey, Option<String>>> = None;
+static mut LIMITS: Option<Cache<LimitKey, Option<limits::RoleLimits>>> = None;
+static mut FUNCTIONS: Option<Cache<FunctionKey, bool>> = None;
+static mut SQL_FUNCTIONS: Option<Cache<SqlFunctionKey, Option<pg_sys::Oid>>> = None;
+static mut UNMASKINGS: Option<Cache<UnmaskingKey, Option<unmasking::Exemption>>> = None;
+
+/// The start of the transaction and the command that read the cached
+/// exemptions
+static mut UNMASKINGS_SNAPSHOT: (pg_sys::TimestampTz, pg_sys::CommandId) = (0, 0);
+
+/// This counter is incremented each time the cache is invalidated
+static mut INVALIDATIONS: u64 = 0;
+
+#[allow(static_mut_refs)]
+fn subqueries() -> &'static mut Cache<SubqueryKey, SubqueryEntry> {
+    unsafe { SUBQUERIES.get_or_insert_with(Cache::new) }
+}
+
+#[allow(static_mut_refs)]
+fn rules() -> &'static mut Cache<RuleKey, Option<String>> {
+    unsafe { RULES.get_or_insert_with(Cache::new) }
+}
+
+#[allow(static_mut_refs)]
+fn roles() -> &'static mut Cache<RoleKey, Option<String>> {
+    unsafe { ROLES.get_or_insert_with(Cache::new) }
+}
+
+#[allow(static_mut_refs)]
+fn limits() -> &'static mut Cache<LimitKey, Option<limits::RoleLimits>> {
+    unsafe { LIMITS.get_or_insert_with(Cache::new) }
+}
+
+#[allow(static_mut_refs)]
+fn functions() -> &'static mut Cache<FunctionKey, bool> {
+    unsafe { FUNCTIONS.get_or_insert_with(Cache::new) }
+}
+
+#[allow(static_mut_refs)]
+fn sql_functions() -> &'static mut Cache<SqlFunctionKey, Option<pg_sys::Oid>> {
+    unsafe { SQL_FUNCTIONS.get_or_insert_with(Cache::new) }
+}
+
+#[allow(static_mut_refs)]
+fn unmaskings() -> &'static mut Cache<UnmaskingKey, Option<unmasking::Exemption>> {
+    unsafe { UNMASKINGS.get_or_insert_with(Cache::new) }
+}
+
+pub fn register_callbacks() {
+    unsafe {
+        pg_guard_ffi_boundary(|| {
+            compat::CacheRegisterRelcacheCallback(
+                Some(invalidate_relation_callback),
+                pg_sys::Datum::from(0),
+            );
+            compat::CacheRegisterSyscacheCallback(
+                pg_sys::SysCacheIdentifier::PROCOID as i32,
+                Some(invalidate_subqueries_callback),
+                pg_sys::Datum::from(0),
+            );
+            compat::CacheRegisterSyscacheCallback(
+                pg_sys::SysCacheIdentifier::AUTHOID as i32,
+                Some(invalidate_roles_callback),
+                pg_sys::Datum::from(0),
+            );
+            compat::CacheRegisterSyscacheCallback(
+                pg_sys::SysCacheIdentifier::AUTHMEMMEMROLE as i32,
+                Some(invalidate_roles_callback),
+                pg_sys::Datum::from(0),
+            );
+        })
+    }
+}
+
+/// Returns the namespaces of the active search path, including the implicit
+/// ones (`pg_catalog` and the temporary schema)
+///
+fn search_path() -> Vec<pg_sys::Oid> {
+    unsafe {
+        let list = pg_sys::fetch_search_path(true);
+        let namespaces = PgList::<pg_sys::Oid>::from_pg(list).iter_oid().collect();
+        pg_sys::list_free(list);
+        namespaces
+    }
+}
+
+/// Returns a copy of the masking subquery of a relation
+/// or None if the relation is not masked in this policy
+///
+/// The copy is allocated in the current memory context and can be modified
+/// by the caller.
+///
+pub fn masking_subquery(relid: pg_sys::Oid, policy: String) -> Option<PgBox<pg_sys::Query>> {
+    let key = SubqueryKey {
+        relid,
+        policy: policy.clone(),
+        privacy_by_default: guc::ANON_PRIVACY_BY_DEFAULT.get(),
+        strict_mode: guc::ANON_STRICT_MODE.get(),
+        search_path: search_path(),
+    };
+
+    if let Some(entry) = subqueries().lookup(&key) {
+        return copy_query(entry.query);
+    }
+
+    // The invalidation messages may be processed while we're building the
+    // subquery. In that case the subquery may be outdated and we don't keep it.
+    let invalidations = unsafe { INVALIDATIONS };
+    let query = masking::subquery_tree(relid, policy);
+    if invalidations != unsafe { INVALIDATIONS } {
+        return query;
+    }
+
+    let entry = match query {
+        Some(ref q) => unsafe {
+            let context = pg_sys::AllocSetContextCreateInternal(
+                pg_sys::CacheMemoryContext,
+                c_str!("anon masking subquery").as_ptr(),
+                pg_sys::ALLOCSET_SMALL_MINSIZE as usize,
+                pg_sys::ALLOCSET_SMALL_INITSIZE as usize,
+                pg_sys::ALLOCSET_SMALL_MAXSIZE as usize,
+            );
+            let cached = PgMemoryContexts::For(context)
+                .switch_to(|_| pg_sys::copyObjectImpl(q.as_ptr() as *const std::ffi::c_void))
+                as *mut pg_sys::Query;
+            SubqueryEntry {
+                context,
+                query: cached,
+            }
+        },
+        None => SubqueryEntry {
+            context: std::ptr::null_mut(),
+            query: std::ptr::null_mut(),
+        },
+    };
+    subqueries().entries.insert(key, entry);
+    query
+}
+
+/// Returns the masking rule of a column
+/// or None if the column has no rule in this policy
+///
+pub fn column_rule(relid: pg_sys::Oid, attnum: i16, policy: &str) -> Option<String> {
+    let key = RuleKey {
+        relid,
+        attnum,
+        policy: policy.to_string(),
+    };
+
+    if let Some(rule) = rules().lookup(&key) {
+        return rule.clone();
+    }
+
+    let invalidations = unsafe { INVALIDATIONS };
+    let rule = masking::rule_on_column(relid, attnum, policy)
+        .ok()
+        .map(|r| r.to_string());
+    if invalidations == unsafe { INVALIDATIONS } {
+        rules().entries.insert(key, rule.clone());
+    }
+    rule
+}
+
+/// Returns the masking policy of a role
+/// or None if the role is not masked
+///
+pub fn masking_policy(roleid: pg_sys::Oid) -> Option<String> {
+    let key = RoleKey {
+        roleid,
+        policies: masking::list_masking_policies().join(":"),
+    };
+
+    if let Some(policy) = roles().lookup(&key) {
+        return policy.clone();
+    }
+
+    let invalidations = unsafe { INVALIDATIONS };
+    let policy = masking::get_masking_policy(roleid);
+    if invalidations == unsafe { INVALIDATIONS } {
+        roles().entries.insert(key, policy.clone());
+    }
+    policy
+}
+
+/// Returns the limits of a role in a policy
+/// or None if the role is not masked in this policy
+///
+pub fn masking_limits(roleid: pg_sys::Oid, policy: &str) -> Option<limits::RoleLimits> {
+    let key = LimitKey {
+        roleid,
+        policy: policy.to_string(),
+    };
+
+    if let Some(limits) = limits().lookup(&key) {
+        return *limits;
+    }
+
+    let invalidations = unsafe { INVALIDATIONS };
+    let role_limits = masking::get_masking_limits(roleid, policy);
+    if invalidations == unsafe { INVALIDATIONS } {
+        limits().entries.insert(key, role_limits);
+    }
+    role_limits
+}
+
+/// Returns the temporary unmasking exemption of a role on a relation
+/// or None if the relation is masked for this role
+///
+/// The validity of an exemption depends on the start of the transaction, so
+/// the entries are removed when a new transaction starts. They are also
+/// removed when the transaction modified the database, because the
+/// `anon.unmasking` table may have been modified.
+///
+pub fn unmasking_exemption(
+    roleid: pg_sys::Oid,
+    relid: pg_sys::Oid,
+) -> Option<unmasking::Exemption> {
+    let snapshot = unsafe {
+        (
+            pg_sys::GetCurrentTransactionStartTimestamp(),
+            pg_sys::GetCurrentCommandId(false),
+        )
+    };
+    if snapshot != unsafe { UNMASKINGS_SNAPSHOT } {
+        unmaskings().entries.clear();
+        unsafe { UNMASKINGS_SNAPSHOT = snapshot };
+    }
+
+    let key = UnmaskingKey { roleid, relid };
+    if let Some(exemption) = unmaskings().lookup(&key) {
+        return exemption.clone();
+    }
+
+    let exemption = unmasking::exemption(roleid, relid);
+    unmaskings().entries.insert(key, exemption.clone());
+    exemption
+}
+
+/// Returns true if the masked roles of a policy are not allowed to call a
+/// function
+///
+pub fn is_forbidden_function(funcid: pg_sys::Oid, policy: &str) -> bool {
+    let key = FunctionKey {
+        funcid,
+        policy: policy.to_string(),
+        denylist: guc::ANON_FORBIDDEN_FUNCTIONS
+            .get()
+            .unwrap()
+            .to_string_lossy()
+            .to_string(),
+    };
+
+    if let Some(forbidden) = functions().lookup(&key) {
+        return *forbidden;
+    }
+
+    let invalidations = unsafe { INVALIDATIONS };
+    let forbidden = masking::is_forbidden_function(funcid, policy);
+    if invalidations == unsafe { INVALIDATIONS } {
+        functions().entries.insert(key, forbidden);
+    }
+    forbidden
+}
+
+/// Returns the first masked relation read by the pre-parsed body of a SQL
+/// function, see `walker::check_sql_function()`
+///
+/// The entries are removed when a function is modified and when a relation
+/// is invalidated, because any relation may be masked or unmasked.
+///
+pub fn sql_function_masked_relation(funcid: pg_sys::Oid, policy: &str) -> Option<pg_sys::Oid> {
+    let key = SqlFunctionKey {
+        funcid,
+        policy: policy.to_string(),
+        privacy_by_default: guc::ANON_PRIVACY_BY_DEFAULT.get(),
+    };
+
+    if let Some(relid) = sql_functions().lookup(&key) {
+        return *relid;
+    }
+
+    let invalidations = unsafe { INVALIDATIONS };
+    let relid = unsafe { walker::sql_function_masked_relation(funcid, policy.to_string()) };
+    if invalidations == unsafe { INVALIDATIONS } {
+        sql_functions().entries.insert(key, relid);
+    }
+    relid
+}
+
+/// Returns the name, the hit count, the miss count and the number of entries
+/// of each cache
+///
+pub fn stats() -> Vec<(String, i64, i64, i64)> {
+    fn row<K, V>(name: &str, cache: &Cache<K, V>) -> (String, i64, i64, i64) {
+        (
+            name.to_string(),
+            cache.hits,
+            cache.misses,
+            cache.entries.len() as i64,
+        )
+    }
+    vec![
+        row("subqueries", subqueries()),
+        row("rules", rules()),
+        row("roles", roles()),
+        row("limits", limits()),
+        row("functions", functions()),
+        row("sql_functions", sql_functions()),
+        row("unmaskings", unmaskings()),
+    ]
+}
+
+/// Invalidate the cache entries after a security label is modified
+///
+/// The invalidation message is sent to the other backends when the
+/// transaction is committed. This will also reset the cached plans that
+/// depend on the relation, or all the cached plans when a role, a schema or
+/// the database is relabeled.
+///
+pub fn invalidate_object(object: &pg_sys::ObjectAddress) {
+    match object.classId {
+        pg_sys::RelationRelationId => unsafe {
+            pg_guard_ffi_boundary(|| compat::CacheInvalidateRelcacheByRelid(object.objectId))
+        },
+        pg_sys::AuthIdRelationId => invalidate_masked_roles(),
+        // There's no relcache for the functions, instead we flush their
+        // catalog cache, which calls the syscache callbacks
+        pg_sys::ProcedureRelationId => unsafe {
+            pg_guard_ffi_boundary(|| compat::CacheInvalidateCatalog(object.classId))
+        },
+        pg_sys::DatabaseRelationId | pg_sys::NamespaceRelationId => unsafe {
+            pg_guard_ffi_boundary(|| compat::CacheInvalidateRelcacheAll())
+        },
+        _ => (),
+    }
+}
+
+/// Tell all the backends that a role was masked or unmasked, directly or
+/// through a membership
+///
+/// The roles have no relcache entry, the signal is a relcache invalidation
+/// of the `pg_shseclabel` catalog, which is shared by all the databases. See
+/// `invalidate_relation_callback()`.
+///
+pub fn invalidate_masked_roles() {
+    unsafe {
+        pg_guard_ffi_boundary(|| {
+            compat::CacheInvalidateRelcacheByRelid(compat::SharedSecLabelRelationId)
+        })
+    }
+}
+
+fn copy_query(query: *mut pg_sys::Query) -> Option<PgBox<pg_sys::Query>> {
+    if query.is_null() {
+        return None;
+    }
+    unsafe {
+        Some(PgBox::from_pg(
+            pg_sys::copyObjectImpl(query as *const std::ffi::c_void) as *mut pg_sys::Query,
+        ))
+    }
+}
+
+/// Remove the cache entries of a relation, or all the entries when relid
+/// is InvalidOid
+///
+fn invalidate_relation(relid: pg_sys::Oid) {
+    unsafe {
+        INVALIDATIONS += 1;
+    }
+    let all = relid == pg_sys::InvalidOid;
+    subqueries().entries.retain(|key, entry| {
+        if !all && key.relid != relid {
+            return true;
+        }
+        if !entry.context.is_null() {
+            unsafe { pg_sys::MemoryContextDelete(entry.context) };
+        }
+        false
+    });
+    rules().entries.retain(|key, _| !all && key.relid != relid);
+    sql_functions().entries.clear();
+    if all {
+        roles().entries.clear();
+        limits().entries.clear();
+        functions().entries.clear();
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn invalidate_relation_callback(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    if relid == compat::SharedSecLabelRelationId {
+        invalidate_masked_roles_callback();
+        return;
+    }
+    session::invalidate(relid);
+    invalidate_relation(relid);
+}
+
+/// A role was masked or unmasked, see `invalidate_masked_roles()`
+///
+unsafe fn invalidate_masked_roles_callback() {
+    INVALIDATIONS += 1;
+    roles().entries.clear();
+    limits().entries.clear();
+
+    // The prepared statements and the PL/pgSQL functions keep the plans that
+    // were masked with the previous policy of the role. We don't know which
+    // plans were built for which role, so they are all rebuilt.
+    pg_sys::ResetPlanCache();
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn invalidate_subqueries_callback(
+    _arg: pg_sys::Datum,
+    _cacheid: i32,
+    _hashvalue: u32,
+) {
+    INVALIDATIONS += 1;
+    subqueries().entries.retain(|_, entry| {
+        if !entry.context.is_null() {
+            pg_sys::MemoryContextDelete(entry.context);
+        }
+        false
+    });
+    functions().entries.clear();
+    sql_functions().entries.clear();
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn invalidate_roles_callback(
+    _arg: pg_sys::Datum,
+    _cacheid: i32,
+    _hashvalue: u32,
+) {
+    // The role cache is cheap to rebuild, but the plan cache is only reset
+    // when a masked role is involved, see `invalidate_masked_roles()`
+    INVALIDATIONS += 1;
+    roles().entries.clear();
+    limits().entries.clear();
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::cache::*;
+    use crate::fixture;
+    use crate::label_providers::ANON_DEFAULT_MASKING_POLICY;
+
+    fn is_cached(relid: pg_sys::Oid) -> bool {
+        subqueries().entries.keys().any(|k| k.relid == relid)
+    }
+
+    #[pg_test]
+    fn test_masking_subquery() {
+        let relid = fixture::create_table_person();
+        let policy = ANON_DEFAULT_MASKING_POLICY.to_string();
+        assert!(!is_cached(relid));
+        assert!(masking_subquery(relid, policy.clone()).is_some());
+        assert!(is_cached(relid));
+        // the second call returns a copy of the cached subquery
+        assert!(masking_subquery(relid, policy).is_some());
+    }
+
+    #[pg_test]
+    fn test_masking_subquery_not_masked() {
+        let relid = fixture::create_table_call();
+        let policy = ANON_DEFAULT_MASKING_POLICY.to_string();
+        assert!(masking_subquery(relid, policy.clone()).is_none());
+        assert!(is_cached(relid));
+        assert!(masking_subquery(relid, policy).is_none());
+    }
+
+    #[pg_test]
+    fn test_masking_subquery_invalidation() {
+        let relid = fixture::create_table_call();
+        let policy = ANON_DEFAULT_MASKING_POLICY.to_string();
+        assert!(masking_subquery(relid, policy.clone()).is_none());
+        Spi::run("SECURITY LABEL FOR anon ON COLUMN call.sender IS 'MASKED WITH VALUE NULL'")
+            .unwrap();
+        unsafe { pg_sys::CommandCounterIncrement() };
+        assert!(!is_cached(relid));
+        assert!(masking_subquery(relid, policy).is_some());
+    }
+
+    #[pg_test]
+    fn test_masking_subquery_search_path() {
+        let relid = fixture::create_table_call();
+        let policy = ANON_DEFAULT_MASKING_POLICY.to_string();
+        Spi::run(
+            "
+            CREATE SCHEMA one;
+            CREATE SCHEMA two;
+            CREATE FUNCTION one.mask() RETURNS TEXT LANGUAGE SQL AS $$ SELECT '1' $$;
+            CREATE FUNCTION two.mask() RETURNS TEXT LANGUAGE SQL AS $$ SELECT '2' $$;
+            SET anon.restrict_to_trusted_schemas TO FALSE;
+            SET search_path TO one, public;
+            SECURITY LABEL FOR anon ON COLUMN call.sender IS 'MASKED WITH FUNCTION mask()';
+            ",
+        )
+        .unwrap();
+        assert!(masking_subquery(relid, policy.clone()).is_some());
+        let misses = subqueries().misses;
+        assert!(masking_subquery(relid, policy.clone()).is_some());
+        assert_eq!(subqueries().misses, misses);
+        // mask() is now resolved to two.mask()
+        Spi::run("SET search_path TO two, public").unwrap();
+        assert!(masking_subquery(relid, policy).is_some());
+        assert_eq!(subqueries().misses, misses + 1);
+    }
+
+    #[pg_test]
+    fn test_column_rule() {
+        let relid = fixture::create_table_person();
+        let policy = ANON_DEFAULT_MASKING_POLICY;
+        let (hits, misses) = (rules().hits, rules().misses);
+        assert_eq!(
+            column_rule(relid, 3, policy),
+            Some("MASKED WITH VALUE NULL".to_string())
+        );
+        assert_eq!(column_rule(relid, 3, policy), column_rule(relid, 3, policy));
+        assert_eq!(column_rule(relid, 2, policy), None);
+        assert_eq!(rules().hits - hits, 2);
+        assert_eq!(rules().misses - misses, 2);
+        Spi::run("SECURITY LABEL FOR anon ON COLUMN person.lastname IS NULL").unwrap();
+        unsafe { pg_sys::CommandCounterIncrement() };
+        assert_eq!(column_rule(relid, 3, policy), None);
+    }
+
+    #[pg_test]
+    fn test_masking_policy() {
+        let batman = fixture::create_masked_role();
+        let (hits, misses) = (roles().hits, roles().misses);
+        assert_eq!(masking_policy(batman), Some("anon".to_string()));
+        assert_eq!(masking_policy(batman), Some("anon".to_string()));
+        assert_eq!(roles().hits - hits, 1);
+        assert_eq!(roles().misses - misses, 1);
+        Spi::run("SECURITY LABEL FOR anon ON ROLE batman IS NULL").unwrap();
+        unsafe { pg_sys::CommandCounterIncrement() };
+        assert_eq!(masking_policy(batman), None);
+    }
+
+    #[pg_test]
+    fn test_masking_policy_membership() {
+        let batman = fixture::create_masked_role();
+        let bruce = fixture::create_unmasked_role();
+        assert_eq!(masking_policy(bruce), None);
+        Spi::run("GRANT batman TO bruce").unwrap();
+        unsafe { pg_sys::CommandCounterIncrement() };
+        assert_eq!(masking_policy(bruce), Some("anon".to_string()));
+        assert_eq!(masking_policy(batman), Some("anon".to_string()));
+    }
+
+    #[cfg(not(feature = "pg13"))]
+    #[pg_test]
+    fn test_sql_function_masked_relation() {
+        let relid = fixture::create_table_person();
+        let policy = ANON_DEFAULT_MASKING_POLICY;
+        Spi::run(
+            "
+            CREATE FUNCTION public.lastnames() RETURNS SETOF TEXT
+            LANGUAGE SQL
+            BEGIN ATOMIC
+              SELECT lastname FROM public.person;
+            END;
+            ",
+        )
+        .unwrap();
+        let funcid = Spi::get_one::<pg_sys::Oid>("SELECT 'public.lastnames'::REGPROC::OID")
+            .unwrap()
+            .unwrap();
+        let misses = sql_functions().misses;
+        assert_eq!(sql_function_masked_relation(funcid, policy), Some(relid));
+        assert_eq!(sql_function_masked_relation(funcid, policy), Some(relid));
+        assert_eq!(sql_functions().misses, misses + 1);
+        // The function body is modified
+        Spi::run(
+            "
+            CREATE OR REPLACE FUNCTION public.lastnames() RETURNS SETOF TEXT
+            LANGUAGE SQL
+            BEGIN ATOMIC
+              SELECT 'Connor';
+            END;
+            ",
+        )
+        .unwrap();
+        unsafe { pg_sys::CommandCounterIncrement() };
+        assert_eq!(sql_function_masked_relation(funcid, policy), None);
+    }
+
+    #[pg_test]
+    fn test_stats() {
+        let names: Vec<String> = stats().into_iter().map(|s| s.0).collect();
+        assert_eq!(
+            names,
+            vec![
+                "subqueries",
+                "rules",
+                "roles",
+                "limits",
+                "functions",
+                "sql_functions",
+                "unmaskings"
+            ]
+        );
+    }
+}
@@ -6,6 +6,8 @@
 /// We're declaring here a set of replacements to avoid conditional macro
 /// codeblocks in the main extension codebase.
 ///
+#[cfg(not(any(feature = "pg13", feature = "pg14")))]
+use pgrx::list::old_list::PgList;
 use pgrx::prelude::*;
 use std::os::raw::c_char;
 
@@ -53,6 +55,30 @@ macro_rules! rte_perminfo_index_disable {
 
 pub(crate) use rte_perminfo_index_disable;
 
+///
+/// ## query_set_rteperminfos
+///
+/// Since PG16, the RTEPermissionInfo list is stored in the Query and the
+/// ParseState. When we build a Query manually, we need to copy the list
+/// collected by the ParseState.
+///
+
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15"))]
+#[macro_export]
+macro_rules! query_set_rteperminfos {
+    ($query: ident, $pstate: ident) => {};
+}
+
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+#[macro_export]
+macro_rules! query_set_rteperminfos {
+    ($query: ident, $pstate: ident) => {
+        $query.rteperminfos = $pstate.p_rteperminfos
+    };
+}
+
+pub(crate) use query_set_rteperminfos;
+
 ///
 /// SchemaValue type
 ///
@@ -79,6 +105,73 @@ pub unsafe fn strVal(v: SchemaValue) -> *const c_char {
     v.val.str_
 }
 
+//
+// Anum_pg_auth_members_roleid
+//
+// The pg_auth_members catalog is not included in the PGRX bindings and since
+// PG16 it has a new `oid` column in first position.
+//
+// https://github.com/postgres/postgres/commit/6566133c5f52771198aca07ed18f84519fac1be7
+//
+
+#[allow(non_upper_case_globals)]
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15"))]
+pub const Anum_pg_auth_members_roleid: pg_sys::AttrNumber = 1;
+
+#[allow(non_upper_case_globals)]
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+pub const Anum_pg_auth_members_roleid: pg_sys::AttrNumber = 2;
+
+//
+// Inherited memberships
+//
+// Until PG15, the INHERIT attribute of a role applies to all its memberships.
+// Since PG16, each grant has its own INHERIT option and the attribute of the
+// role is only the default value of the option.
+//
+// https://github.com/postgres/postgres/commit/e3ce2de09d814f8770b2e3b3c152b7671bcdb83f
+//
+
+/// Returns true if the member inherits the privileges of the role granted by
+/// a pg_auth_members tuple
+///
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15"))]
+pub unsafe fn membership_is_inherited(
+    memberid: pg_sys::Oid,
+    _tuple: *mut pg_sys::HeapTupleData,
+) -> bool {
+    let authtup = pg_sys::SearchSysCache1(
+        pg_sys::SysCacheIdentifier::AUTHOID as i32,
+        pg_sys::Datum::from(memberid),
+    );
+    if authtup.is_null() {
+        return false;
+    }
+    let authform = pg_sys::heap_tuple_get_struct::<pg_sys::FormData_pg_authid>(authtup);
+    let inherit = (*authform).rolinherit;
+    pg_sys::ReleaseSysCache(authtup);
+    inherit
+}
+
+#[allow(non_upper_case_globals)]
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+const Anum_pg_auth_members_inherit_option: pg_sys::AttrNumber = 6;
+
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+pub unsafe fn membership_is_inherited(
+    _memberid: pg_sys::Oid,
+    tuple: *mut pg_sys::HeapTupleData,
+) -> bool {
+    let mut isnull = false;
+    let datum = pg_sys::SysCacheGetAttr(
+        pg_sys::SysCacheIdentifier::AUTHMEMMEMROLE as i32,
+        tuple,
+        Anum_pg_auth_members_inherit_option,
+        &mut isnull,
+    );
+    bool::from_datum(datum, isnull).unwrap_or(false)
+}
+
 ///
 /// IsCatalogRelationOid
 /// Remove this when catalog.c is available in PGRX
@@ -87,3 +180,233 @@ pub unsafe fn strVal(v: SchemaValue) -> *const c_char {
 pub fn IsCatalogRelationOid(relid: pg_sys::Oid) -> bool {
     u32::from(relid) < pg_sys::FirstNormalObjectId
 }
+
+//
+// TsmRoutine
+//
+// The TsmRoutine struct is opaque in the PGRX bindings because tsmapi.h is not
+// included. We only need the first fields of the struct, which have not
+// changed since PG9.5, so we declare them here and ignore the callbacks.
+//
+// https://github.com/postgres/postgres/blob/REL_17_STABLE/src/include/access/tsmapi.h
+//
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct TsmRoutine {
+    pub type_: pg_sys::NodeTag,
+    pub parameterTypes: *mut pg_sys::List,
+    pub repeatable_across_queries: bool,
+    pub repeatable_across_scans: bool,
+}
+
+//
+// Cache invalidation
+//
+// The utils/inval.h header is not included in the PGRX bindings
+//
+
+pub type RelcacheCallbackFunction =
+    Option<unsafe extern "C-unwind" fn(arg: pg_sys::Datum, relid: pg_sys::Oid)>;
+
+pub type SyscacheCallbackFunction =
+    Option<unsafe extern "C-unwind" fn(arg: pg_sys::Datum, cacheid: i32, hashvalue: u32)>;
+
+// The `#[pg_guard]` attribute can only be used on extern blocks inside the
+// pgrx-pg-sys crate, so the callers must wrap these calls with
+// `pg_sys::ffi::pg_guard_ffi_boundary`
+extern "C-unwind" {
+    pub fn CacheInvalidateCatalog(catalogId: pg_sys::Oid);
+    pub fn CacheInvalidateRelcacheAll();
+    pub fn CacheInvalidateRelcacheByRelid(relid: pg_sys::Oid);
+    pub fn CacheRegisterRelcacheCallback(func: RelcacheCallbackFunction, arg: pg_sys::Datum);
+    pub fn CacheRegisterSyscacheCallback(
+        cacheid: i32,
+        func: SyscacheCallbackFunction,
+        arg: pg_sys::Datum,
+    );
+}
+
+//
+// MERGE
+//
+// The MERGE statement was introduced in PG15. Since PG17, the join condition
+// is stored in its own field of the Query and an action may apply to the
+// target rows that have no match in the source ( `NOT MATCHED BY SOURCE` )
+//
+
+/// The parts of a MERGE action that may read or write the target relation
+pub struct MergeActionParts {
+    /// The action is applied to an existing row of the target relation
+    pub on_target_row: bool,
+    pub qual: *mut *mut pg_sys::Node,
+    pub target_list: *mut *mut pg_sys::List,
+}
+
+#[cfg(any(feature = "pg13", feature = "pg14"))]
+pub fn is_merge(_command_type: pg_sys::CmdType::Type) -> bool {
+    false
+}
+
+#[cfg(not(any(feature = "pg13", feature = "pg14")))]
+pub fn is_merge(command_type: pg_sys::CmdType::Type) -> bool {
+    command_type == pg_sys::CmdType::CMD_MERGE
+}
+
+#[cfg(any(feature = "pg13", feature = "pg14"))]
+pub unsafe fn merge_actions(_query: *mut pg_sys::Query) -> Vec<MergeActionParts> {
+    Vec::new()
+}
+
+#[cfg(any(feature = "pg15", feature = "pg16"))]
+pub unsafe fn merge_actions(query: *mut pg_sys::Query) -> Vec<MergeActionParts> {
+    PgList::<pg_sys::MergeAction>::from_pg((*query).mergeActionList)
+        .iter_ptr()
+        .map(|action| MergeActionParts {
+            on_target_row: (*action).matched,
+            qual: &mut (*action).qual,
+            target_list: &mut (*action).targetList,
+        })
+        .collect()
+}
+
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16")))]
+pub unsafe fn merge_actions(query: *mut pg_sys::Query) -> Vec<MergeActionParts> {
+    PgList::<pg_sys::MergeAction>::from_pg((*query).mergeActionList)
+        .iter_ptr()
+        .map(|action| MergeActionParts {
+            on_target_row: (*action).matchKind
+                != pg_sys::MergeMatchKind::MERGE_WHEN_NOT_MATCHED_BY_TARGET,
+            qual: &mut (*action).qual,
+            target_list: &mut (*action).targetList,
+        })
+        .collect()
+}
+
+/// Returns the join condition of a MERGE statement, when it is not stored in
+/// the jointree of the Query
+///
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+pub unsafe fn merge_join_condition(_query: *mut pg_sys::Query) -> Option<*mut *mut pg_sys::Node> {
+    None
+}
+
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16")))]
+pub unsafe fn merge_join_condition(query: *mut pg_sys::Query) -> Option<*mut *mut pg_sys::Node> {
+    Some(&mut (*query).mergeJoinCondition)
+}
+
+//
+// SQL functions
+//
+// Since PG14, the body of a SQL function declared with `BEGIN ATOMIC` or
+// `RETURN` is parsed when the function is created and the query tree is
+// stored in `pg_proc.prosqlbody`. The pg_language catalog is not included in
+// the PGRX bindings.
+//
+
+#[allow(non_upper_case_globals)]
+pub const SQLlanguageId: pg_sys::Oid = pg_sys::Oid::from_u32(14);
+
+#[cfg(feature = "pg13")]
+pub unsafe fn sql_function_body(_proctup: pg_sys::HeapTuple) -> *mut pg_sys::Node {
+    std::ptr::null_mut()
+}
+
+#[cfg(not(feature = "pg13"))]
+pub unsafe fn sql_function_body(proctup: pg_sys::HeapTuple) -> *mut pg_sys::Node {
+    let mut isnull = false;
+    let datum = pg_sys::SysCacheGetAttr(
+        pg_sys::SysCacheIdentifier::PROCOID as i32,
+        proctup,
+        pg_sys::Anum_pg_proc_prosqlbody as pg_sys::AttrNumber,
+        &mut isnull,
+    );
+    if isnull {
+        return std::ptr::null_mut();
+    }
+    pg_sys::stringToNode(pg_sys::text_to_cstring(datum.cast_mut_ptr())) as *mut pg_sys::Node
+}
+
+//
+// Query rewriting
+//
+// The rewrite/rewriteManip.h header is not included in the PGRX bindings
+//
+
+/// `REPLACEVARS_REPORT_ERROR` in the `ReplaceVarsNoMatchOption` enum
+pub const REPLACEVARS_REPORT_ERROR: i32 = 0;
+
+// Same as above, the callers must wrap these calls with
+// `pg_sys::ffi::pg_guard_ffi_boundary`
+extern "C-unwind" {
+    pub fn checkExprHasSubLink(node: *mut pg_sys::Node) -> bool;
+    pub fn ChangeVarNodes(
+        node: *mut pg_sys::Node,
+        rt_index: i32,
+        new_index: i32,
+        sublevels_up: i32,
+    );
+    pub fn ReplaceVarsFromTargetList(
+        node: *mut pg_sys::Node,
+        target_varno: i32,
+        sublevels_up: i32,
+        target_rte: *mut pg_sys::RangeTblEntry,
+        targetlist: *mut pg_sys::List,
+        nomatch_option: i32,
+        nomatch_varno: i32,
+        outer_hasSubLinks: *mut bool,
+    ) -> *mut pg_sys::Node;
+}
+
+//
+// Statistics
+//
+// The catalog/pg_statistic_ext_data.h header is not included in the PGRX
+// bindings
+//
+
+#[allow(non_upper_case_globals)]
+pub const StatisticExtDataRelationId: pg_sys::Oid = pg_sys::Oid::from_u32(3429);
+
+//
+// Security labels
+//
+// The catalog/pg_shseclabel.h header is not included in the PGRX bindings
+//
+
+#[allow(non_upper_case_globals)]
+pub const SharedSecLabelRelationId: pg_sys::Oid = pg_sys::Oid::from_u32(3592);
+
+//
+// Timeouts
+//
+// The utils/timeout.h header is not included in the PGRX bindings
+//
+
+/// `STATEMENT_TIMEOUT` in the `TimeoutId` enum
+pub const STATEMENT_TIMEOUT: i32 = 3;
+
+// Same as above, the callers must wrap these calls with
+// `pg_sys::ffi::pg_guard_ffi_boundary`
+extern "C-unwind" {
+    pub fn enable_timeout_after(id: i32, delay_ms: i32);
+    pub fn get_timeout_active(id: i32) -> bool;
+}
+
+//
+// Deparsing
+//
+// `pg_get_querydef()` is exported since PG15
+//
+
+#[cfg(any(feature = "pg13", feature = "pg14"))]
+pub unsafe fn get_querydef(_query: *mut pg_sys::Query) -> Option<String> {
+    None
+}
+
+#[cfg(not(any(feature = "pg13", feature = "pg14")))]
+pub unsafe fn get_querydef(query: *mut pg_sys::Query) -> Option<String> {
+    let def = pg_sys::pg_get_querydef(query, true);
+    Some(std::ffi::CStr::from_ptr(def).to_string_lossy().to_string())
+}
@@ -1,3 +1,6 @@
+use pgrx::pg_sys::elog::PgLogLevel;
+use pgrx::pg_sys::function_name;
+use pgrx::pg_sys::panic::ErrorReport;
 #[allow(unused_imports)]
 use pgrx::prelude::*;
 use pgrx::PgSqlErrorCode::*;
@@ -32,6 +35,28 @@ impl AnonError {
             );
         }
     }
+
+    /// Report the error as a warning, the query is not interrupted
+    pub fn warning(&self) {
+        self.report(PgLogLevel::WARNING)
+    }
+
+    /// Report the error in the server log only, the query is not interrupted
+    pub fn audit(&self) {
+        self.report(PgLogLevel::LOG_SERVER_ONLY)
+    }
+
+    fn report(&self, level: PgLogLevel) {
+        let mut report = ErrorReport::new(
+            self.error_code,
+            format!("Anon: {}", self.message),
+            function_name!(),
+        );
+        if let Some(d) = &self.detail {
+            report = report.set_detail(d.clone());
+        }
+        report.report(level);
+    }
 }
 
 // Postgres error codes
@@ -69,6 +94,14 @@ pub fn insufficient_privilege(reason: String) -> AnonError {
     AnonError::new(ERRCODE_INSUFFICIENT_PRIVILEGE, reason, None)
 }
 
+pub fn invalid_parameter_value(parameter: &str, value: &str) -> AnonError {
+    AnonError::new(
+        ERRCODE_INVALID_PARAMETER_VALUE,
+        format!("`{value}` is not a valid value for {parameter}"),
+        None,
+    )
+}
+
 pub fn invalid_label_for(an_object: &str, label: &str, hint: Option<String>) -> AnonError {
     AnonError::new(
         ERRCODE_SYNTAX_ERROR,
@@ -77,6 +110,30 @@ pub fn invalid_label_for(an_object: &str, label: &str, hint: Option<String>) ->
     )
 }
 
+pub fn invalid_masking_rule(rule: &str, hint: Option<String>) -> AnonError {
+    AnonError::new(
+        ERRCODE_SYNTAX_ERROR,
+        format!("`{rule}` is not a valid masking rule"),
+        hint,
+    )
+}
+
+pub fn invalid_tablesample(ratio: &str, hint: Option<String>) -> AnonError {
+    AnonError::new(
+        ERRCODE_INVALID_TABLESAMPLE_ARGUMENT,
+        format!("`TABLESAMPLE {ratio}` is not a valid sampling ratio"),
+        hint,
+    )
+}
+
+pub fn unmasking_expired(relation: &str) -> AnonError {
+    AnonError::new(
+        ERRCODE_INSUFFICIENT_PRIVILEGE,
+        format!("the temporary unmasking of {relation} has expired"),
+        Some("Prepare the statement again to read the masked data".to_string()),
+    )
+}
+
 pub fn internal(message: &str) -> AnonError {
     AnonError::new(
         ERRCODE_INTERNAL_ERROR,
@@ -0,0 +1,244 @@
+///
+/// # Redacted EXPLAIN
+///
+/// By default, the masked roles are not allowed to run an EXPLAIN statement
+/// because the query plan may reveal information about the authentic data.
+///
+/// When `anon.redacted_explain` is enabled, the query is masked before being
+/// planned and the output is redacted: the expressions (and the constants
+/// they contain) and the row estimates are removed, the node types and the
+/// costs are kept.
+///
+use crate::error;
+use crate::walker;
+use pgrx::list::old_list::PgList;
+use pgrx::prelude::*;
+use regex::Regex;
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+/// The plan properties that may contain an expression, a constant or a
+/// sampling ratio
+static REDACTED_PROPERTIES: &str = "Filter|Join Filter|One-Time Filter|\
+    Index Cond|Recheck Cond|Hash Cond|Merge Cond|TID Cond|Order By|\
+    Run Condition|Output|Sort Key|Presorted Key|Group Key|Group Keys|\
+    Cache Key|Function Call|Table Function Call|Sampling|\
+    Sampling Parameters|Repeatable Seed";
+
+static REDACTED: &str = "<redacted>";
+
+/// Mask the query of an EXPLAIN statement
+///
+/// Only the SELECT statements can be explained. The ANALYZE option is
+/// refused because it would run the query and show the actual number of rows.
+/// The output can be redacted only in TEXT and JSON format.
+///
+/// * stmt is the EXPLAIN statement
+/// * policy is the masking policy to apply
+///
+/// Returns the walker, so that the statement can be audited
+///
+pub fn mask_explained_query(stmt: *mut pg_sys::ExplainStmt, policy: String) -> walker::TreeWalker {
+    let stmt = unsafe { PgBox::from_pg(stmt) };
+
+    let options = unsafe { PgList::<pg_sys::DefElem>::from_pg(stmt.options) };
+    for opt in options.iter_ptr() {
+        let name = unsafe { CStr::from_ptr((*opt).defname) }.to_str().unwrap();
+        match name {
+            "analyze" if unsafe { pg_sys::defGetBoolean(opt) } => {
+                error::feature_not_supported("EXPLAIN ANALYZE for a masked role").ereport()
+            }
+            "format" => {
+                let format = unsafe { CStr::from_ptr(pg_sys::defGetString(opt)) }
+                    .to_str()
+                    .unwrap()
+                    .to_lowercase();
+                if format != "text" && format != "json" {
+                    error::feature_not_supported(&format!(
+                        "EXPLAIN (FORMAT {format}) for a masked role"
+                    ))
+                    .ereport()
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // EXPLAIN EXECUTE, EXPLAIN CREATE TABLE AS, etc. are utility statements
+    // that would not be masked
+    let is_select = unsafe { pgrx::is_a(stmt.query, pg_sys::NodeTag::T_Query) }
+        && unsafe { (*(stmt.query as *mut pg_sys::Query)).commandType }
+            == pg_sys::CmdType::CMD_SELECT;
+    if !is_select {
+        error::insufficient_privilege("role is masked".to_string()).ereport();
+    }
+
+    let query = unsafe { PgBox::from_pg(stmt.query as *mut pg_sys::Query) };
+    let mut walker = walker::TreeWalker::new(policy);
+    unsafe {
+        walker.rewrite(&query);
+    }
+    walker
+}
+
+/// Remove the sensitive information from the output of an EXPLAIN statement
+///
+/// The output is processed line by line. This works for the TEXT format and
+/// the JSON format because each plan property is printed on its own line.
+///
+pub fn redact(plan: &str) -> String {
+    static TEXT_PROPERTY: OnceLock<Regex> = OnceLock::new();
+    static JSON_PROPERTY: OnceLock<Regex> = OnceLock::new();
+    static TEXT_ROWS: OnceLock<Regex> = OnceLock::new();
+    static JSON_ROWS: OnceLock<Regex> = OnceLock::new();
+
+    let text_property = TEXT_PROPERTY
+        .get_or_init(|| Regex::new(&format!(r"^(\s*)({REDACTED_PROPERTIES}): .*$")).unwrap());
+    let json_property = JSON_PROPERTY.get_or_init(|| {
+        Regex::new(&format!(r#"^(\s*"(?:{REDACTED_PROPERTIES})": ).*?(,?)$"#)).unwrap()
+    });
+    let text_rows = TEXT_ROWS.get_or_init(|| Regex::new(r"\brows=\d+").unwrap());
+    let json_rows = JSON_ROWS.get_or_init(|| Regex::new(r#"^(\s*"Plan Rows": )\d+(,?)$"#).unwrap());
+
+    plan.split('\n')
+        .map(|line| {
+            if text_property.is_match(line) {
+                text_property
+                    .replace(line, format!("${{1}}${{2}}: {REDACTED}"))
+                    .to_string()
+            } else if json_property.is_match(line) {
+                json_property
+                    .replace(line, format!("${{1}}\"{REDACTED}\"${{2}}"))
+                    .to_string()
+            } else if json_rows.is_match(line) {
+                json_rows.replace(line, "${1}null${2}").to_string()
+            } else {
+                text_rows.replace_all(line, "rows=?").to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+//----------------------------------------------------------------------------
+// Destination Receiver
+//----------------------------------------------------------------------------
+
+/// A DestReceiver that redacts the EXPLAIN output before sending it to the
+/// original receiver
+///
+#[repr(C)]
+struct RedactedReceiver {
+    /// Must be the first field, Postgres will see this struct as a
+    /// DestReceiver
+    pub_: pg_sys::DestReceiver,
+    inner: *mut pg_sys::DestReceiver,
+}
+
+/// Wrap the destination of an EXPLAIN statement
+///
+pub fn redacted_receiver(dest: PgBox<pg_sys::DestReceiver>) -> PgBox<pg_sys::DestReceiver> {
+    let mut receiver = unsafe { PgBox::<RedactedReceiver>::alloc0() };
+    receiver.pub_.receiveSlot = Some(redacted_receive_slot);
+    receiver.pub_.rStartup = Some(redacted_startup);
+    receiver.pub_.rShutdown = Some(redacted_shutdown);
+    receiver.pub_.rDestroy = Some(redacted_destroy);
+    receiver.pub_.mydest = dest.mydest;
+    receiver.inner = dest.as_ptr();
+    unsafe { PgBox::from_pg(receiver.into_pg() as *mut pg_sys::DestReceiver) }
+}
+
+fn inner(receiver: *mut pg_sys::DestReceiver) -> *mut pg_sys::DestReceiver {
+    unsafe { (*(receiver as *mut RedactedReceiver)).inner }
+}
+
+/// The EXPLAIN output is a virtual tuple with a single text column
+///
+#[pg_guard]
+unsafe extern "C-unwind" fn redacted_receive_slot(
+    slot: *mut pg_sys::TupleTableSlot,
+    receiver: *mut pg_sys::DestReceiver,
+) -> bool {
+    if (*slot).tts_nvalid > 0 && !*(*slot).tts_isnull {
+        let plan = String::from_datum(*(*slot).tts_values, false).unwrap_or_default();
+        if let Some(datum) = redact(&plan).into_datum() {
+            *(*slot).tts_values = datum;
+        }
+    }
+    let inner = inner(receiver);
+    (*inner).receiveSlot.unwrap()(slot, inner)
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn redacted_startup(
+    receiver: *mut pg_sys::DestReceiver,
+    operation: ::core::ffi::c_int,
+    typeinfo: pg_sys::TupleDesc,
+) {
+    let inner = inner(receiver);
+    (*inner).rStartup.unwrap()(inner, operation, typeinfo)
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn redacted_shutdown(receiver: *mut pg_sys::DestReceiver) {
+    let inner = inner(receiver);
+    (*inner).rShutdown.unwrap()(inner)
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn redacted_destroy(receiver: *mut pg_sys::DestReceiver) {
+    let inner = inner(receiver);
+    (*inner).rDestroy.unwrap()(inner)
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::explain::*;
+
+    #[test]
+    fn test_redact_text() {
+        let plan = "Seq Scan on person  (cost=0.00..25.88 rows=6 width=64)\n  \
+                    Filter: (firstname = 'Sarah'::text)";
+        assert_eq!(
+            redact(plan),
+            "Seq Scan on person  (cost=0.00..25.88 rows=? width=64)\n  Filter: <redacted>"
+        );
+    }
+
+    #[test]
+    fn test_redact_text_unchanged() {
+        let plan = "  ->  Hash  (cost=1.01..1.01 width=4)";
+        assert_eq!(redact(plan), plan);
+    }
+
+    #[test]
+    fn test_redact_json() {
+        let plan = r#"[
+  {
+    "Plan": {
+      "Node Type": "Seq Scan",
+      "Plan Rows": 6,
+      "Plan Width": 64,
+      "Filter": "(firstname = 'Sarah,'::text)",
+      "Output": ["firstname", "lastname"]
+    }
+  }
+]"#;
+        let expected = r#"[
+  {
+    "Plan": {
+      "Node Type": "Seq Scan",
+      "Plan Rows": null,
+      "Plan Width": 64,
+      "Filter": "<redacted>",
+      "Output": "<redacted>"
+    }
+  }
+]"#;
+        assert_eq!(redact(plan), expected);
+    }
+}
@@ -0,0 +1,63 @@
+///
+/// # Filtering
+///
+use crate::masking;
+use crate::masking_rule;
+use crate::masking_rule::MaskingRule;
+use pgrx::prelude::*;
+
+/// Return the predicate of the FILTER rule declared on a table
+///
+/// Only the rows matching this predicate are revealed to the masked roles
+///
+pub fn get_filter(relid: pg_sys::Oid, policy: &str) -> Result<&str, masking::Reason> {
+    let seclabel = masking::rule_on_table(relid, policy)?;
+    let Ok(MaskingRule::Filter(predicate)) = masking_rule::parse(seclabel) else {
+        return Err(masking::Reason::InvalidInput);
+    };
+    Ok(predicate)
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::filtering::*;
+    use crate::fixture;
+    use crate::label_providers::ANON_DEFAULT_MASKING_POLICY;
+    use crate::masking;
+
+    #[pg_test]
+    fn test_get_filter() {
+        let relid = fixture::create_table_person();
+        Spi::run(
+            "SECURITY LABEL FOR anon ON TABLE person IS 'FILTER WITH firstname <> ''Sarah''';",
+        )
+        .unwrap();
+        assert_eq!(
+            Ok("firstname <> 'Sarah'"),
+            get_filter(relid, ANON_DEFAULT_MASKING_POLICY)
+        );
+    }
+
+    #[pg_test]
+    fn test_get_filter_tablesample() {
+        let relid = fixture::create_table_person();
+        assert_eq!(
+            Err(masking::Reason::InvalidInput),
+            get_filter(relid, ANON_DEFAULT_MASKING_POLICY)
+        );
+    }
+
+    #[pg_test]
+    fn test_get_filter_no_policy() {
+        let relid = fixture::create_table_person();
+        assert_eq!(
+            Err(masking::Reason::NoRule),
+            get_filter(relid, "does_not_exist")
+        );
+    }
+}
@@ -2,14 +2,33 @@
 // GUC Variables
 //----------------------------------------------------------------------------
 
+use crate::audit;
 use pgrx::*;
 use std::ffi::CStr;
 
+pub static ANON_AGGREGATE_ONLY_POLICIES: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    }));
+
+pub static ANON_AUDIT_LOG: GucSetting<audit::Verbosity> =
+    GucSetting::<audit::Verbosity>::new(audit::Verbosity::Off);
+
 pub static ANON_DUMMY_LOCALE: GucSetting<Option<&'static CStr>> =
     GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
         CStr::from_bytes_with_nul_unchecked(b"en_US\0")
     }));
 
+pub static ANON_FORBIDDEN_FUNCTIONS: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    }));
+
+pub static ANON_INFERENCE_GUARD: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    }));
+
 pub static ANON_K_ANONYMITY_PROVIDER: GucSetting<Option<&'static CStr>> =
     GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
         CStr::from_bytes_with_nul_unchecked(b"k_anonymity\0")
@@ -20,16 +39,35 @@ pub static ANON_MASKING_POLICIES: GucSetting<Option<&'static CStr>> =
         CStr::from_bytes_with_nul_unchecked(b"\0")
     }));
 
+pub static ANON_MASK_CALLING_ROLE: GucSetting<bool> = GucSetting::<bool>::new(false);
+
 pub static ANON_PRIVACY_BY_DEFAULT: GucSetting<bool> = GucSetting::<bool>::new(false);
 
+pub static ANON_REDACTED_EXPLAIN: GucSetting<bool> = GucSetting::<bool>::new(false);
+
 pub static ANON_RESTRICT_TO_TRUSTED_SCHEMAS: GucSetting<bool> = GucSetting::<bool>::new(true);
 
+pub static ANON_SESSION_TOKEN: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    }));
+
+pub static ANON_SESSION_TOKEN_SECRET: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    }));
+
 pub static ANON_STRICT_MODE: GucSetting<bool> = GucSetting::<bool>::new(true);
 
 pub static ANON_TRANSPARENT_DYNAMIC_MASKING: GucSetting<bool> = GucSetting::<bool>::new(false);
 
 pub static ANON_STATIC_MASKING: GucSetting<bool> = GucSetting::<bool>::new(true);
 
+pub static ANON_WRITE_THROUGH_POLICIES: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"\0")
+    }));
+
 // The GUC vars below are not used in the Rust code
 // but they are used in the plpgsql code
 
@@ -56,6 +94,24 @@ static ANON_MASK_SCHEMA: GucSetting<Option<&'static CStr>> =
 // Register the GUC parameters for the extension
 //
 pub fn register_gucs() {
+    GucRegistry::define_string_guc(
+        "anon.aggregate_only_policies",
+        "Masking policies whose masked roles can only run aggregated queries",
+        "A comma-separated list of policy:k, the groups smaller than k are suppressed and k is required",
+        &ANON_AGGREGATE_ONLY_POLICIES,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+
+    GucRegistry::define_enum_guc(
+        "anon.audit_log",
+        "Report the statements rewritten by the dynamic masking engine",
+        "The records are written in the server log as JSON lines",
+        &ANON_AUDIT_LOG,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+
     GucRegistry::define_string_guc(
         "anon.dummy_locale",
         "The default locale for the dummy data functions",
@@ -65,6 +121,24 @@ pub fn register_gucs() {
         GucFlags::SUPERUSER_ONLY,
     );
 
+    GucRegistry::define_string_guc(
+        "anon.forbidden_functions",
+        "Functions that the masked roles are not allowed to call",
+        "A comma-separated list of qualified function names",
+        &ANON_FORBIDDEN_FUNCTIONS,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+
+    GucRegistry::define_string_guc(
+        "anon.inference_guard",
+        "Check the masked columns used in the predicates of the masked roles",
+        "A comma-separated list of policy:action, the action is audit, warning or error",
+        &ANON_INFERENCE_GUARD,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+
     GucRegistry::define_string_guc(
         "anon.k_anonymity_provider",
         "The security label provider used for k-anonymity",
@@ -91,6 +165,15 @@ pub fn register_gucs() {
         GucFlags::SUPERUSER_ONLY, /* | GucFlags::LIST_INPUT */
     );
 
+    GucRegistry::define_bool_guc(
+        "anon.mask_calling_role",
+        "Mask the queries when the calling role or the session user is masked",
+        "The queries inside a SECURITY DEFINER function are masked if the role that called the function is masked",
+        &ANON_MASK_CALLING_ROLE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
     GucRegistry::define_bool_guc(
         "anon.privacy_by_default",
         "Mask all columns with NULL (or the default value for NOT NULL columns)",
@@ -108,6 +191,15 @@ pub fn register_gucs() {
         GucFlags::default(),
     );
 
+    GucRegistry::define_bool_guc(
+        "anon.redacted_explain",
+        "Allow the masked roles to run EXPLAIN, with a redacted output",
+        "The query is masked and the expressions and row estimates are removed from the plan",
+        &ANON_REDACTED_EXPLAIN,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
     GucRegistry::define_bool_guc(
         "anon.static_masking",
         "Static Masking engine",
@@ -126,6 +218,24 @@ pub fn register_gucs() {
         GucFlags::SUPERUSER_ONLY,
     );
 
+    GucRegistry::define_string_guc(
+        "anon.session_token",
+        "A signed token selecting the masking policy of the session",
+        "The token can only be set when the connection is opened",
+        &ANON_SESSION_TOKEN,
+        GucContext::Backend,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "anon.session_token_secret",
+        "The secret key used to sign the session tokens",
+        "The session tokens are ignored when the secret is empty",
+        &ANON_SESSION_TOKEN_SECRET,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+
     GucRegistry::define_bool_guc(
         "anon.strict_mode",
         "A masking rule cannot change a column data type, unless you disable this",
@@ -135,6 +245,15 @@ pub fn register_gucs() {
         GucFlags::default(),
     );
 
+    GucRegistry::define_string_guc(
+        "anon.write_through_policies",
+        "Masking policies whose masked roles are allowed to write",
+        "The masked columns can't be written and they are read through their masks",
+        &ANON_WRITE_THROUGH_POLICIES,
+        GucContext::Suset,
+        GucFlags::SUPERUSER_ONLY,
+    );
+
     // The GUC vars below are not used in the Rust code
     // but they are used in the plpgsql code
 
@@ -1,7 +1,13 @@
+use crate::cache;
 use crate::error;
+use crate::explain;
 use crate::guc;
+use crate::limits;
 use crate::log;
 use crate::masking;
+use crate::preview;
+use crate::session;
+use crate::stats;
 use crate::utils;
 use crate::walker;
 use pgrx::prelude::*;
@@ -37,33 +43,50 @@ use pgrx::list::old_list::PgList;
 /// * `pstmt` is the utility statement
 /// * `policy` is the masking policy to apply
 ///
-fn pa_rewrite_utility(pstmt: &PgBox<pg_sys::PlannedStmt>, policy: String) {
+/// Returns the masked relations read by the statement
+///
+fn pa_rewrite_utility(pstmt: &PgBox<pg_sys::PlannedStmt>, policy: String) -> Vec<pg_sys::Oid> {
     let command_type = pstmt.commandType;
     assert!(command_type == pg_sys::CmdType::CMD_UTILITY);
 
     // here is a list of statements we can't really handle for now
     //
-    // * EXPLAIN can leak metadata to the masked roles
+    // * EXPLAIN can leak metadata to the masked roles, unless its output is
+    //   redacted
     // * TRUNCATE is not allowed because masked roles are read-only
     //
     unsafe {
-        if pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_ExplainStmt)
+        if (pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_ExplainStmt)
+            && !guc::ANON_REDACTED_EXPLAIN.get())
             || pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_TruncateStmt)
         {
             error::insufficient_privilege("role is masked".to_string()).ereport();
         }
     }
 
+    if unsafe { pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_ExplainStmt) } {
+        log::debug1!("Anon: EXPLAIN found");
+        // The query is masked here and the output is redacted by the
+        // destination receiver, see `process_utility_hook()`
+        let walker =
+            explain::mask_explained_query(pstmt.utilityStmt as *mut pg_sys::ExplainStmt, policy);
+        walker.audit(pstmt.utilityStmt, pstmt.queryId);
+        return walker.masked_relations();
+    }
+
     if unsafe { pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_DeclareCursorStmt) } {
         log::debug1!("Anon: CURSOR found");
         let cursorstmt =
             unsafe { PgBox::from_pg(pstmt.utilityStmt as *mut pg_sys::DeclareCursorStmt) };
         let cursorquery = unsafe { PgBox::from_pg(cursorstmt.query as *mut pg_sys::Query) };
 
+        let mut walker = walker::TreeWalker::new(policy);
         unsafe {
-            walker::TreeWalker::new(policy).rewrite(&cursorquery);
+            walker.rewrite(&cursorquery);
         }
+        walker.audit(pstmt.utilityStmt, pstmt.queryId);
         cursorstmt.into_pg();
+        return walker.masked_relations();
     }
 
     if unsafe { pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_CopyStmt) } {
@@ -79,14 +102,14 @@ fn pa_rewrite_utility(pstmt: &PgBox<pg_sys::PlannedStmt>, policy: String) {
 
         // ignore `COPY FROM` statements
         if copystmt.is_from {
-            return;
+            return vec![];
         }
 
         // This is a `COPY (SELECT ...) TO` statements
         // The SELECT subquery will be masked later by the `rewrite_walker()`
         // when triggered by the post_parse_analyze hook
         if copystmt.relation.is_null() {
-            return;
+            return vec![];
         }
 
         // We now know this is a `COPY xxx TO ...` statement
@@ -147,7 +170,7 @@ fn pa_rewrite_utility(pstmt: &PgBox<pg_sys::PlannedStmt>, policy: String) {
 
         let Some(relname) = utils::get_relation_qualified_name(relid) else {
             error::internal("Cannot get relation name");
-            return;
+            return vec![];
         };
         let msq_sql = format!("SELECT {} FROM {relname}", attributes.join(","));
         let msq_raw_stmt = masking::parse_subquery(msq_sql.clone());
@@ -160,6 +183,94 @@ fn pa_rewrite_utility(pstmt: &PgBox<pg_sys::PlannedStmt>, policy: String) {
 
         // Return the pointer to Postgres
         copystmt.into_pg();
+
+        if cache::masking_subquery(relid, policy).is_some() {
+            return vec![relid];
+        }
+    }
+    vec![]
+}
+
+/// Returns the masking policy applied to the current query, if any
+///
+/// By default, this is the masking policy of the current user. Inside a
+/// SECURITY DEFINER function, the current user is the owner of the function.
+/// When `anon.mask_calling_role` is enabled, the query is also masked if the
+/// role that called the function (or the session user) is masked.
+///
+/// The session may also select a policy, see the `session` module. The
+/// precedence rules are:
+///
+///   1. The policy of a valid session token comes first, it is signed by the
+///      administrator and it may replace the policy of the role
+///   2. Then the masking policy of the role, as described above
+///   3. Then the policy of the first session policy rule matching the
+///      session, it only applies to the roles that are not masked
+///
+/// While a superuser previews a role, the session is ignored, see the
+/// `preview` module.
+///
+pub fn current_masking_policy() -> Option<String> {
+    // The session of a superuser previewing a role is ignored
+    let session = match preview::previewed_role() {
+        Some(_) => session::SessionPolicies::default(),
+        None => session::policies(),
+    };
+    if session.token.is_some() {
+        return session.token;
+    }
+    let policy = cache::masking_policy(unsafe { pg_sys::GetUserId() });
+    if policy.is_some() {
+        return policy;
+    }
+    if guc::ANON_MASK_CALLING_ROLE.get() {
+        let [outer_userid, session_userid] = preview::calling_roles();
+        let policy =
+            cache::masking_policy(outer_userid).or_else(|| cache::masking_policy(session_userid));
+        if policy.is_some() {
+            return policy;
+        }
+    }
+    session.rule
+}
+
+/// Returns true if a role statement changes the membership of a masked role
+///
+/// The members of a masked role are masked too, so adding or removing a
+/// member (or dropping the masked role itself) changes their masking policy.
+/// The other role statements don't need to reset the plan cache.
+///
+unsafe fn changes_masked_membership(stmt: *mut pg_sys::Node) -> bool {
+    let is_masked = |roleid: pg_sys::Oid| {
+        roleid != pg_sys::InvalidOid && cache::masking_policy(roleid).is_some()
+    };
+    let is_masked_rolespec = |rolespec: *mut pg_sys::RoleSpec| {
+        (*rolespec).roletype != pg_sys::RoleSpecType::ROLESPEC_PUBLIC
+            && is_masked(pg_sys::get_rolespec_oid(rolespec, true))
+    };
+
+    if pgrx::is_a(stmt, pg_sys::NodeTag::T_GrantRoleStmt) {
+        let grant = stmt as *mut pg_sys::GrantRoleStmt;
+        PgList::<pg_sys::AccessPriv>::from_pg((*grant).granted_roles)
+            .iter_ptr()
+            .any(|role| {
+                !(*role).priv_name.is_null()
+                    && is_masked(pg_sys::get_role_oid((*role).priv_name, true))
+            })
+    } else if pgrx::is_a(stmt, pg_sys::NodeTag::T_DropRoleStmt) {
+        let drop = stmt as *mut pg_sys::DropRoleStmt;
+        PgList::<pg_sys::RoleSpec>::from_pg((*drop).roles)
+            .iter_ptr()
+            .any(is_masked_rolespec)
+    } else if pgrx::is_a(stmt, pg_sys::NodeTag::T_AlterRoleStmt) {
+        // ALTER GROUP .. ADD USER / DROP USER
+        let alter = stmt as *mut pg_sys::AlterRoleStmt;
+        PgList::<pg_sys::DefElem>::from_pg((*alter).options)
+            .iter_ptr()
+            .any(|option| std::ffi::CStr::from_ptr((*option).defname).to_bytes() == b"rolemembers")
+            && is_masked_rolespec((*alter).role)
+    } else {
+        false
     }
 }
 
@@ -198,14 +309,35 @@ impl pgrx::hooks::PgHooks for AnonHooks {
             completion_tag: *mut pg_sys::QueryCompletion,
         ) -> HookResult<()>,
     ) -> HookResult<()> {
+        let mut dest = dest;
         if unsafe { pg_sys::IsTransactionState() } {
-            let uid = unsafe { pg_sys::GetUserId() };
+            // The other backends are notified when the transaction commits
+            if unsafe { changes_masked_membership(pstmt.utilityStmt) } {
+                cache::invalidate_masked_roles();
+            }
 
             // Rewrite the utility command when transparent dynamic masking
             // is enabled and the role is masked
             if guc::ANON_TRANSPARENT_DYNAMIC_MASKING.get() {
-                if let Some(masking_policy) = masking::get_masking_policy(uid) {
-                    pa_rewrite_utility(&pstmt, masking_policy);
+                if let Some(masking_policy) = current_masking_policy() {
+                    let counter =
+                        if unsafe { pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_CopyStmt) } {
+                            stats::Counter::Copy
+                        } else {
+                            stats::Counter::Query
+                        };
+                    stats::track(
+                        &masking_policy.clone(),
+                        counter,
+                        || pa_rewrite_utility(&pstmt, masking_policy),
+                        |relids| relids.clone(),
+                    );
+
+                    // At this stage, we know that the redacted EXPLAIN is
+                    // enabled, otherwise the statement would be rejected
+                    if unsafe { pgrx::is_a(pstmt.utilityStmt, pg_sys::NodeTag::T_ExplainStmt) } {
+                        dest = explain::redacted_receiver(dest);
+                    }
                 }
             }
         }
@@ -238,18 +370,61 @@ impl pgrx::hooks::PgHooks for AnonHooks {
             jumble_state: Option<PgBox<JumbleState>>,
         ) -> HookResult<()>,
     ) -> HookResult<()> {
-        if unsafe { pg_sys::IsTransactionState() } {
-            let uid = unsafe { pg_sys::GetUserId() };
-            if guc::ANON_TRANSPARENT_DYNAMIC_MASKING.get() {
-                if let Some(masking_policy) = masking::get_masking_policy(uid) {
-                    unsafe {
-                        walker::TreeWalker::new(masking_policy).rewrite(&query);
+        let mut rewritten = None;
+        if unsafe { pg_sys::IsTransactionState() } && guc::ANON_TRANSPARENT_DYNAMIC_MASKING.get() {
+            if let Some(masking_policy) = current_masking_policy() {
+                let walker = stats::track(
+                    &masking_policy.clone(),
+                    stats::Counter::Query,
+                    || {
+                        let mut walker = walker::TreeWalker::new(masking_policy);
+                        unsafe {
+                            walker.rewrite(&query);
+                        }
+                        walker
+                    },
+                    |walker| walker.masked_relations(),
+                );
+                rewritten = Some((walker, query.as_ptr()));
+            }
+        }
+        // Call the previous hook (if any)
+        let result = prev_hook(parse_state, query, jumble_state);
+
+        // The statement is reported after the previous hooks, because
+        // pg_stat_statements computes the queryid here with PG13
+        if let Some((walker, query)) = rewritten {
+            walker.audit(query as *mut pg_sys::Node, unsafe { (*query).queryId });
+        }
+        result
+    }
+
+    /// The executor_start hook is called before each statement is executed
+    ///
+    /// It is used to arm the statement timeout of the masked roles when the
+    /// statement reads a masked relation, see the `limits` module
+    ///
+    fn executor_start(
+        &mut self,
+        query_desc: PgBox<pg_sys::QueryDesc>,
+        eflags: i32,
+        prev_hook: fn(query_desc: PgBox<pg_sys::QueryDesc>, eflags: i32) -> HookResult<()>,
+    ) -> HookResult<()> {
+        if unsafe { pg_sys::IsTransactionState() }
+            && guc::ANON_TRANSPARENT_DYNAMIC_MASKING.get()
+            && (eflags & pg_sys::EXEC_FLAG_EXPLAIN_ONLY as i32) == 0
+        {
+            // Only the statements rewritten by the masking engine are limited
+            if let Some(policy) = current_masking_policy() {
+                if let Some(timeout) = limits::current_limits(&policy).statement_timeout {
+                    if unsafe { limits::reads_protected_relation(query_desc.plannedstmt, &policy) }
+                    {
+                        limits::enable_statement_timeout(timeout);
                     }
                 }
             }
         }
-        // Call the previous hook (if any)
-        prev_hook(parse_state, query, jumble_state)
+        prev_hook(query_desc, eflags)
     }
 }
 
@@ -257,6 +432,7 @@ impl pgrx::hooks::PgHooks for AnonHooks {
 #[pg_schema]
 mod tests {
     use crate::fixture;
+    use pgrx::list::old_list::PgList;
     use pgrx::prelude::*;
 
     //
@@ -284,6 +460,34 @@ mod tests {
         .unwrap();
     }
 
+    fn changes_masked_membership(statement: &str) -> bool {
+        let statement = std::ffi::CString::new(statement).unwrap();
+        unsafe {
+            let raw_stmts =
+                PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(statement.as_ptr()));
+            super::changes_masked_membership((*raw_stmts.get_ptr(0).unwrap()).stmt)
+        }
+    }
+
+    #[pg_test]
+    fn test_changes_masked_membership() {
+        fixture::create_masked_role();
+        Spi::run("CREATE ROLE robin; CREATE ROLE alfred;").unwrap();
+        assert!(changes_masked_membership("GRANT batman TO robin"));
+        assert!(changes_masked_membership("REVOKE batman FROM robin"));
+        assert!(changes_masked_membership(
+            "ALTER GROUP batman ADD USER robin"
+        ));
+        assert!(changes_masked_membership("DROP ROLE robin, batman"));
+        assert!(!changes_masked_membership("GRANT alfred TO robin"));
+        assert!(!changes_masked_membership("GRANT robin TO batman"));
+        assert!(!changes_masked_membership("ALTER ROLE batman NOLOGIN"));
+        assert!(!changes_masked_membership("DROP ROLE IF EXISTS joker"));
+        assert!(!changes_masked_membership(
+            "CREATE ROLE joker IN ROLE batman"
+        ));
+    }
+
     #[pg_test]
     fn test_security_label() {
         fixture::create_table_person();
@@ -314,6 +518,89 @@ mod tests {
         .unwrap();
     }
 
+    #[pg_test(error = "Anon: role is masked")]
+    fn test_explain_masked_role() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET ROLE batman;
+            EXPLAIN SELECT 1;
+        ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_redacted_explain() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET anon.redacted_explain TO TRUE;
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT SELECT ON ALL TABLES IN SCHEMA public TO batman;
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        let plan: Vec<String> = Spi::connect(|client| {
+            client
+                .select(
+                    "EXPLAIN SELECT * FROM person WHERE firstname = 'Sarah'",
+                    None,
+                    &[],
+                )
+                .unwrap()
+                .map(|row| row.get::<String>(1).unwrap().unwrap_or_default())
+                .collect()
+        });
+        let plan = plan.join("\n");
+        assert!(plan.contains("Filter: <redacted>"));
+        assert!(plan.contains("rows=?"));
+        assert!(!plan.contains("Sarah"));
+    }
+
+    #[pg_test(error = "Anon: EXPLAIN ANALYZE for a masked role is not supported")]
+    fn test_redacted_explain_analyze() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET anon.redacted_explain TO TRUE;
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT SELECT ON ALL TABLES IN SCHEMA public TO batman;
+            SET ROLE batman;
+            EXPLAIN ANALYZE SELECT * FROM person;
+        ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_mask_calling_role() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET anon.mask_calling_role TO TRUE;
+            CREATE FUNCTION public.lastname() RETURNS TEXT
+            LANGUAGE plpgsql SECURITY DEFINER
+            AS $$ BEGIN RETURN (SELECT lastname FROM public.person LIMIT 1); END $$;
+            GRANT USAGE ON SCHEMA public TO batman;
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        // The function is owned by an unmasked role
+        let lastname = Spi::get_one::<String>("SELECT public.lastname()").unwrap();
+        assert_eq!(lastname, None);
+    }
+
     #[pg_test]
     fn test_post_parse_analyze() {
         fixture::create_table_person();
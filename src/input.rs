@@ -3,8 +3,10 @@ use crate::error;
 use crate::guc;
 use crate::macros;
 use crate::masking;
-use crate::re;
+use crate::masking_rule;
+use crate::masking_rule::MaskingRule;
 use crate::walker;
+use pgrx::list::old_list::PgList;
 use pgrx::prelude::*;
 use std::ffi::CString;
 use std::os::raw::c_char;
@@ -32,18 +34,47 @@ pub fn check_function(expr: &str, policy: &'static str) -> Result<(), String> {
         return Err(format!("{expr} is not a function"));
     }
 
-    if !guc::ANON_RESTRICT_TO_TRUSTED_SCHEMAS.get() {
-        return Ok(());
-    }
-
     // Walk through the parse tree and check that the function itself and
     // all other functions used as parameters belong to a trusted schema.
     // The goal is to block privilege escalation attacks using something like:
     //
     // `MASKED WITH FUNCTION pg_catalog.upper(public.elevate())`
     //
+    check_trusted_functions(expr, &func, policy)
+}
+
+/// check that an expression is a valid condition for a masking rule
+///
+/// The predicate is evaluated for each row, so it must follow the same rules
+/// as the masking functions: all the functions must be trusted. Subqueries
+/// are not allowed because they could read data from other relations.
+///
+pub fn check_predicate(expr: &str, policy: &'static str) -> Result<(), String> {
+    let Ok(pred) = parse_expression(expr) else {
+        return Err(format!("{expr} is not a valid predicate"));
+    };
+
+    let mut walker = walker::TreeWalker::new(policy.to_string());
+    if unsafe { walker.has_sublink(&pred) } {
+        return Err(format!("{expr} contains a subquery"));
+    }
+
+    check_trusted_functions(expr, &pred, policy)
+}
+
+/// check that all the functions used in an expression are trusted
+///
+fn check_trusted_functions(
+    expr: &str,
+    node: &PgBox<pg_sys::Node>,
+    policy: &'static str,
+) -> Result<(), String> {
+    if !guc::ANON_RESTRICT_TO_TRUSTED_SCHEMAS.get() {
+        return Ok(());
+    }
+
     let mut walker = walker::TreeWalker::new(policy.to_string());
-    if unsafe { walker.is_untrusted(&func) } {
+    if unsafe { walker.is_untrusted(node) } {
         match walker.reason.expect("The reason should be defined") {
             Reason::SchemaNotTrusted => {
                 return Err(format!("{expr} does not belong in a TRUSTED schema"))
@@ -82,6 +113,53 @@ pub fn check_tablesample(expr: &str) -> Result<(), String> {
     Ok(())
 }
 
+/// Parse a sampling ratio (e.g. `BERNOULLI(10) REPEATABLE(42)`) and return
+/// the RangeTableSample raw node
+///
+pub fn parse_tablesample(ratio: &str) -> Result<PgBox<pg_sys::RangeTableSample>, String> {
+    if ratio.is_empty() {
+        return Err("Expression is empty".to_string());
+    }
+
+    let query_string = format!("SELECT 1 FROM foo TABLESAMPLE {ratio}");
+    let query_c_string = CString::new(query_string.as_str()).unwrap();
+
+    let raw_parsetree_list = PgTryBuilder::new(|| {
+        Some(unsafe { compat::raw_parser(query_c_string.as_c_str().as_ptr() as *const c_char) })
+    })
+    .catch_others(|_| None)
+    .execute();
+
+    // Only one statement in the parsetree is allowed
+    if raw_parsetree_list.is_none()
+        || raw_parsetree_list.unwrap().is_null()
+        || unsafe { raw_parsetree_list.unwrap().as_ref().unwrap().length > 1 }
+    {
+        return Err(format!("{ratio} is not a valid sampling ratio"));
+    }
+
+    let raw_stmt = unsafe {
+        PgBox::<pg_sys::RawStmt>::from_pg(
+            pg_sys::list_nth(raw_parsetree_list.unwrap(), 0) as *mut pg_sys::RawStmt
+        )
+    };
+
+    let stmt =
+        unsafe { PgBox::<pg_sys::SelectStmt>::from_pg(raw_stmt.stmt as *mut pg_sys::SelectStmt) };
+
+    // The FROM clause should contain only the sampled relation
+    let from_clause = unsafe { PgList::<pg_sys::Node>::from_pg(stmt.fromClause) };
+    match from_clause.head() {
+        Some(rts)
+            if from_clause.len() == 1
+                && unsafe { pgrx::is_a(rts, pg_sys::NodeTag::T_RangeTableSample) } =>
+        {
+            Ok(unsafe { PgBox::from_pg(rts as *mut pg_sys::RangeTableSample) })
+        }
+        _ => Err(format!("{ratio} is not a valid sampling ratio")),
+    }
+}
+
 /// check that an expression is a valid masking value
 ///
 pub fn check_value(expr: &str) -> Result<(), String> {
@@ -166,11 +244,10 @@ pub fn is_trusted_function(
             }
 
             // Read the security label and check its content
-            if re::is_match_trusted(seclabel) {
-                trusted = Some(true);
-            }
-            if re::is_match_untrusted(seclabel) {
-                trusted = Some(false);
+            match masking_rule::parse(seclabel) {
+                Ok(MaskingRule::Trusted) => trusted = Some(true),
+                Ok(MaskingRule::Untrusted) => trusted = Some(false),
+                _ => (),
             }
         }
     }
@@ -200,7 +277,7 @@ fn is_trusted_namespace(namespace_id: pg_sys::Oid, policy: &str) -> Result<(), R
     };
 
     if let Ok(seclabel) = masking::rule_on_schema(namespace_id, policy) {
-        if re::is_match_trusted(seclabel) {
+        if masking_rule::parse(seclabel) == Ok(MaskingRule::Trusted) {
             return Ok(());
         }
     }
@@ -289,6 +366,26 @@ mod tests {
         assert!(check_function("foo()", "anon").is_err());
     }
 
+    #[pg_test]
+    fn test_check_predicate_ok() {
+        let _outfit = fixture::create_masking_functions();
+        assert!(check_predicate("country <> 'FR'", "anon").is_ok());
+        assert!(check_predicate("owner = current_user", "anon").is_ok());
+        assert!(check_predicate("a IS NULL OR outfit.mask(b) > 0", "anon").is_ok());
+    }
+
+    #[pg_test]
+    fn test_check_predicate_err() {
+        let _outfit = fixture::create_masking_functions();
+        assert!(check_predicate("", "anon").is_err());
+        assert!(check_predicate("owner_id = current_user_id()", "anon").is_err());
+        assert!(check_predicate("outfit.belt() = 'x'", "anon").is_err());
+        assert!(check_predicate("outfit.cape() > 0", "anon").is_err());
+        assert!(check_predicate("id IN (SELECT id FROM vip)", "anon").is_err());
+        assert!(check_predicate("EXISTS (SELECT 1)", "anon").is_err());
+        assert!(check_predicate("a = 1, b = 2", "anon").is_err());
+    }
+
     #[pg_test]
     fn test_check_tablesample() {
         assert!(check_tablesample("TABLESAMPLE SYSTEM(10)").is_ok());
@@ -296,6 +393,18 @@ mod tests {
         assert!(check_tablesample("TABLESAMPLE SYSTEM(10); DROP TABLE Students;--").is_err());
     }
 
+    #[pg_test]
+    fn test_parse_tablesample() {
+        let rts = parse_tablesample("BERNOULLI(10) REPEATABLE(42)").unwrap();
+        assert!(!rts.repeatable.is_null());
+        let rts = parse_tablesample("SYSTEM(10)").unwrap();
+        assert!(rts.repeatable.is_null());
+        assert!(parse_tablesample("").is_err());
+        assert!(parse_tablesample("SYSTEM").is_err());
+        assert!(parse_tablesample("SYSTEM(10), bar").is_err());
+        assert!(parse_tablesample("SYSTEM(10); DROP TABLE Students;--").is_err());
+    }
+
     #[pg_test]
     fn test_check_value() {
         assert!(check_value("foo()").is_err());
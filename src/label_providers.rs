@@ -3,12 +3,15 @@
 ///
 /// The *_relabel functions are called every time a security label is declared
 ///
+use crate::cache;
 use crate::error;
 use crate::guc;
 use crate::input;
+use crate::limits;
 use crate::log;
 use crate::masking;
-use crate::re;
+use crate::masking_rule;
+use crate::masking_rule::{Mask, MaskingRule};
 use pgrx::prelude::*;
 use std::ffi::CStr;
 use std::ffi::CString;
@@ -69,10 +72,11 @@ unsafe extern "C-unwind" fn k_anonymity_object_relabel(
     if object.classId == pg_sys::RelationRelationId {
         let label_cstr = unsafe { CStr::from_ptr(seclabel_ptr) };
         let label = label_cstr.to_str().expect("Failed to convert seclabel");
-        if re::is_match_indirect_identifier(label) {
-            return;
+        match masking_rule::parse(label) {
+            Ok(MaskingRule::IndirectIdentifier) => return,
+            Ok(_) => error::invalid_label_for("a column", label, None).ereport(),
+            Err(e) => error::invalid_label_for("a column", label, Some(e.to_string())).ereport(),
         }
-        error::invalid_label_for("a column", label, None).ereport();
     }
 
     /* Everything else is not supported */
@@ -92,22 +96,28 @@ unsafe extern "C-unwind" fn masking_policy_object_relabel(
     object_ptr: *const pg_sys::ObjectAddress,
     seclabel_ptr: *const c_char,
 ) {
-    /* SECURITY LABEL FOR anon ON COLUMN foo.bar IS NULL */
-    if seclabel_ptr.is_null() {
-        return;
-    }
-
     // convert the object C pointer into a smart pointer
     let object = unsafe {
         PgBox::<pg_sys::ObjectAddress>::from_pg(object_ptr as *mut pg_sys::ObjectAddress)
     };
 
+    // The masking subqueries based on this object will be rebuilt.
+    // If the label is invalid, the transaction is rolled back and the
+    // invalidation is harmless
+    cache::invalidate_object(&object);
+
+    /* SECURITY LABEL FOR anon ON COLUMN foo.bar IS NULL */
+    if seclabel_ptr.is_null() {
+        return;
+    }
+
     // Extract the security label
     let label_cstr = unsafe { CStr::from_ptr(seclabel_ptr) };
     let label = label_cstr.to_str().expect("Failed to convert seclabel");
 
     match object.classId {
         /* SECURITY LABEL FOR anon ON FUNCTION public.foo() IS 'TRUSTED' */
+        /* SECURITY LABEL FOR anon ON FUNCTION public.foo() IS 'FORBIDDEN' */
         pg_sys::ProcedureRelationId => relabel_function(label),
 
         /* SECURITY LABEL FOR anon ON DATABASE d IS 'TABLESAMPLE SYSTEM(10)' */
@@ -128,7 +138,8 @@ unsafe extern "C-unwind" fn masking_policy_object_relabel(
                 relabel_table(label)
             } else {
                 /* SECURITY LABEL FOR anon ON COLUMN t.i IS '[...]' */
-                relabel_column(label)
+                relabel_column(label);
+                typecheck_column(&object, label)
             }
         }
 
@@ -144,17 +155,34 @@ unsafe extern "C-unwind" fn masking_policy_object_relabel(
 }
 
 fn relabel_column(label: &str) {
-    /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'MASKED WITH VALUE $x$' */
-    if let Some(val) = re::capture_value(label) {
-        let check_val = input::check_value(val);
-        if check_val.is_ok() {
-            return;
+    let (mask, condition) = match masking_rule::parse(label) {
+        /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'MASKED WITH ... [WHEN|UNLESS $x$]' */
+        Ok(MaskingRule::MaskedWith { mask, condition }) => (mask, condition),
+
+        /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'NOT MASKED' */
+        Ok(MaskingRule::NotMasked) => return,
+
+        Ok(_) => return error::invalid_label_for("a column", label, None).ereport(),
+        Err(e) => {
+            return error::invalid_label_for("a column", label, Some(e.to_string())).ereport()
+        }
+    };
+
+    /* SECURITY LABEL FOR anon ON COLUMN t.i IS '... WHEN $x$' */
+    /* SECURITY LABEL FOR anon ON COLUMN t.i IS '... UNLESS $x$' */
+    if let Some(condition) = condition {
+        if let Err(detail) =
+            input::check_predicate(condition.predicate, ANON_DEFAULT_MASKING_POLICY)
+        {
+            error::invalid_label_for("a column", label, Some(detail)).ereport();
         }
-        error::invalid_label_for("a column", label, Some(check_val.unwrap_err())).ereport();
     }
 
-    /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'MASKED WITH FUNCTION $x$' */
-    if let Some(func) = re::capture_function(label) {
+    let check = match mask {
+        /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'MASKED WITH VALUE $x$' */
+        Mask::Value(val) => input::check_value(val),
+
+        /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'MASKED WITH FUNCTION $x$' */
         //
         // Inside a *_relabel function, we can't know the name of the label
         // provider, because most of the extensions that use security labels
@@ -166,33 +194,49 @@ fn relabel_column(label: &str) {
         // the default masking policy. In consequence, if a function declared
         // as trusted, it is trusted for all policies.
         //
-        let check_func = input::check_function(func, ANON_DEFAULT_MASKING_POLICY);
-        if check_func.is_ok() {
-            return;
-        }
-        error::invalid_label_for("a column", label, Some(check_func.unwrap_err())).ereport();
+        Mask::Function(func) => input::check_function(func, ANON_DEFAULT_MASKING_POLICY),
+    };
+
+    if let Err(detail) = check {
+        error::invalid_label_for("a column", label, Some(detail)).ereport();
     }
+}
 
-    /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'NOT MASKED */
-    if re::is_match_not_masked(label) {
+/// Check that the mask of a column can be cast into the type of the column
+/// and that its condition is a boolean
+///
+/// Otherwise the rule would fail only when a masked role reads the table or
+/// when the static masking is applied. When strict mode is off, the masked
+/// column takes the type of the mask, so any type is accepted.
+///
+fn typecheck_column(object: &pg_sys::ObjectAddress, label: &str) {
+    let Ok(MaskingRule::MaskedWith { mask, condition }) = masking_rule::parse(label) else {
         return;
+    };
+    let attnum = object.objectSubId as i16;
+    if guc::ANON_STRICT_MODE.get() {
+        if let Err(detail) = masking::check_mask_type(object.objectId, attnum, mask.expression()) {
+            error::invalid_label_for("a column", label, Some(detail)).ereport();
+        }
+    }
+    if let Some(condition) = condition {
+        if let Err(detail) = masking::check_condition_type(object.objectId, &condition) {
+            error::invalid_label_for("a column", label, Some(detail)).ereport();
+        }
     }
-
-    error::invalid_label_for("a column", label, None).ereport();
 }
 
 fn relabel_database(label: &str) {
-    let mut detail: Option<String> = None;
-
-    if re::capture_tablesample(label).is_some() {
-        let check_tbs = input::check_tablesample(label);
-        if check_tbs.is_ok() {
-            return;
+    match masking_rule::parse(label) {
+        /* SECURITY LABEL FOR anon ON DATABASE d IS 'TABLESAMPLE SYSTEM(10)' */
+        Ok(MaskingRule::TableSample(_)) => {
+            if let Err(detail) = input::check_tablesample(label) {
+                error::invalid_label_for("a database", label, Some(detail)).ereport();
+            }
         }
-        detail = Some(check_tbs.unwrap_err());
+        Ok(_) => error::invalid_label_for("a database", label, None).ereport(),
+        Err(e) => error::invalid_label_for("a database", label, Some(e.to_string())).ereport(),
     }
-
-    error::invalid_label_for("a database", label, detail).ereport();
 }
 
 fn relabel_function(label: &str) {
@@ -203,18 +247,23 @@ fn relabel_function(label: &str) {
         .ereport();
     }
 
-    if re::is_match_trusted(label) || re::is_match_untrusted(label) {
-        return;
+    match masking_rule::parse(label) {
+        Ok(MaskingRule::Trusted) | Ok(MaskingRule::Untrusted) | Ok(MaskingRule::Forbidden) => (),
+        Ok(_) => error::invalid_label_for("a function", label, None).ereport(),
+        Err(e) => error::invalid_label_for("a function", label, Some(e.to_string())).ereport(),
     }
-
-    error::invalid_label_for("a function", label, None).ereport();
 }
 
 fn relabel_role(label: &str) {
-    if re::is_match_masked(label) {
-        return;
+    match masking_rule::parse(label) {
+        Ok(MaskingRule::Masked(limits)) => {
+            if let Err(e) = limits::RoleLimits::parse(&limits) {
+                error::invalid_label_for("a role", label, Some(e)).ereport();
+            }
+        }
+        Ok(_) => error::invalid_label_for("a role", label, None).ereport(),
+        Err(e) => error::invalid_label_for("a role", label, Some(e.to_string())).ereport(),
     }
-    error::invalid_label_for("a role", label, None).ereport();
 }
 
 fn relabel_schema(label: &str) {
@@ -224,24 +273,34 @@ fn relabel_schema(label: &str) {
         )
         .ereport();
     }
-    if re::is_match_trusted(label) {
-        return;
-    }
 
-    error::invalid_label_for("a schema", label, None).ereport();
+    match masking_rule::parse(label) {
+        Ok(MaskingRule::Trusted) => (),
+        Ok(_) => error::invalid_label_for("a schema", label, None).ereport(),
+        Err(e) => error::invalid_label_for("a schema", label, Some(e.to_string())).ereport(),
+    }
 }
 
 // relabel_table is **almost** equivalent to relabel_database
 fn relabel_table(label: &str) {
-    let mut detail: Option<String> = None;
-    if re::capture_tablesample(label).is_some() {
-        let check_tbs = input::check_tablesample(label);
-        if check_tbs.is_ok() {
-            return;
+    let check = match masking_rule::parse(label) {
+        /* SECURITY LABEL FOR anon ON TABLE t IS 'TABLESAMPLE SYSTEM(10)' */
+        Ok(MaskingRule::TableSample(_)) => input::check_tablesample(label).map_err(Some),
+
+        /* SECURITY LABEL FOR anon ON TABLE t IS 'FILTER WITH $x$' */
+        // Just like masking functions, the filter is checked against the
+        // default masking policy. See relabel_column() for more details.
+        Ok(MaskingRule::Filter(predicate)) => {
+            input::check_predicate(predicate, ANON_DEFAULT_MASKING_POLICY).map_err(Some)
         }
-        detail = Some(check_tbs.unwrap_err());
+
+        Ok(_) => Err(None),
+        Err(e) => Err(Some(e.to_string())),
+    };
+
+    if let Err(detail) = check {
+        error::invalid_label_for("a table", label, detail).ereport();
     }
-    error::invalid_label_for("a table", label, detail).ereport();
 }
 
 //----------------------------------------------------------------------------
@@ -274,6 +333,17 @@ mod tests {
         relabel_table("INVALID LABEL")
     }
 
+    #[pg_test]
+    fn test_relabel_table_filter() {
+        relabel_table("FILTER WITH region = 'EU'")
+    }
+
+    #[pg_test(error = "Anon: `FILTER WITH public.belt() = 'x'` is not a valid label for a table")]
+    fn test_relabel_table_filter_untrusted() {
+        let _outfit = fixture::create_masking_functions();
+        relabel_table("FILTER WITH public.belt() = 'x'")
+    }
+
     #[pg_test]
     fn test_relabel_schema_valid_label() {
         relabel_schema("TRUSTED")
@@ -289,6 +359,11 @@ mod tests {
         relabel_function("TRUSTED")
     }
 
+    #[pg_test]
+    fn test_relabel_function_forbidden() {
+        relabel_function("FORBIDDEN")
+    }
+
     #[pg_test(error = "Anon: `INVALID LABEL` is not a valid label for a function")]
     fn test_relabel_function_invalid_label() {
         relabel_function("INVALID LABEL")
@@ -296,7 +371,15 @@ mod tests {
 
     #[pg_test]
     fn test_relabel_role_valid_label() {
-        relabel_role("MASKED")
+        relabel_role("MASKED");
+        relabel_role("MASKED WITH ROW LIMIT 1000 AND STATEMENT TIMEOUT '30s'");
+    }
+
+    #[pg_test(
+        error = "Anon: `MASKED WITH STATEMENT TIMEOUT 1parsec` is not a valid label for a role"
+    )]
+    fn test_relabel_role_invalid_limit() {
+        relabel_role("MASKED WITH STATEMENT TIMEOUT 1parsec")
     }
 
     #[pg_test(error = "Anon: `INVALID LABEL` is not a valid label for a role")]
@@ -316,6 +399,79 @@ mod tests {
         relabel_column("INVALID LABEL")
     }
 
+    #[pg_test]
+    fn test_relabel_column_conditional_label() {
+        relabel_column("MASKED WITH VALUE NULL WHEN country <> 'FR'");
+        relabel_column("MASKED WITH FUNCTION anon.firstname() UNLESS owner = current_user");
+    }
+
+    #[pg_test(
+        error = "Anon: `MASKED WITH VALUE NULL WHEN id IN (SELECT 1)` is not a valid label for a column"
+    )]
+    fn test_relabel_column_conditional_label_with_subquery() {
+        relabel_column("MASKED WITH VALUE NULL WHEN id IN (SELECT 1)")
+    }
+
+    #[pg_test]
+    fn test_typecheck_column() {
+        Spi::run(
+            "
+            CREATE TABLE hero(id INT, name TEXT, birth DATE);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH VALUE 0';
+            SECURITY LABEL FOR anon ON COLUMN hero.name IS 'MASKED WITH VALUE 0';
+            SECURITY LABEL FOR anon ON COLUMN hero.birth IS 'MASKED WITH FUNCTION anon.random_date()';
+        ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(
+        error = "Anon: `MASKED WITH FUNCTION anon.random_date()` is not a valid label for a column"
+    )]
+    fn test_typecheck_column_function() {
+        Spi::run(
+            "
+            CREATE TABLE hero(id INT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH FUNCTION anon.random_date()';
+        ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "Anon: `MASKED WITH VALUE 'x'` is not a valid label for a column")]
+    fn test_typecheck_column_value() {
+        Spi::run(
+            "
+            CREATE TABLE hero(id INT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH VALUE ''x''';
+        ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_typecheck_column_strict_mode_off() {
+        Spi::run(
+            "
+            SET anon.strict_mode TO off;
+            CREATE TABLE hero(id INT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH FUNCTION anon.random_date()';
+        ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "Anon: `MASKED WITH VALUE 0 UNLESS id` is not a valid label for a column")]
+    fn test_typecheck_column_condition() {
+        Spi::run(
+            "
+            CREATE TABLE hero(id INT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH VALUE 0 UNLESS id';
+        ",
+        )
+        .unwrap();
+    }
+
     #[pg_test(error = "Anon: Labeling this object is not supported")]
     fn test_label_on_type() {
         Spi::run(
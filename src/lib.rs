@@ -1,21 +1,34 @@
 use pgrx::pgrx_macros::extension_sql_file;
 use pgrx::prelude::*;
 
+mod aggregation;
+mod audit;
+mod cache;
 mod compat;
 mod dummy;
 mod error;
+mod explain;
+mod filtering;
 mod fixture;
 mod guc;
 mod hooks;
 mod input;
 mod label_providers;
+mod limits;
+mod lint;
 mod log;
 mod macros;
 mod masking;
+mod masking_rule;
+mod preview;
 mod random;
 mod re;
 mod sampling;
+mod session;
 mod static_masking;
+mod statistics;
+mod stats;
+mod unmasking;
 mod utils;
 mod walker;
 
@@ -40,6 +53,8 @@ extension_sql_file!("../sql/pseudo.sql", requires = ["init"]);
 extension_sql_file!("../sql/random.sql", requires = ["anon"]);
 extension_sql_file!("../sql/static_masking.sql", requires = ["anon"]);
 extension_sql_file!("../sql/legacy_dynamic_masking.sql", requires = ["anon"]);
+extension_sql_file!("../sql/session_policy.sql", requires = ["anon"]);
+extension_sql_file!("../sql/unmasking.sql", requires = ["anon"]);
 // GCOVR_EXCL_STOP
 
 pgrx::pg_module_magic!();
@@ -295,7 +310,14 @@ mod anon {
     //------------------------------------------------------------------------
     // Masking engine
     //------------------------------------------------------------------------
+    use crate::cache;
+    use crate::hooks;
+    use crate::lint;
     use crate::masking;
+    use crate::preview;
+    use crate::session;
+    use crate::stats;
+    use crate::unmasking;
 
     #[pg_extern]
     pub fn masking_expressions_for_table(r: pg_sys::Oid, p: String) -> String {
@@ -308,6 +330,195 @@ mod anon {
         Some(val)
     }
 
+    /// Returns the masking policy applied to a role, including the policies
+    /// inherited from the roles it belongs to
+    #[pg_extern]
+    pub fn get_masking_policy(roleid: pg_sys::Oid) -> Option<String> {
+        masking::get_masking_policy(roleid)
+    }
+
+    /// Returns the masking policy applied to the queries of the current user,
+    /// including the policy selected by the session token or by the session
+    /// policy rules
+    #[pg_extern]
+    pub fn session_masking_policy() -> Option<String> {
+        hooks::current_masking_policy()
+    }
+
+    /// The guard of a temporarily unmasked relation, see
+    /// `unmasking::unmask_relation()`
+    #[pg_extern]
+    pub fn check_unmasking(roleid: pg_sys::Oid, relid: pg_sys::Oid) -> bool {
+        unmasking::check_unmasking(roleid, relid)
+    }
+
+    /// Tell all the backends that the session policy rules were modified,
+    /// see `session::invalidate_rules()`
+    #[pg_extern]
+    pub fn invalidate_session_policy_rules() {
+        session::invalidate_rules()
+    }
+
+    /// Returns a session token selecting a masking policy for a user until a
+    /// given time
+    #[pg_extern]
+    pub fn sign_session_token(
+        policy: String,
+        username: String,
+        expires_at: default!(
+            pgrx::datum::TimestampWithTimeZone,
+            "pg_catalog.now() + '1 day'::INTERVAL"
+        ),
+    ) -> String {
+        session::token(&policy, &username, expires_at.into())
+    }
+
+    /// Returns the hit and miss counts of the masking caches of the current
+    /// backend
+    #[pg_extern]
+    pub fn cache_stats() -> TableIterator<
+        'static,
+        (
+            name!(cache, String),
+            name!(hits, i64),
+            name!(misses, i64),
+            name!(entries, i64),
+        ),
+    > {
+        TableIterator::new(cache::stats())
+    }
+
+    /// Returns the activity of the dynamic masking engine, see the
+    /// `anon.stat_masking` view
+    #[pg_extern]
+    #[allow(clippy::type_complexity)]
+    pub fn stat_masking() -> TableIterator<
+        'static,
+        (
+            name!(dbid, pg_sys::Oid),
+            name!(policy, String),
+            name!(relid, Option<pg_sys::Oid>),
+            name!(queries, i64),
+            name!(copies, i64),
+            name!(rejected, i64),
+            name!(total_time, f64),
+        ),
+    > {
+        TableIterator::new(stats::entries())
+    }
+
+    /// Discard the statistics of the dynamic masking engine
+    #[pg_extern]
+    pub fn stat_masking_reset() {
+        stats::reset()
+    }
+
+    extension_sql!(
+        r#"
+    CREATE VIEW anon.stat_masking AS
+    SELECT s.dbid, s.policy, s.relid, c.oid::REGCLASS AS relname,
+           s.queries, s.copies, s.rejected, s.total_time
+    FROM anon.stat_masking() s
+    LEFT JOIN pg_catalog.pg_database d ON d.oid = s.dbid
+    LEFT JOIN pg_catalog.pg_class c
+      ON c.oid = s.relid AND d.datname = pg_catalog.current_database();
+
+    COMMENT ON VIEW anon.stat_masking
+    IS 'Statements rewritten or rejected by the dynamic masking engine, per policy and per relation';
+
+    REVOKE ALL ON anon.stat_masking FROM PUBLIC;
+    REVOKE ALL ON FUNCTION anon.stat_masking FROM PUBLIC;
+    REVOKE ALL ON FUNCTION anon.stat_masking_reset FROM PUBLIC;
+    "#,
+        name = "stat_masking_view",
+        requires = ["anon", stat_masking, stat_masking_reset]
+    );
+
+    #[pg_extern(sql = "
+        CREATE FUNCTION anon.explain_masking(tablename REGCLASS, policy TEXT)
+        RETURNS TABLE (
+            attname TEXT,
+            label TEXT,
+            kind TEXT,
+            expression TEXT,
+            generated BOOLEAN,
+            tablesample TEXT
+        )
+        AS 'MODULE_PATHNAME', 'explain_masking_wrapper'
+        LANGUAGE C STRICT;
+
+        CREATE FUNCTION anon.explain_masking(tablename REGCLASS)
+        RETURNS TABLE (
+            attname TEXT,
+            label TEXT,
+            kind TEXT,
+            expression TEXT,
+            generated BOOLEAN,
+            tablesample TEXT
+        )
+        AS $$ SELECT * FROM anon.explain_masking(tablename, 'anon'); $$
+        LANGUAGE SQL STRICT;
+    ")]
+    #[allow(clippy::type_complexity)]
+    pub fn explain_masking(
+        r: pg_sys::Oid,
+        p: String,
+    ) -> TableIterator<
+        'static,
+        (
+            name!(attname, String),
+            name!(label, Option<String>),
+            name!(kind, String),
+            name!(expression, String),
+            name!(generated, bool),
+            name!(tablesample, Option<String>),
+        ),
+    > {
+        TableIterator::new(masking::explain_masking(r, p))
+    }
+
+    /// Check again all the security labels of all the masking policies and
+    /// returns one row for each problem found
+    #[pg_extern]
+    #[allow(clippy::type_complexity)]
+    pub fn lint_rules() -> TableIterator<
+        'static,
+        (
+            name!(policy, String),
+            name!(object_type, String),
+            name!(object_identity, String),
+            name!(label, String),
+            name!(severity, String),
+            name!(message, String),
+        ),
+    > {
+        TableIterator::new(lint::lint_rules())
+    }
+
+    /// Returns a query rewritten with the masking policy of a role
+    #[pg_extern(sql = "
+        CREATE FUNCTION anon.rewrite_query(query TEXT, role REGROLE)
+        RETURNS TEXT
+        AS 'MODULE_PATHNAME', 'rewrite_query_wrapper'
+        LANGUAGE C STRICT;
+    ")]
+    pub fn rewrite_query(query: &str, roleid: pg_sys::Oid) -> String {
+        preview::rewrite_query(query, roleid)
+    }
+
+    /// Executes a query with the privileges and the masking policy of a role
+    /// The rows are returned as JSON strings, `json` has the same binary
+    /// representation as `text`
+    #[pg_extern(sql = "
+        CREATE FUNCTION anon.simulate(query TEXT, role REGROLE)
+        RETURNS SETOF JSON
+        AS 'MODULE_PATHNAME', 'simulate_wrapper'
+        LANGUAGE C STRICT;
+    ")]
+    pub fn simulate(query: &str, roleid: pg_sys::Oid) -> SetOfIterator<'static, String> {
+        SetOfIterator::new(preview::simulate(query, roleid))
+    }
+
     //
     // The masking engine functions are used by the V1 dynamic masking engine
     // They are exposed for backward compat' and may be made private in
@@ -318,6 +529,24 @@ mod anon {
         r#"
     SECURITY LABEL FOR anon ON FUNCTION anon.masking_expressions_for_table IS 'UNTRUSTED';
     SECURITY LABEL FOR anon ON FUNCTION anon.masking_value_for_column IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.get_masking_policy IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.session_masking_policy IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.check_unmasking IS 'UNTRUSTED';
+    REVOKE ALL ON FUNCTION anon.check_unmasking FROM PUBLIC;
+    SECURITY LABEL FOR anon ON FUNCTION anon.invalidate_session_policy_rules IS 'UNTRUSTED';
+    REVOKE ALL ON FUNCTION anon.invalidate_session_policy_rules FROM PUBLIC;
+    SECURITY LABEL FOR anon ON FUNCTION anon.sign_session_token IS 'UNTRUSTED';
+    REVOKE ALL ON FUNCTION anon.sign_session_token FROM PUBLIC;
+    SECURITY LABEL FOR anon ON FUNCTION anon.cache_stats IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.stat_masking IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.stat_masking_reset IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.explain_masking(REGCLASS,TEXT) IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.explain_masking(REGCLASS) IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.lint_rules IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.rewrite_query IS 'UNTRUSTED';
+    SECURITY LABEL FOR anon ON FUNCTION anon.simulate IS 'UNTRUSTED';
+    REVOKE ALL ON FUNCTION anon.rewrite_query FROM PUBLIC;
+    REVOKE ALL ON FUNCTION anon.simulate FROM PUBLIC;
     "#,
         name = "unstrust_masking_engine_functions",
         requires = ["anon"]
@@ -422,12 +651,6 @@ mod anon {
     // with `cargo pgrx run --release`
     //
     //------------------------------------------------------------------------
-    #[cfg(debug_assertions)]
-    #[pg_extern]
-    pub fn get_masking_policy(roleid: pg_sys::Oid) -> Option<String> {
-        masking::get_masking_policy(roleid)
-    }
-
     #[cfg(debug_assertions)]
     #[pg_extern]
     pub fn list_masking_policies() -> Vec<&'static str> {
@@ -481,6 +704,8 @@ pub unsafe extern "C-unwind" fn _PG_init() {
     pgrx::hooks::register_hook(&mut HOOKS);
     guc::register_gucs();
     label_providers::register_label_providers();
+    cache::register_callbacks();
+    stats::init();
     log::debug1!("Anon: extension initialized");
 }
 
@@ -631,6 +856,47 @@ mod tests {
         masking_value_for_column(pg_sys::InvalidOid, 2, "anon".into());
     }
 
+    #[pg_test]
+    fn test_anon_get_masking_policy() {
+        let batman = fixture::create_masked_role();
+        let bruce = fixture::create_unmasked_role();
+        assert_eq!(get_masking_policy(batman), Some("anon".into()));
+        assert_eq!(get_masking_policy(bruce), None);
+    }
+
+    #[pg_test]
+    fn test_anon_cache_stats() {
+        let result = Spi::get_one::<i64>(
+            "SELECT count(*) FROM anon.cache_stats() WHERE hits >= 0 AND misses >= 0",
+        );
+        assert_eq!(result, Ok(Some(7)));
+    }
+
+    #[pg_test]
+    fn test_anon_explain_masking() {
+        fixture::create_table_person();
+        let kind = Spi::get_one::<String>(
+            "SELECT kind FROM anon.explain_masking('person'::REGCLASS) WHERE attname = 'lastname'",
+        );
+        assert_eq!(kind, Ok(Some("value".to_string())));
+    }
+
+    #[pg_test]
+    fn test_anon_lint_rules() {
+        Spi::run(
+            "
+            CREATE TABLE hero(id TEXT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH VALUE ''x''';
+            ALTER TABLE hero ALTER COLUMN id TYPE INT USING 0;
+        ",
+        )
+        .unwrap();
+        let severity = Spi::get_one::<String>(
+            "SELECT severity FROM anon.lint_rules() WHERE object_identity = 'public.hero.id'",
+        );
+        assert_eq!(severity, Ok(Some("error".to_string())));
+    }
+
     #[pg_test]
     fn test_anon_anonymize_table() {
         let oid = fixture::create_table_person();
@@ -688,6 +954,7 @@ pub mod pg_test {
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        // The statistics are allocated in shared memory
+        vec!["shared_preload_libraries = 'anon'"]
     }
 }
@@ -0,0 +1,321 @@
+///
+/// # Limits
+///
+/// The masking functions are called for each row, so a masked role reading a
+/// large table may run very long queries. The limits of a masked role are
+/// declared in its security label:
+///
+/// ```sql
+/// SECURITY LABEL FOR anon ON ROLE skynet
+///   IS 'MASKED WITH ROW LIMIT 1000 AND STATEMENT TIMEOUT 30s';
+/// ```
+///
+/// * `ROW LIMIT`: the maximum number of rows returned by a SELECT statement
+///   reading a masked relation. A LIMIT clause is added to the rewritten
+///   query.
+/// * `STATEMENT TIMEOUT`: the maximum duration of a statement. It is armed
+///   when the executor starts and it applies only to the statements of the
+///   masked roles that read a masked relation.
+///
+/// The members of a masked role inherit its limits. The values are checked
+/// when the label is declared.
+///
+use crate::aggregation;
+use crate::cache;
+use crate::compat;
+use crate::guc;
+use crate::masking_rule;
+use crate::preview;
+use crate::unmasking;
+use crate::utils;
+use pgrx::list::old_list::PgList;
+use pgrx::pg_sys::ffi::pg_guard_ffi_boundary;
+use pgrx::prelude::*;
+use pgrx::void_mut_ptr;
+use std::ffi::CString;
+
+/// The limits of a masked role
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct RoleLimits {
+    /// The maximum number of rows returned by a statement
+    pub row_limit: Option<i64>,
+    /// The statement timeout in milliseconds
+    pub statement_timeout: Option<i32>,
+}
+
+impl RoleLimits {
+    /// Check the limits of a role label
+    ///
+    /// The row limit is a positive integer. The timeout is an integer with
+    /// an optional unit (`ms`, `s`, `min`, `h`, `d`), just like the
+    /// `statement_timeout` parameter. A timeout of 0 is ignored.
+    ///
+    pub fn parse(limits: &masking_rule::Limits) -> Result<RoleLimits, String> {
+        let row_limit = match limits.row_limit {
+            Some(value) => match value.parse::<i64>() {
+                Ok(n) if n >= 0 => Some(n),
+                _ => return Err(format!("`{value}` is not a valid row limit")),
+            },
+            None => None,
+        };
+        let statement_timeout = match limits.statement_timeout {
+            Some(value) => match parse_timeout(value) {
+                Some(timeout) => (timeout > 0).then_some(timeout),
+                None => return Err(format!("`{value}` is not a valid statement timeout")),
+            },
+            None => None,
+        };
+        Ok(RoleLimits {
+            row_limit,
+            statement_timeout,
+        })
+    }
+}
+
+/// Returns a duration in milliseconds
+fn parse_timeout(value: &str) -> Option<i32> {
+    let value = CString::new(value).ok()?;
+    let mut timeout: i32 = 0;
+    let is_valid = unsafe {
+        pg_sys::parse_int(
+            value.as_ptr(),
+            &mut timeout,
+            pg_sys::GUC_UNIT_MS as i32,
+            std::ptr::null_mut(),
+        )
+    };
+    (is_valid && timeout >= 0).then_some(timeout)
+}
+
+/// Returns the limits of the current role in a policy
+///
+/// When `anon.mask_calling_role` is enabled, the calling role and the
+/// session role may provide the policy and its limits, see
+/// `hooks::current_masking_policy()`. A policy selected by a session token
+/// has no limits unless the role is masked in this policy.
+///
+pub fn current_limits(policy: &str) -> RoleLimits {
+    let mut roles = vec![unsafe { pg_sys::GetUserId() }];
+    if guc::ANON_MASK_CALLING_ROLE.get() {
+        roles.extend(preview::calling_roles());
+    }
+    roles
+        .into_iter()
+        .find_map(|roleid| cache::masking_limits(roleid, policy))
+        .unwrap_or_default()
+}
+
+/// Add a LIMIT clause to a SELECT statement
+///
+/// When the statement already has a LIMIT clause, the smallest limit wins.
+/// With `FETCH FIRST ... WITH TIES`, the rows tied with the last row are
+/// still returned.
+///
+pub unsafe fn limit_rows(query: *mut pg_sys::Query, n: i64) {
+    let q = &mut *query;
+    if q.commandType != pg_sys::CmdType::CMD_SELECT || !q.utilityStmt.is_null() {
+        return;
+    }
+    let limit = pg_sys::makeConst(
+        pg_sys::INT8OID,
+        -1,
+        pg_sys::InvalidOid,
+        8,
+        n.into_datum().unwrap(),
+        false,
+        true,
+    ) as *mut pg_sys::Node;
+
+    if q.limitCount.is_null() {
+        q.limitCount = limit;
+        q.limitOption = pg_sys::LimitOption::LIMIT_OPTION_COUNT;
+        return;
+    }
+
+    // LEAST(<limit count>, n), the limit count is already coerced to bigint
+    // and a NULL limit count is ignored
+    let mut least = PgBox::<pg_sys::MinMaxExpr>::alloc_node(pg_sys::NodeTag::T_MinMaxExpr);
+    least.minmaxtype = pg_sys::INT8OID;
+    least.op = pg_sys::MinMaxOp::IS_LEAST;
+    least.args = pg_sys::list_make2_impl(
+        pg_sys::NodeTag::T_List,
+        pg_sys::ListCell {
+            ptr_value: q.limitCount as void_mut_ptr,
+        },
+        pg_sys::ListCell {
+            ptr_value: limit as void_mut_ptr,
+        },
+    );
+    least.location = -1;
+    q.limitCount = least.into_pg() as *mut pg_sys::Node;
+}
+
+/// Returns true if a planned statement reads a relation protected by the
+/// masking policy, i.e. a statement rewritten by the masking engine
+///
+/// The masked relations are replaced by their masking subquery, but they are
+/// still listed in the range table of the plan. See `rewrite_walker()` for
+/// the relations that are protected.
+///
+pub unsafe fn reads_protected_relation(pstmt: *mut pg_sys::PlannedStmt, policy: &str) -> bool {
+    let threshold = aggregation::get_threshold(policy);
+    PgList::<pg_sys::RangeTblEntry>::from_pg((*pstmt).rtable)
+        .iter_ptr()
+        .any(|rte| {
+            let relid = (*rte).relid;
+            if (*rte).rtekind != pg_sys::RTEKind::RTE_RELATION
+                || relid == pg_sys::InvalidOid
+                || compat::IsCatalogRelationOid(relid)
+                || utils::is_anon_relation_oid(relid)
+            {
+                return false;
+            }
+            match cache::masking_subquery(relid, policy.to_string()) {
+                Some(_) => !unmasking::is_unmasked(pg_sys::GetUserId(), relid),
+                None => threshold.is_some() && aggregation::has_indirect_identifiers(relid),
+            }
+        })
+}
+
+/// Arm the statement timeout of a masked role
+///
+/// The timeout is counted from the start of the statement, so arming it
+/// again for each nested statement (e.g. in a PL/pgSQL function) doesn't
+/// postpone it. The `statement_timeout` parameter is kept if it is shorter.
+///
+pub fn enable_statement_timeout(timeout: i32) {
+    unsafe {
+        if pg_sys::StatementTimeout > 0
+            && pg_sys::StatementTimeout <= timeout
+            && pg_guard_ffi_boundary(|| compat::get_timeout_active(compat::STATEMENT_TIMEOUT))
+        {
+            return;
+        }
+        let elapsed =
+            (pg_sys::GetCurrentTimestamp() - pg_sys::GetCurrentStatementStartTimestamp()) / 1000;
+        let delay = (timeout as i64 - elapsed).clamp(1, timeout as i64) as i32;
+        pg_guard_ffi_boundary(|| compat::enable_timeout_after(compat::STATEMENT_TIMEOUT, delay));
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fixture;
+    use crate::limits::*;
+
+    fn limits(label: &str) -> Result<RoleLimits, String> {
+        match masking_rule::parse(label) {
+            Ok(masking_rule::MaskingRule::Masked(limits)) => RoleLimits::parse(&limits),
+            _ => panic!("`{label}` is not a role label"),
+        }
+    }
+
+    #[pg_test]
+    fn test_role_limits() {
+        assert_eq!(limits("MASKED"), Ok(RoleLimits::default()));
+        assert_eq!(
+            limits("MASKED WITH ROW LIMIT 100 AND STATEMENT TIMEOUT 30s"),
+            Ok(RoleLimits {
+                row_limit: Some(100),
+                statement_timeout: Some(30000),
+            })
+        );
+        assert_eq!(
+            limits("MASKED WITH ROW LIMIT 0 AND STATEMENT TIMEOUT 0"),
+            Ok(RoleLimits {
+                row_limit: Some(0),
+                statement_timeout: None,
+            })
+        );
+        assert_eq!(
+            limits("MASKED WITH STATEMENT TIMEOUT '5 min'"),
+            Ok(RoleLimits {
+                row_limit: None,
+                statement_timeout: Some(300000),
+            })
+        );
+    }
+
+    #[pg_test]
+    fn test_role_limits_invalid() {
+        assert_eq!(
+            limits("MASKED WITH ROW LIMIT -1"),
+            Err("`-1` is not a valid row limit".to_string())
+        );
+        assert_eq!(
+            limits("MASKED WITH STATEMENT TIMEOUT '1 parsec'"),
+            Err("`1 parsec` is not a valid statement timeout".to_string())
+        );
+    }
+
+    #[pg_test]
+    fn test_current_limits() {
+        fixture::create_masked_role();
+        assert_eq!(current_limits("anon"), RoleLimits::default());
+        Spi::run(
+            "
+            SECURITY LABEL FOR anon ON ROLE batman
+              IS 'MASKED WITH ROW LIMIT 10 AND STATEMENT TIMEOUT 1s';
+            CREATE ROLE robin IN ROLE batman;
+            SET ROLE robin;
+        ",
+        )
+        .unwrap();
+        let expected = RoleLimits {
+            row_limit: Some(10),
+            statement_timeout: Some(1000),
+        };
+        assert_eq!(current_limits("anon"), expected);
+        assert_eq!(current_limits("devtests"), RoleLimits::default());
+    }
+
+    fn grant_person_to_batman(label: &str) {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(&format!(
+            "
+            INSERT INTO person SELECT 'Kyle', 'Reese' FROM generate_series(1,9);
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SECURITY LABEL FOR anon ON ROLE batman IS '{label}';
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT SELECT ON person TO batman;
+            SET ROLE batman;
+        "
+        ))
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_limit_rows() {
+        grant_person_to_batman("MASKED WITH ROW LIMIT 3");
+        let rows = |sql: &str| Spi::connect(|client| client.select(sql, None, &[]).unwrap().len());
+        assert_eq!(rows("SELECT * FROM person"), 3);
+        assert_eq!(rows("SELECT * FROM person LIMIT 2"), 2);
+        assert_eq!(rows("SELECT * FROM person LIMIT 5"), 3);
+        assert_eq!(rows("SELECT * FROM person LIMIT ALL"), 3);
+        // Only the rows returned are limited
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM person"),
+            Ok(Some(10))
+        );
+    }
+
+    #[pg_test(error = "canceling statement due to statement timeout")]
+    fn test_enable_statement_timeout() {
+        grant_person_to_batman("MASKED WITH STATEMENT TIMEOUT 100ms");
+        Spi::run("SELECT count(*), pg_catalog.pg_sleep(1) FROM person").unwrap();
+    }
+
+    #[pg_test]
+    fn test_enable_statement_timeout_unmasked_relation() {
+        grant_person_to_batman("MASKED WITH STATEMENT TIMEOUT 100ms");
+        // This statement is not rewritten, so it's not limited
+        Spi::run("SELECT pg_catalog.pg_sleep(0.2)").unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+///
+/// # Linting the masking rules
+///
+/// The security labels are checked only once, when they are declared. After
+/// that, a function may be declared as `UNTRUSTED`, a schema may lose its
+/// `TRUSTED` label, the type of a column may change or a masking function may
+/// be dropped. The linter reads all the labels of the registered masking
+/// policies and checks them again.
+///
+use crate::input;
+use crate::label_providers::ANON_DEFAULT_MASKING_POLICY;
+use crate::masking;
+use crate::masking_rule;
+use crate::masking_rule::{Condition, Mask, MaskingRule};
+use crate::utils;
+use pgrx::prelude::*;
+use std::ffi::CStr;
+
+///
+/// The Severity enum describes how bad a problem is
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Severity {
+    /// The label would be rejected or the masking subquery can't be built
+    Error,
+    /// The rule works but it probably does not do what was intended
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let severity = match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}")
+    }
+}
+
+/// policy, object type, object identity, label, severity, message
+pub type Diagnostic = (String, String, String, String, String, String);
+
+/// A security label declared in a masking policy
+struct Label {
+    policy: String,
+    object_type: String,
+    identity: String,
+    label: String,
+    classoid: pg_sys::Oid,
+    objoid: pg_sys::Oid,
+    objsubid: i32,
+}
+
+/// The labels are stored in pg_seclabel, except the labels on roles and
+/// databases which are stored in pg_shseclabel
+static LABELS_QUERY: &str = "
+    SELECT l.provider, o.type, o.identity, l.label, l.classoid, l.objoid, l.objsubid
+    FROM (
+        SELECT provider, label, classoid, objoid, objsubid
+        FROM pg_catalog.pg_seclabel
+        UNION ALL
+        SELECT provider, label, classoid, objoid, 0
+        FROM pg_catalog.pg_shseclabel
+        WHERE classoid <> 'pg_catalog.pg_database'::REGCLASS
+        OR objoid = (
+            SELECT oid
+            FROM pg_catalog.pg_database
+            WHERE datname = pg_catalog.current_database()
+        )
+    ) AS l,
+    LATERAL pg_catalog.pg_identify_object(l.classoid, l.objoid, l.objsubid) AS o
+    WHERE l.provider = ANY($1)
+    ORDER BY l.provider, o.type, o.identity
+";
+
+/// Check all the security labels of all the registered masking policies
+///
+/// Returns one diagnostic for each problem found
+///
+pub fn lint_rules() -> Vec<Diagnostic> {
+    let policies: Vec<String> = masking::list_masking_policies()
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    for l in read_labels(policies) {
+        // A label may refer to an object that does not exist anymore (e.g. a
+        // dropped schema) and the checks would raise an error
+        let problems = utils::try_in_subtransaction(|| lint_label(&l))
+            .unwrap_or_else(|message| vec![(Severity::Error, message)]);
+        for (severity, message) in problems {
+            diagnostics.push((
+                l.policy.clone(),
+                l.object_type.clone(),
+                l.identity.clone(),
+                l.label.clone(),
+                severity.to_string(),
+                message,
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// Read the security labels of the given policies
+///
+fn read_labels(policies: Vec<String>) -> Vec<Label> {
+    Spi::connect(|client| {
+        client
+            .select(LABELS_QUERY, None, &[policies.into()])
+            .unwrap()
+            .map(|row| Label {
+                policy: row.get(1).unwrap().unwrap_or_default(),
+                object_type: row.get(2).unwrap().unwrap_or_default(),
+                identity: row.get(3).unwrap().unwrap_or_default(),
+                label: row.get(4).unwrap().unwrap_or_default(),
+                classoid: row.get(5).unwrap().unwrap_or_default(),
+                objoid: row.get(6).unwrap().unwrap_or_default(),
+                objsubid: row.get(7).unwrap().unwrap_or_default(),
+            })
+            .collect()
+    })
+}
+
+/// Check a security label, just like `masking_policy_object_relabel()` does
+///
+fn lint_label(l: &Label) -> Vec<(Severity, String)> {
+    let rule = match masking_rule::parse(&l.label) {
+        Ok(rule) => rule,
+        Err(e) => return vec![(Severity::Error, e.to_string())],
+    };
+
+    let is_column = l.objsubid != 0;
+    let check = match (l.classoid, rule) {
+        /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'MASKED WITH ...' */
+        (pg_sys::RelationRelationId, MaskingRule::MaskedWith { mask, condition }) if is_column => {
+            return lint_masked_column(l, mask, condition);
+        }
+
+        /* SECURITY LABEL FOR anon ON COLUMN t.i IS 'NOT MASKED' */
+        (pg_sys::RelationRelationId, MaskingRule::NotMasked) if is_column => Ok(()),
+
+        /* SECURITY LABEL FOR anon ON TABLE t IS 'TABLESAMPLE SYSTEM(10)' */
+        /* SECURITY LABEL FOR anon ON DATABASE d IS 'TABLESAMPLE SYSTEM(10)' */
+        (pg_sys::RelationRelationId, MaskingRule::TableSample(_)) if !is_column => {
+            input::check_tablesample(&l.label)
+        }
+        (pg_sys::DatabaseRelationId, MaskingRule::TableSample(_)) => {
+            input::check_tablesample(&l.label)
+        }
+
+        /* SECURITY LABEL FOR anon ON TABLE t IS 'FILTER WITH $x$' */
+        (pg_sys::RelationRelationId, MaskingRule::Filter(predicate)) if !is_column => {
+            input::check_predicate(predicate, ANON_DEFAULT_MASKING_POLICY)
+        }
+
+        /* SECURITY LABEL FOR anon ON FUNCTION public.foo() IS 'TRUSTED' */
+        /* SECURITY LABEL FOR anon ON FUNCTION public.foo() IS 'FORBIDDEN' */
+        (
+            pg_sys::ProcedureRelationId,
+            MaskingRule::Trusted | MaskingRule::Untrusted | MaskingRule::Forbidden,
+        ) => Ok(()),
+
+        /* SECURITY LABEL FOR anon ON ROLE batman IS 'MASKED' */
+        (pg_sys::AuthIdRelationId, MaskingRule::Masked(_)) => Ok(()),
+
+        /* SECURITY LABEL FOR anon ON SCHEMA public IS 'TRUSTED' */
+        (pg_sys::NamespaceRelationId, MaskingRule::Trusted) => Ok(()),
+
+        _ => Err(format!("this is not a valid label for a {}", l.object_type)),
+    };
+
+    match check {
+        Ok(()) => vec![],
+        Err(message) => vec![(Severity::Error, message)],
+    }
+}
+
+/// Check a `MASKED WITH` rule and type-check its expression against the
+/// column
+///
+/// Just like in `relabel_column()`, the functions are checked against the
+/// default masking policy
+///
+fn lint_masked_column(
+    l: &Label,
+    mask: Mask,
+    condition: Option<Condition>,
+) -> Vec<(Severity, String)> {
+    let mut problems = Vec::new();
+
+    if let Some(condition) = condition {
+        if let Err(message) =
+            input::check_predicate(condition.predicate, ANON_DEFAULT_MASKING_POLICY)
+        {
+            problems.push((Severity::Error, message));
+        }
+    }
+
+    let check = match mask {
+        Mask::Value(val) => input::check_value(val),
+        Mask::Function(func) => input::check_function(func, ANON_DEFAULT_MASKING_POLICY),
+    };
+    if let Err(message) = check {
+        problems.push((Severity::Error, message));
+    }
+
+    // There's no point in type-checking an invalid expression
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let attnum = l.objsubid as i16;
+    match masking::masking_expression_type(l.objoid, attnum, l.policy.clone()) {
+        Err(message) => problems.push((Severity::Error, message)),
+        Ok(expression_type) => {
+            // When strict mode is off, the masked column takes the type of
+            // the masking expression
+            let column_type = unsafe { pg_sys::get_atttype(l.objoid, attnum) };
+            if !can_assign(expression_type, column_type) {
+                problems.push((
+                    Severity::Warning,
+                    format!(
+                        "the masking expression returns {}, which can't be assigned to a column of type {}",
+                        type_name(expression_type),
+                        type_name(column_type)
+                    ),
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Check that a value of type `source` can be stored in a column of type
+/// `target`
+///
+fn can_assign(source: pg_sys::Oid, target: pg_sys::Oid) -> bool {
+    let input_typeids = [source];
+    let target_typeids = [target];
+    source == target
+        || unsafe {
+            pg_sys::can_coerce_type(
+                1,
+                input_typeids.as_ptr(),
+                target_typeids.as_ptr(),
+                pg_sys::CoercionContext::COERCION_ASSIGNMENT,
+            )
+        }
+}
+
+fn type_name(typeid: pg_sys::Oid) -> String {
+    unsafe { CStr::from_ptr(pg_sys::format_type_be(typeid)) }
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fixture;
+    use crate::lint::*;
+
+    fn severities() -> Vec<String> {
+        lint_rules().into_iter().map(|d| d.4).collect()
+    }
+
+    #[pg_test]
+    fn test_lint_rules_valid() {
+        fixture::create_table_person();
+        assert!(lint_rules().is_empty());
+    }
+
+    #[pg_test]
+    fn test_lint_rules_untrusted_function() {
+        fixture::create_masking_functions();
+        Spi::run(
+            "
+            CREATE TABLE hero(id INT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id
+              IS 'MASKED WITH FUNCTION outfit.mask(id)';
+            SECURITY LABEL FOR anon ON FUNCTION outfit.mask(INT) IS 'UNTRUSTED';
+        ",
+        )
+        .unwrap();
+        let diagnostics = lint_rules();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].2, "public.hero.id");
+        assert_eq!(diagnostics[0].4, "error");
+        assert_eq!(diagnostics[0].5, "outfit.mask(id) is UNTRUSTED");
+    }
+
+    #[pg_test]
+    fn test_lint_rules_dropped_function() {
+        Spi::run(
+            "
+            CREATE TABLE hero(name TEXT);
+            CREATE FUNCTION anon.mask_hero() RETURNS TEXT LANGUAGE SQL AS $$ SELECT 'x' $$;
+            SECURITY LABEL FOR anon ON COLUMN hero.name
+              IS 'MASKED WITH FUNCTION anon.mask_hero()';
+            DROP FUNCTION anon.mask_hero();
+        ",
+        )
+        .unwrap();
+        assert_eq!(severities(), vec!["error"]);
+    }
+
+    #[pg_test]
+    fn test_lint_rules_type_mismatch() {
+        Spi::run(
+            "
+            CREATE TABLE hero(id TEXT);
+            SECURITY LABEL FOR anon ON COLUMN hero.id IS 'MASKED WITH VALUE ''x''';
+            ALTER TABLE hero ALTER COLUMN id TYPE INT USING 0;
+        ",
+        )
+        .unwrap();
+        // CAST('x' AS integer) fails
+        assert_eq!(severities(), vec!["error"]);
+
+        // 'x' is a text value
+        Spi::run("SET anon.strict_mode TO off").unwrap();
+        assert_eq!(severities(), vec!["warning"]);
+    }
+}
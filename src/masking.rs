@@ -1,13 +1,23 @@
+use crate::cache;
+use crate::compat;
+use crate::error;
+use crate::filtering;
 use crate::guc;
+use crate::input;
+use crate::limits;
 use crate::log;
+use crate::masking_rule;
+use crate::masking_rule::{Condition, ConditionKind, Mask, MaskingRule};
 use crate::re;
 use crate::sampling;
+use crate::statistics;
 use crate::utils;
 ///
 /// # Masking Engine
 ///
 use c_str_macro::c_str;
 use md5::{Digest, Md5};
+use pgrx::list::old_list::PgList;
 use pgrx::prelude::*;
 use std::ffi::CStr;
 use std::ffi::CString;
@@ -28,6 +38,43 @@ pub enum Reason {
     InvalidInput,
 }
 
+//----------------------------------------------------------------------------
+// Masking kinds
+//----------------------------------------------------------------------------
+
+///
+/// The MaskingKind enum describes how the value of a column is obtained
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MaskingKind {
+    /// `MASKED WITH FUNCTION ...`
+    Function,
+    /// `MASKED WITH VALUE ...`
+    Value,
+    /// `NOT MASKED` or no rule, the authentic value is revealed
+    NotMasked,
+    /// Privacy by default: the default value of the column
+    Default,
+    /// Privacy by default: the column has no default value
+    Null,
+}
+
+impl std::fmt::Display for MaskingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self {
+            MaskingKind::Function => "function",
+            MaskingKind::Value => "value",
+            MaskingKind::NotMasked => "not masked",
+            MaskingKind::Default => "default",
+            MaskingKind::Null => "null",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+/// attname, label, kind, expression, generated, tablesample
+pub type ColumnExplanation = (String, Option<String>, String, String, bool, Option<String>);
+
 //----------------------------------------------------------------------------
 // Public functions
 //----------------------------------------------------------------------------
@@ -35,15 +82,27 @@ pub enum Reason {
 /// For a given role, returns the policy in which he/she is masked
 /// or the NULL if the role is not masked.
 ///
+/// A role is masked if it is declared as MASKED or if any role it belongs to
+/// (directly or indirectly) is declared as MASKED.
+///
+/// When several policies are found, the precedence rule is:
+///
+///   1. The closest role wins: a label on the role itself comes first, then
+///      the labels on the roles granted to it, then the labels on the roles
+///      granted to those roles, etc.
+///   2. For roles at the same distance, the policies are checked in the order
+///      returned by `list_masking_policies()`, i.e. "anon" first and then
+///      the order of the `anon.masking_policies` parameter
+///
 /// * roleid is the id of the user we want to mask
 ///
 pub fn get_masking_policy(roleid: pg_sys::Oid) -> Option<String> {
-    // Possible Improvement : allow masking rule inheritance by checking
-    // also the roles that the user belongs to
-    // This may be done by using `roles_is_member_of()` ?
-    for policy in list_masking_policies() {
-        if has_mask_in_policy(roleid, policy) {
-            return Some(policy.to_string());
+    let policies = list_masking_policies();
+    for roles in utils::roles_is_member_of(roleid) {
+        for &policy in &policies {
+            if roles.iter().any(|r| has_mask_in_policy(*r, policy)) {
+                return Some(policy.to_string());
+            }
         }
     }
 
@@ -51,6 +110,27 @@ pub fn get_masking_policy(roleid: pg_sys::Oid) -> Option<String> {
     None
 }
 
+/// Returns the limits of a role in a policy
+/// or None if the role is not masked in this policy
+///
+/// The limits are read on the closest role declared as MASKED in the policy,
+/// with the same precedence rule as `get_masking_policy()`. An invalid limit
+/// is rejected when the label is declared, so it is ignored here.
+///
+pub fn get_masking_limits(roleid: pg_sys::Oid, policy: &str) -> Option<limits::RoleLimits> {
+    for roles in utils::roles_is_member_of(roleid) {
+        for role in roles {
+            let Ok(seclabel) = rule_on_role(role, policy) else {
+                continue;
+            };
+            if let Ok(MaskingRule::Masked(l)) = masking_rule::parse(seclabel) {
+                return Some(limits::RoleLimits::parse(&l).unwrap_or_default());
+            }
+        }
+    }
+    None
+}
+
 /// Return all the registered masking policies
 ///
 /// We can't use pg_sys::SplitGUCList(...) here because extension are not
@@ -69,6 +149,74 @@ pub fn list_masking_policies() -> Vec<&'static str> {
     masking_policies
 }
 
+/// Returns true if the masked roles of a policy are allowed to write
+///
+pub fn is_write_through_policy(policy: &str) -> bool {
+    re::capture_guc_list(guc::ANON_WRITE_THROUGH_POLICIES.get().unwrap()).contains(&policy)
+}
+
+/// What to do when a masked role filters, joins, groups or sorts the rows
+/// on a masked column
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum InferenceGuard {
+    Off,
+    /// Report the query in the server log
+    Audit,
+    /// Report the query to the masked role
+    Warning,
+    /// Refuse the query
+    Error,
+}
+
+/// Returns the option of a policy in a list of `policy:option` items
+///
+/// The option is empty when the policy is listed without an option
+///
+pub fn policy_option<'a>(items: &'a CStr, policy: &str) -> Option<&'a str> {
+    re::capture_guc_list(items).into_iter().find_map(|item| {
+        let (p, option) = item.split_once(':').unwrap_or((item, ""));
+        (p == policy).then_some(option)
+    })
+}
+
+/// Returns the inference guard of a policy
+///
+/// `anon.inference_guard` is a list of `policy:action` items. An unknown
+/// action is considered as `error`.
+///
+pub fn inference_guard(policy: &str) -> InferenceGuard {
+    let Some(action) = policy_option(guc::ANON_INFERENCE_GUARD.get().unwrap(), policy) else {
+        return InferenceGuard::Off;
+    };
+    match action.to_ascii_lowercase().as_str() {
+        "off" => InferenceGuard::Off,
+        "audit" => InferenceGuard::Audit,
+        "warning" => InferenceGuard::Warning,
+        _ => InferenceGuard::Error,
+    }
+}
+
+/// Returns true if the masked roles of a policy are not allowed to call a
+/// function
+///
+/// A function is forbidden if it is labeled as `FORBIDDEN` for this policy
+/// or if its qualified name is listed in `anon.forbidden_functions`
+///
+pub fn is_forbidden_function(funcid: pg_sys::Oid, policy: &str) -> bool {
+    if let Ok(seclabel) = rule_on_function(funcid, policy) {
+        if masking_rule::parse(seclabel) == Ok(MaskingRule::Forbidden) {
+            return true;
+        }
+    }
+
+    let denylist = re::capture_guc_list(guc::ANON_FORBIDDEN_FUNCTIONS.get().unwrap());
+    if denylist.is_empty() {
+        return false;
+    }
+    utils::get_function_qualified_name(funcid).is_some_and(|name| denylist.contains(&name.as_str()))
+}
+
 /// Returns a String and bool
 ///
 /// The String is the "select clause filters" that will mask the authentic data
@@ -166,13 +314,174 @@ pub fn masking_value_for_column(
     Some((masking_value, att_is_masked))
 }
 
+/// Describe how each column of a relation is masked
+///
+/// Returns one row per column (except the dropped ones) with:
+///
+/// * the name of the column
+/// * its security label in this policy (if any)
+/// * the kind of masking: function, value, not masked, default or null
+/// * the masking expression, as it will be placed in the masking subquery
+/// * whether the column is generated
+/// * the sampling ratio of the relation (if any)
+///
+pub fn explain_masking(relid: pg_sys::Oid, policy: String) -> Vec<ColumnExplanation> {
+    let lockmode = pg_sys::AccessShareLock as i32;
+
+    // `pg_sys::relation_open()` will raise XX000
+    // if the specified oid isn't a valid relation
+    let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+
+    let reldesc = unsafe { PgBox::from_pg(relation.rd_att) };
+    let natts = reldesc.natts;
+    let attrs = unsafe { reldesc.attrs.as_slice(natts.try_into().unwrap()) };
+
+    let ratio = sampling::get_ratio(relid, &policy)
+        .ok()
+        .map(|r| r.to_string());
+
+    let mut explanations = Vec::new();
+    for a in attrs {
+        if a.attisdropped {
+            continue;
+        }
+        let label = cache::column_rule(relid, a.attnum, &policy);
+        let (expression, kind) = masking_for_att(&relation, a, policy.clone());
+        explanations.push((
+            name_data_to_str(&a.attname).to_string(),
+            label,
+            kind.to_string(),
+            expression,
+            is_generated(a),
+            ratio.clone(),
+        ));
+    }
+
+    // pass the relation back to Postgres
+    unsafe {
+        pg_sys::relation_close(relation.as_ptr(), lockmode);
+    }
+
+    explanations
+}
+
+/// Type-check the masking expression of a column
+///
+/// The expression is transformed exactly like in the masking subquery and
+/// the function returns the type of the result. When the transformation
+/// fails (e.g. the function does not exist or the value can't be cast into
+/// the type of the column), the error message is returned instead.
+///
+/// * relid is the relation OID
+/// * attnum is the attribute position, numbered from 1 up
+/// * policy is the masking policy
+///
+pub fn masking_expression_type(
+    relid: pg_sys::Oid,
+    attnum: i16,
+    policy: String,
+) -> Result<pg_sys::Oid, String> {
+    let lockmode = pg_sys::AccessShareLock as i32;
+
+    // `pg_sys::relation_open()` will raise XX000
+    // if the specified oid isn't a valid relation
+    let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+
+    let reldesc = unsafe { PgBox::from_pg(relation.rd_att) };
+    let natts = reldesc.natts;
+    let attrs = unsafe { reldesc.attrs.as_slice(natts.try_into().unwrap()) };
+
+    // Here attributes are numbered from 0 up
+    let a = attrs[attnum as usize - 1];
+    let (expression, _) = masking_for_att(&relation, &a, policy);
+    let expression_type = analyze_expression(&relation, &expression);
+
+    // pass the relation back to Postgres
+    unsafe {
+        pg_sys::relation_close(relation.as_ptr(), lockmode);
+    }
+
+    expression_type
+}
+
+/// Check that a mask can be cast into the type of a column
+///
+/// The mask is decorated with `cast_as_regtype()` and transformed just like
+/// in the masking subquery when strict mode is on. This will reject a value
+/// or a function that can't be converted into the type of the column.
+///
+/// * relid is the relation OID
+/// * attnum is the attribute position, numbered from 1 up
+/// * mask is the masking function or the masking value
+///
+pub fn check_mask_type(relid: pg_sys::Oid, attnum: i16, mask: &str) -> Result<(), String> {
+    let lockmode = pg_sys::AccessShareLock as i32;
+
+    // `pg_sys::relation_open()` will raise XX000
+    // if the specified oid isn't a valid relation
+    let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+
+    let reldesc = unsafe { PgBox::from_pg(relation.rd_att) };
+    let natts = reldesc.natts;
+    let attrs = unsafe { reldesc.attrs.as_slice(natts.try_into().unwrap()) };
+
+    // Here attributes are numbered from 0 up
+    let a = attrs[attnum as usize - 1];
+    let expression = cast_as_regtype(mask.to_string(), a.atttypid, a.atttypmod);
+    let check = analyze_expression(&relation, &expression).map(|_| ());
+
+    // pass the relation back to Postgres
+    unsafe {
+        pg_sys::relation_close(relation.as_ptr(), lockmode);
+    }
+
+    check
+}
+
+/// Check that the condition of a conditional mask is a boolean
+///
+/// The predicate is transformed and coerced just like in the `CASE`
+/// expression of the masking subquery, see `conditional_mask()`. Otherwise
+/// a predicate such as `WHEN 1` would fail only when the table is read.
+///
+/// * relid is the relation OID
+/// * condition is the `WHEN` or `UNLESS` clause of the masking rule
+///
+pub fn check_condition_type(relid: pg_sys::Oid, condition: &Condition) -> Result<(), String> {
+    let raw_expr = input::parse_expression(condition.predicate)?;
+    let construct = match condition.kind {
+        ConditionKind::When => c_str!("WHEN"),
+        ConditionKind::Unless => c_str!("UNLESS"),
+    };
+    let lockmode = pg_sys::AccessShareLock as i32;
+    let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+    let pstate = relation_pstate(&relation);
+
+    let check = utils::try_in_subtransaction(|| unsafe {
+        let node = pg_sys::transformExpr(
+            pstate.as_ptr(),
+            raw_expr.as_ptr(),
+            pg_sys::ParseExprKind::EXPR_KIND_SELECT_TARGET,
+        );
+        pg_sys::coerce_to_boolean(pstate.as_ptr(), node, construct.as_ptr());
+    });
+
+    // pass the relation back to Postgres
+    unsafe {
+        pg_sys::relation_close(relation.as_ptr(), lockmode);
+    }
+
+    check
+}
+
 /// Prepare a SQL Statement object that will replace the authentic relation
 ///
 /// * relid is the oid of the relation
 /// * policy is the masking policy to apply
 ///
 /// The masking subquery is composed of 2 SELECT
-///   - The first will apply the masking filters and the tablesample ratio
+///   - The first will apply the masking filters, the tablesample ratio and the
+///     row filter (if any)
 ///   - The second will apply the generated column expressions (if any)
 ///
 /// Example:
@@ -207,10 +516,11 @@ pub fn masking_value_for_column(
 pub fn subquery(relid: pg_sys::Oid, policy: String) -> Option<String> {
     let (masking_expressions, table_is_masked) = masking_expressions(relid, policy.clone());
     let ratio = sampling::get_ratio(relid, &policy);
+    let filter = filtering::get_filter(relid, &policy);
 
-    // if there's no mask and no tablesample ratio,
+    // if there's no mask, no tablesample ratio and no filter,
     // do not provide a subquery for this table
-    if !table_is_masked && ratio.is_err() {
+    if !table_is_masked && ratio.is_err() && filter.is_err() {
         return None;
     }
 
@@ -218,10 +528,14 @@ pub fn subquery(relid: pg_sys::Oid, policy: String) -> Option<String> {
 
     let tablename = utils::get_relation_qualified_name(relid)?;
 
-    let tablesample: String = if ratio.is_ok() {
-        format!("TABLESAMPLE {}", ratio.unwrap())
-    } else {
-        "".into()
+    let tablesample: String = match ratio {
+        Ok(ratio) => format!("TABLESAMPLE {ratio}"),
+        Err(_) => "".into(),
+    };
+
+    let where_clause: String = match filter {
+        Ok(predicate) => format!("WHERE ({predicate})"),
+        Err(_) => "".into(),
     };
 
     // build an alias for the masking subquery and use the hash of the table
@@ -239,10 +553,193 @@ pub fn subquery(relid: pg_sys::Oid, policy: String) -> Option<String> {
             SELECT {masking_expressions}
             FROM {tablename}
             {tablesample}
+            {where_clause}
         ) AS anon_alias_{tablename_hash}"
     ))
 }
 
+/// Build the masking subquery that will replace the authentic relation
+/// directly as a Query tree
+///
+/// This is the equivalent of `subquery()` followed by `parse_subquery()` and
+/// `transformTopLevelStmt()`, except that we don't need to format and parse a
+/// full SQL statement: only the masking rules are parsed.
+///
+/// The target list of the masking subquery contains one entry for each
+/// attribute of the relation. The dropped columns are replaced by a NULL
+/// placeholder, so that the position of each column in the subquery is equal
+/// to its attribute number in the authentic relation.
+///
+/// The tablesample ratio and the row filter are applied directly on the
+/// authentic relation and, just like `subquery()`, the generated columns are
+/// computed in a second level.
+///
+/// * relid is the oid of the relation
+/// * policy is the masking policy to apply
+///
+pub fn subquery_tree(relid: pg_sys::Oid, policy: String) -> Option<PgBox<pg_sys::Query>> {
+    let ratio = sampling::get_ratio(relid, &policy);
+    let filter = filtering::get_filter(relid, &policy);
+    let lockmode = pg_sys::AccessShareLock as i32;
+
+    // `pg_sys::relation_open()` will raise XX000
+    // if the specified oid isn't a valid relation
+    let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+
+    // reldesc is a TupleDescData object
+    // https://doxygen.postgresql.org/structTupleDescData.html
+    let reldesc = unsafe { PgBox::from_pg(relation.rd_att) };
+    let natts = reldesc.natts;
+    let attrs = unsafe { reldesc.attrs.as_slice(natts.try_into().unwrap()) };
+
+    // A value is None when the attribute is dropped
+    let mut masking_values = Vec::new();
+    let mut generation_values = Vec::new();
+    let mut table_is_masked = false;
+    let mut table_has_one_generated_column = false;
+    for a in attrs {
+        if a.attisdropped {
+            masking_values.push(None);
+            generation_values.push(None);
+            continue;
+        }
+        let (masking_value, att_is_masked) = value_for_att(&relation, a, policy.clone());
+        if att_is_masked {
+            table_is_masked = true;
+        }
+        masking_values.push(Some(masking_value));
+
+        let generation_value = default_for_att(&relation, a, true);
+        if generation_value.is_some() {
+            table_has_one_generated_column = true;
+        }
+        generation_values
+            .push(generation_value.or(Some(utils::quote_name_data(&a.attname).to_string())));
+    }
+
+    // if there's no mask, no tablesample ratio and no filter,
+    // do not provide a subquery for this table
+    if !table_is_masked && ratio.is_err() && filter.is_err() {
+        unsafe {
+            pg_sys::relation_close(relation.as_ptr(), lockmode);
+        }
+        return None;
+    }
+
+    //
+    // First level: the masking values are applied on the authentic relation
+    //
+    let pstate = unsafe { PgBox::from_pg(pg_sys::make_parsestate(std::ptr::null_mut())) };
+    let nsitem = unsafe {
+        PgBox::from_pg(pg_sys::addRangeTableEntryForRelation(
+            pstate.as_ptr(),
+            relation.as_ptr(),
+            lockmode,
+            std::ptr::null_mut(),
+            true,
+            true,
+        ))
+    };
+    unsafe { pg_sys::addNSItemToQuery(pstate.as_ptr(), nsitem.as_ptr(), true, true, true) };
+
+    let target_list = transform_target_list(&pstate, attrs, masking_values);
+
+    if let Ok(ratio) = ratio {
+        let mut rte = unsafe { PgBox::from_pg(nsitem.p_rte) };
+        rte.tablesample = sampling::tablesample_clause(pstate.as_ptr(), ratio).into_pg();
+    }
+
+    let quals = match filter {
+        Ok(predicate) => unsafe {
+            pg_sys::coerce_to_boolean(
+                pstate.as_ptr(),
+                transform_expression(&pstate, predicate, pg_sys::ParseExprKind::EXPR_KIND_WHERE),
+                c_str!("FILTER").as_ptr(),
+            )
+        },
+        Err(_) => std::ptr::null_mut(),
+    };
+
+    let masking_query = make_query(&pstate, target_list, quals);
+
+    if !table_has_one_generated_column {
+        // Keep the lock until the end of the transaction, just like the
+        // parser would do
+        unsafe {
+            pg_sys::relation_close(relation.as_ptr(), pg_sys::NoLock as i32);
+        }
+        return Some(masking_query);
+    }
+
+    //
+    // Second level: the generated columns are computed upon the masked values
+    //
+    let gen_pstate = unsafe { PgBox::from_pg(pg_sys::make_parsestate(std::ptr::null_mut())) };
+    let gen_nsitem = unsafe {
+        PgBox::from_pg(pg_sys::addRangeTableEntryForSubquery(
+            gen_pstate.as_ptr(),
+            masking_query.as_ptr(),
+            pg_sys::makeAlias(c_str!("anon_alias").as_ptr(), std::ptr::null_mut()),
+            false,
+            true,
+        ))
+    };
+    unsafe { pg_sys::addNSItemToQuery(gen_pstate.as_ptr(), gen_nsitem.as_ptr(), true, true, true) };
+
+    let gen_target_list = transform_target_list(&gen_pstate, attrs, generation_values);
+
+    unsafe {
+        pg_sys::relation_close(relation.as_ptr(), pg_sys::NoLock as i32);
+    }
+
+    Some(make_query(
+        &gen_pstate,
+        gen_target_list,
+        std::ptr::null_mut(),
+    ))
+}
+
+/// Returns the subquery that replaces a statistics view for the masked roles
+///
+/// The subquery reads the view and hides the statistics of the masked
+/// relations, see `statistics::predicate()`
+///
+pub fn statistics_subquery(relid: pg_sys::Oid, policy: &str) -> PgBox<pg_sys::Query> {
+    let lockmode = pg_sys::AccessShareLock as i32;
+    let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+    let reldesc = unsafe { PgBox::from_pg(relation.rd_att) };
+    let attrs = unsafe { reldesc.attrs.as_slice(reldesc.natts.try_into().unwrap()) };
+
+    let pstate = unsafe { PgBox::from_pg(pg_sys::make_parsestate(std::ptr::null_mut())) };
+    let nsitem = unsafe {
+        PgBox::from_pg(pg_sys::addRangeTableEntryForRelation(
+            pstate.as_ptr(),
+            relation.as_ptr(),
+            lockmode,
+            std::ptr::null_mut(),
+            true,
+            true,
+        ))
+    };
+    unsafe { pg_sys::addNSItemToQuery(pstate.as_ptr(), nsitem.as_ptr(), true, true, true) };
+
+    let values = attrs
+        .iter()
+        .map(|a| Some(utils::quote_name_data(&a.attname).to_string()))
+        .collect();
+    let target_list = transform_target_list(&pstate, attrs, values);
+    let quals = transform_expression(
+        &pstate,
+        &statistics::predicate(policy),
+        pg_sys::ParseExprKind::EXPR_KIND_WHERE,
+    );
+
+    unsafe {
+        pg_sys::relation_close(relation.as_ptr(), pg_sys::NoLock as i32);
+    }
+    make_query(&pstate, target_list, quals)
+}
+
 /// Prepare a ParseTree object from a SQL query
 ///
 pub fn parse_subquery(query_sql: String) -> PgBox<pg_sys::RawStmt> {
@@ -295,6 +792,10 @@ fn rule(
     Ok(seclabel_str)
 }
 
+pub fn rule_on_column(object_id: pg_sys::Oid, attnum: i16, policy: &str) -> Result<&str, Reason> {
+    rule(pg_sys::RelationRelationId, object_id, attnum.into(), policy)
+}
+
 pub fn rule_on_database(object_id: pg_sys::Oid, policy: &str) -> Result<&str, Reason> {
     rule(pg_sys::DatabaseRelationId, object_id, 0, policy)
 }
@@ -388,11 +889,151 @@ fn generation_expressions(relid: pg_sys::Oid) -> String {
     }
 }
 
+/// Build a SELECT Query object based on a ParseState
+///
+/// The range table and the join list are provided by the ParseState
+///
+fn make_query(
+    pstate: &PgBox<pg_sys::ParseState>,
+    target_list: *mut pg_sys::List,
+    quals: *mut pg_sys::Node,
+) -> PgBox<pg_sys::Query> {
+    if pstate.p_hasAggs || pstate.p_hasWindowFuncs {
+        error::feature_not_supported("Using an aggregate or a window function in a masking rule")
+            .ereport();
+    }
+
+    let mut query = unsafe { PgBox::<pg_sys::Query>::alloc_node(pg_sys::NodeTag::T_Query) };
+    query.commandType = pg_sys::CmdType::CMD_SELECT;
+    // This is marker for the masking subqueries, see `walker::rewrite_walker`
+    query.querySource = pg_sys::QuerySource::QSRC_PARSER;
+    query.canSetTag = true;
+    query.rtable = pstate.p_rtable;
+    compat::query_set_rteperminfos!(query, pstate);
+    query.jointree = unsafe { pg_sys::makeFromExpr(pstate.p_joinlist, quals) };
+    query.targetList = target_list;
+    query.hasSubLinks = pstate.p_hasSubLinks;
+    query.hasTargetSRFs = pstate.p_hasTargetSRFs;
+
+    unsafe {
+        pg_sys::assign_query_collations(pstate.as_ptr(), query.as_ptr());
+        PgBox::from_pg(query.into_pg())
+    }
+}
+
+/// Returns a parse state where the columns of a relation can be referenced
+///
+fn relation_pstate(relation: &PgBox<pg_sys::RelationData>) -> PgBox<pg_sys::ParseState> {
+    let pstate = unsafe { PgBox::from_pg(pg_sys::make_parsestate(std::ptr::null_mut())) };
+    let nsitem = unsafe {
+        pg_sys::addRangeTableEntryForRelation(
+            pstate.as_ptr(),
+            relation.as_ptr(),
+            pg_sys::AccessShareLock as i32,
+            std::ptr::null_mut(),
+            true,
+            true,
+        )
+    };
+    unsafe { pg_sys::addNSItemToQuery(pstate.as_ptr(), nsitem, true, true, true) };
+    pstate
+}
+
+/// Transform an expression in the context of a relation and return its type
+///
+/// The columns of the relation can be referenced by the expression. The
+/// errors raised by the parser are caught in a subtransaction and their
+/// message is returned.
+///
+fn analyze_expression(
+    relation: &PgBox<pg_sys::RelationData>,
+    expression: &str,
+) -> Result<pg_sys::Oid, String> {
+    let pstate = relation_pstate(relation);
+    utils::try_in_subtransaction(|| {
+        let node = transform_expression(
+            &pstate,
+            expression,
+            pg_sys::ParseExprKind::EXPR_KIND_SELECT_TARGET,
+        );
+        unsafe { pg_sys::exprType(node) }
+    })
+}
+
+/// Transform a SQL expression into a Node object
+///
+/// The expression must be a masking rule or a value that was already
+/// checked by the label provider
+///
+fn transform_expression(
+    pstate: &PgBox<pg_sys::ParseState>,
+    expr: &str,
+    kind: pg_sys::ParseExprKind::Type,
+) -> *mut pg_sys::Node {
+    let raw_expr = match input::parse_expression(expr) {
+        Ok(raw_expr) => raw_expr,
+        Err(e) => {
+            error::invalid_masking_rule(expr, Some(e)).ereport();
+            unreachable!()
+        }
+    };
+    let node = unsafe { pg_sys::transformExpr(pstate.as_ptr(), raw_expr.as_ptr(), kind) };
+
+    // Just like in a regular SELECT statement, an unknown-type literal
+    // is considered as a text value
+    if unsafe { pg_sys::exprType(node) } == pg_sys::UNKNOWNOID {
+        return unsafe {
+            pg_sys::coerce_to_specific_type(
+                pstate.as_ptr(),
+                node,
+                pg_sys::TEXTOID,
+                c_str!("SELECT").as_ptr(),
+            )
+        };
+    }
+    node
+}
+
+/// Transform a list of SQL expressions into a target list with one entry for
+/// each attribute of the relation
+///
+/// A None value is replaced by a NULL placeholder
+///
+fn transform_target_list(
+    pstate: &PgBox<pg_sys::ParseState>,
+    attrs: &[pg_sys::FormData_pg_attribute],
+    values: Vec<Option<String>>,
+) -> *mut pg_sys::List {
+    let mut target_list = PgList::<pg_sys::TargetEntry>::new();
+    for (a, value) in attrs.iter().zip(values) {
+        let expr = match value {
+            Some(value) => transform_expression(
+                pstate,
+                &value,
+                pg_sys::ParseExprKind::EXPR_KIND_SELECT_TARGET,
+            ),
+            None => unsafe {
+                pg_sys::makeNullConst(pg_sys::INT4OID, -1, pg_sys::InvalidOid) as *mut pg_sys::Node
+            },
+        };
+        let target = unsafe {
+            pg_sys::makeTargetEntry(
+                expr as *mut pg_sys::Expr,
+                a.attnum,
+                pg_sys::pstrdup(a.attname.data.as_ptr()),
+                false,
+            )
+        };
+        target_list.push(target);
+    }
+    target_list.into_pg()
+}
+
 /// Check that a role is masked in the given policy
 ///
 fn has_mask_in_policy(roleid: pg_sys::Oid, policy: &'static str) -> bool {
     if let Ok(seclabel) = rule_on_role(roleid, policy) {
-        return re::is_match_masked(seclabel);
+        return matches!(masking_rule::parse(seclabel), Ok(MaskingRule::Masked(_)));
     }
     false
 }
@@ -476,6 +1117,24 @@ fn default_for_att(
     None
 }
 
+/// Wrap a masking expression inside a CASE statement so that the mask is
+/// applied only to the rows matching the condition of the rule.
+///
+/// When the predicate is NULL, the value is masked. In other words: if we
+/// can't be sure that the authentic value can be revealed, we hide it.
+///
+fn conditional_mask(mask: &str, attname: &str, condition: &Condition) -> String {
+    let predicate = condition.predicate;
+    match condition.kind {
+        ConditionKind::When => {
+            format!("CASE WHEN ({predicate}) IS NOT FALSE THEN {mask} ELSE {attname} END")
+        }
+        ConditionKind::Unless => {
+            format!("CASE WHEN ({predicate}) IS TRUE THEN {attname} ELSE {mask} END")
+        }
+    }
+}
+
 /// Returns the masking value for a column, with a string and a bool
 ///
 /// the bool means whether the column is masked or not
@@ -492,69 +1151,53 @@ pub fn value_for_att(
     att: &pg_sys::FormData_pg_attribute,
     policy: String,
 ) -> (String, bool) {
+    let (value, kind) = masking_for_att(rel, att, policy);
+    (value, kind != MaskingKind::NotMasked)
+}
+
+/// Same as `value_for_att()` but the bool is replaced by the kind of masking
+/// applied to the column
+///
+fn masking_for_att(
+    rel: &PgBox<pg_sys::RelationData>,
+    att: &pg_sys::FormData_pg_attribute,
+    policy: String,
+) -> (String, MaskingKind) {
     let attname = utils::quote_name_data(&att.attname);
 
     // Get the masking rule, if any
-
-    // This is similar to the ObjectAddressSubSet C macro
-    // https://doxygen.postgresql.org/objectaddress_8h.html
-    let columnobject = pg_sys::ObjectAddress {
-        classId: pg_sys::RelationRelationId,
-        objectId: rel.rd_id,
-        objectSubId: att.attnum as i32,
-    };
-    let policy_c_str = CString::new(policy).unwrap();
-    let policy_c_ptr = policy_c_str.as_ptr();
-    let seclabel_c_ptr = unsafe {
-        PgBox::from_pg(pg_sys::GetSecurityLabel(
-            &columnobject,
-            policy_c_ptr as *const c_char,
-        ))
-    };
-
-    let seclabel_cstr = {
-        if seclabel_c_ptr.as_ptr().is_null() {
-            c_str!("")
-        } else {
-            unsafe { CStr::from_ptr(seclabel_c_ptr.as_ptr()) }
-        }
-    };
+    let seclabel = cache::column_rule(rel.rd_id, att.attnum, &policy).unwrap_or_default();
 
     // No masking rule found and Privacy By Default is off,
     // the authentic value is revealed
-    if seclabel_cstr.is_empty() && !guc::ANON_PRIVACY_BY_DEFAULT.get() {
-        return (attname.to_string(), false);
+    if seclabel.is_empty() && !guc::ANON_PRIVACY_BY_DEFAULT.get() {
+        return (attname.to_string(), MaskingKind::NotMasked);
     }
 
-    let seclabel = seclabel_cstr.to_str().expect("Failed to convert seclabel");
-
     // A masking rule was found
-
-    // Search for a masking function
-    if let Some(function) = re::capture_function(seclabel) {
-        if guc::ANON_STRICT_MODE.get() {
-            return (
-                cast_as_regtype(function.to_string(), att.atttypid, att.atttypmod),
-                true,
-            );
+    match masking_rule::parse(&seclabel) {
+        // Apply the masking function or the masking value, with the optional
+        // WHEN / UNLESS condition
+        Ok(MaskingRule::MaskedWith { mask, condition }) => {
+            let kind = match mask {
+                Mask::Function(_) => MaskingKind::Function,
+                Mask::Value(_) => MaskingKind::Value,
+            };
+            let mask = if guc::ANON_STRICT_MODE.get() {
+                cast_as_regtype(mask.expression().to_string(), att.atttypid, att.atttypmod)
+            } else {
+                mask.expression().to_string()
+            };
+            return match condition {
+                Some(condition) => (conditional_mask(&mask, attname, &condition), kind),
+                None => (mask, kind),
+            };
         }
-        return (function.to_string(), true);
-    }
 
-    // Search for a masking value
-    if let Some(value) = re::capture_value(seclabel) {
-        if guc::ANON_STRICT_MODE.get() {
-            return (
-                cast_as_regtype(value.to_string(), att.atttypid, att.atttypmod),
-                true,
-            );
-        }
-        return (value.to_string(), true);
-    }
+        // The column is declared as not masked, the authentic value is shown
+        Ok(MaskingRule::NotMasked) => return (attname.to_string(), MaskingKind::NotMasked),
 
-    // The column is declared as not masked, the authentic value is shown
-    if re::is_match_not_masked(seclabel) {
-        return (attname.to_string(), false);
+        _ => (),
     }
 
     // There's no masking
@@ -565,14 +1208,14 @@ pub fn value_for_att(
     if att.atthasdef && att.attnum > 0 && !att.attisdropped {
         if let Some(default_value) = default_for_att(rel, att, false) {
             // mask with the default value
-            return (default_value, true);
+            return (default_value, MaskingKind::Default);
         }
         // no default value, mask with "NULL"
-        return ("NULL".to_string(), true);
+        return ("NULL".to_string(), MaskingKind::Null);
     }
 
     // No default value, "NULL" (the literal value) is the last possibility
-    ("NULL".to_string(), true)
+    ("NULL".to_string(), MaskingKind::Null)
 }
 
 //----------------------------------------------------------------------------
@@ -699,6 +1342,132 @@ mod tests {
         assert_eq!(get_masking_policy(anna), analytics);
     }
 
+    #[pg_test]
+    fn test_get_masking_policy_inherited() {
+        let batman = fixture::create_masked_role();
+        Spi::run(
+            "
+            CREATE ROLE robin IN ROLE batman;
+            CREATE ROLE nightwing IN ROLE robin;
+        ",
+        )
+        .unwrap();
+        let nightwing = Spi::get_one::<pg_sys::Oid>("SELECT 'nightwing'::REGROLE::OID")
+            .unwrap()
+            .expect("should be an OID");
+        let expected = Some(ANON_DEFAULT_MASKING_POLICY.to_string());
+        assert_eq!(get_masking_policy(batman), expected);
+        assert_eq!(get_masking_policy(nightwing), expected);
+    }
+
+    #[pg_test]
+    fn test_get_masking_policy_noinherit() {
+        fixture::create_masked_role();
+        Spi::run("CREATE ROLE alfred NOINHERIT IN ROLE batman").unwrap();
+        let alfred = Spi::get_one::<pg_sys::Oid>("SELECT 'alfred'::REGROLE::OID")
+            .unwrap()
+            .expect("should be an OID");
+        assert!(get_masking_policy(alfred).is_none());
+    }
+
+    #[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+    #[pg_test]
+    fn test_get_masking_policy_inherit_false() {
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            CREATE ROLE alfred;
+            GRANT batman TO alfred WITH INHERIT FALSE;
+        ",
+        )
+        .unwrap();
+        let alfred = Spi::get_one::<pg_sys::Oid>("SELECT 'alfred'::REGROLE::OID")
+            .unwrap()
+            .expect("should be an OID");
+        // alfred is masked only after `SET ROLE batman`
+        assert!(get_masking_policy(alfred).is_none());
+    }
+
+    #[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+    #[pg_test]
+    fn test_get_masking_policy_createrole_admin() {
+        Spi::run(
+            "
+            CREATE ROLE alfred CREATEROLE;
+            SET ROLE alfred;
+            CREATE ROLE robin;
+            RESET ROLE;
+            SECURITY LABEL FOR anon ON ROLE robin IS 'MASKED';
+        ",
+        )
+        .unwrap();
+        let alfred = Spi::get_one::<pg_sys::Oid>("SELECT 'alfred'::REGROLE::OID")
+            .unwrap()
+            .expect("should be an OID");
+        // alfred was granted robin WITH ADMIN OPTION, INHERIT FALSE, SET FALSE
+        assert!(get_masking_policy(alfred).is_none());
+    }
+
+    #[pg_test]
+    fn test_get_masking_limits() {
+        let batman = fixture::create_masked_role();
+        Spi::run(
+            "
+            CREATE ROLE robin IN ROLE batman;
+            SECURITY LABEL FOR anon ON ROLE robin
+              IS 'MASKED WITH ROW LIMIT 10';
+            CREATE ROLE nightwing IN ROLE robin;
+        ",
+        )
+        .unwrap();
+        let nightwing = Spi::get_one::<pg_sys::Oid>("SELECT 'nightwing'::REGROLE::OID")
+            .unwrap()
+            .expect("should be an OID");
+        let robin_limits = Some(limits::RoleLimits {
+            row_limit: Some(10),
+            statement_timeout: None,
+        });
+        assert_eq!(
+            get_masking_limits(batman, ANON_DEFAULT_MASKING_POLICY),
+            Some(limits::RoleLimits::default())
+        );
+        assert_eq!(
+            get_masking_limits(nightwing, ANON_DEFAULT_MASKING_POLICY),
+            robin_limits
+        );
+        assert_eq!(get_masking_limits(nightwing, "devtests"), None);
+    }
+
+    #[pg_test]
+    fn test_get_masking_policy_precedence() {
+        fixture::declare_masking_policies();
+        label_providers::register_label_providers();
+        fixture::create_masked_role_in_policy("devin", "devtests");
+        fixture::create_masked_role_in_policy("anna", "analytics");
+        Spi::run(
+            "
+            CREATE ROLE carol IN ROLE anna, devin;
+            CREATE ROLE dave IN ROLE devin;
+            CREATE ROLE erin IN ROLE dave, anna;
+        ",
+        )
+        .unwrap();
+        let oid = |r: &str| {
+            Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{r}'::REGROLE::OID"))
+                .unwrap()
+                .expect("should be an OID")
+        };
+        let devtests = Some("devtests".to_string());
+        let analytics = Some("analytics".to_string());
+        // same distance: the policy order is used
+        assert_eq!(get_masking_policy(oid("carol")), devtests);
+        // the closest role wins
+        assert_eq!(get_masking_policy(oid("erin")), analytics);
+        // a label on the role itself always wins
+        Spi::run("SECURITY LABEL FOR analytics ON ROLE dave IS 'MASKED';").unwrap();
+        assert_eq!(get_masking_policy(oid("dave")), analytics);
+    }
+
     #[pg_test]
     fn test_has_mask_in_policy_anon() {
         let batman = fixture::create_masked_role();
@@ -725,6 +1494,17 @@ mod tests {
         assert!(!has_mask_in_policy(anna, "devtests"));
     }
 
+    #[pg_test]
+    fn test_inference_guard() {
+        assert_eq!(inference_guard("anon"), InferenceGuard::Off);
+        Spi::run("SET anon.inference_guard TO 'anon:warning, devtests:audit, analytics:foo'")
+            .unwrap();
+        assert_eq!(inference_guard("anon"), InferenceGuard::Warning);
+        assert_eq!(inference_guard("devtests"), InferenceGuard::Audit);
+        assert_eq!(inference_guard("analytics"), InferenceGuard::Error);
+        assert_eq!(inference_guard("support"), InferenceGuard::Off);
+    }
+
     #[pg_test]
     fn test_list_masking_policies_default() {
         assert_eq!(vec![ANON_DEFAULT_MASKING_POLICY], list_masking_policies());
@@ -761,6 +1541,59 @@ mod tests {
         assert_eq!(expected_3, result_3);
     }
 
+    #[pg_test]
+    fn test_explain_masking() {
+        let relid = fixture::create_table_person();
+        let anon = ANON_DEFAULT_MASKING_POLICY.to_string();
+        let ratio = Some("BERNOULLI(10)".to_string());
+        assert_eq!(
+            explain_masking(relid, anon),
+            vec![
+                (
+                    "firstname".to_string(),
+                    None,
+                    "not masked".to_string(),
+                    "firstname".to_string(),
+                    false,
+                    ratio.clone()
+                ),
+                (
+                    "lastname".to_string(),
+                    Some("MASKED WITH VALUE NULL".to_string()),
+                    "value".to_string(),
+                    "CAST(NULL AS text)".to_string(),
+                    false,
+                    ratio
+                ),
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_explain_masking_privacy_by_default() {
+        let relid = fixture::create_table_with_defaults();
+        Spi::run("SET anon.privacy_by_default TO on").unwrap();
+        let kinds: Vec<(String, String, bool)> =
+            explain_masking(relid, ANON_DEFAULT_MASKING_POLICY.to_string())
+                .into_iter()
+                .map(|(attname, _, kind, _, generated, _)| (attname, kind, generated))
+                .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("id".to_string(), "null".to_string(), false),
+                ("col_with_default".to_string(), "default".to_string(), false),
+                (
+                    "col_with_complex_default".to_string(),
+                    "default".to_string(),
+                    false
+                ),
+                ("col_without_default".to_string(), "null".to_string(), false),
+                ("col_generated".to_string(), "null".to_string(), true),
+            ]
+        );
+    }
+
     #[pg_test]
     fn test_masking_expressions() {
         let relid = fixture::create_table_person();
@@ -897,6 +1730,18 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[pg_test]
+    fn test_subquery_filter() {
+        let relid = fixture::create_table_call();
+        Spi::run("SECURITY LABEL FOR anon ON TABLE call IS 'FILTER WITH sender IS NOT NULL';")
+            .unwrap();
+        let result = subquery(relid, ANON_DEFAULT_MASKING_POLICY.to_string());
+        assert!(result.is_some());
+        assert!(result.unwrap().contains("WHERE (sender IS NOT NULL)"));
+        let another_policy = "does_not_exist".to_string();
+        assert!(subquery(relid, another_policy).is_none());
+    }
+
     #[pg_test]
     fn test_parse_subquery() {
         let relid = fixture::create_table_person();
@@ -907,6 +1752,27 @@ mod tests {
         assert!(result.contains("lastname"));
     }
 
+    #[pg_test]
+    fn test_subquery_tree() {
+        let relid = fixture::create_table_person();
+        let query = subquery_tree(relid, ANON_DEFAULT_MASKING_POLICY.to_string()).unwrap();
+        // the dropped column is replaced by a placeholder
+        let targets = unsafe { PgList::<pg_sys::TargetEntry>::from_pg(query.targetList) };
+        assert_eq!(targets.len(), 3);
+        let rtable = unsafe { PgList::<pg_sys::RangeTblEntry>::from_pg(query.rtable) };
+        let rte = unsafe { PgBox::from_pg(rtable.head().unwrap()) };
+        assert_eq!(rte.relid, relid);
+        assert!(!rte.tablesample.is_null());
+    }
+
+    #[pg_test]
+    fn test_subquery_tree_none() {
+        let relid = fixture::create_table_call();
+        assert!(subquery_tree(relid, ANON_DEFAULT_MASKING_POLICY.to_string()).is_none());
+        let relid = fixture::create_table_person();
+        assert!(subquery_tree(relid, "does_not_exist".to_string()).is_none());
+    }
+
     #[pg_test]
     fn test_value_for_att() {
         // Create a table
@@ -945,6 +1811,49 @@ mod tests {
         assert!(!masked5);
     }
 
+    #[pg_test]
+    fn test_value_for_att_conditional() {
+        let relid = fixture::create_table_person();
+        Spi::run(
+            "
+            SECURITY LABEL FOR anon ON COLUMN person.firstname
+              IS 'MASKED WITH VALUE ''xxx'' WHEN lastname <> ''Connor''';
+            SECURITY LABEL FOR anon ON COLUMN person.lastname
+              IS 'MASKED WITH VALUE NULL UNLESS firstname = ''Sarah''';
+        ",
+        )
+        .unwrap();
+        let lockmode = pg_sys::AccessShareLock as i32;
+        let relation = unsafe { PgBox::from_pg(pg_sys::relation_open(relid, lockmode)) };
+        let reldesc = unsafe { PgBox::from_pg(relation.rd_att) };
+
+        let natts = reldesc.natts;
+        let attrs = unsafe { reldesc.attrs.as_slice(natts.try_into().unwrap()) };
+        let att_firstname = attrs[1];
+        let att_lastname = attrs[2];
+
+        let (val1, masked1) = value_for_att(&relation, &att_firstname, "anon".into());
+        assert_eq!(
+            val1,
+            "CASE WHEN (lastname <> 'Connor') IS NOT FALSE \
+             THEN CAST('xxx' AS character varying(30)) ELSE firstname END"
+        );
+        assert!(masked1);
+
+        let (val2, masked2) = value_for_att(&relation, &att_lastname, "anon".into());
+        assert_eq!(
+            val2,
+            "CASE WHEN (firstname = 'Sarah') IS TRUE \
+             THEN lastname ELSE CAST(NULL AS text) END"
+        );
+        assert!(masked2);
+
+        let firstname = Spi::get_one::<String>(&format!("SELECT {val1} FROM person"));
+        assert_eq!(firstname, Ok(Some("Sarah".to_string())));
+        let lastname = Spi::get_one::<String>(&format!("SELECT {val2} FROM person"));
+        assert_eq!(lastname, Ok(Some("Connor".to_string())));
+    }
+
     #[pg_test]
     fn test_value_for_att_with_quotes() {
         // Create a table
@@ -0,0 +1,898 @@
+///
+/// # Masking Rules
+///
+/// A security label is parsed into a `MaskingRule`. The keywords of the rule
+/// are case-insensitive and may be separated by any blank characters or SQL
+/// comments.
+///
+/// The expressions (masking functions, masking values, predicates, etc.) are
+/// not parsed here: they are checked by the Postgres parser later. However
+/// the parser is aware of the SQL lexical rules so that a `WHEN` keyword
+/// inside a string, a comment, a sub-expression or a `CASE` expression is not
+/// considered as the beginning of a condition.
+///
+use std::fmt;
+
+//----------------------------------------------------------------------------
+// Abstract Syntax Tree
+//----------------------------------------------------------------------------
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Mask<'a> {
+    /// `MASKED WITH FUNCTION anon.fake_email()`
+    Function(&'a str),
+    /// `MASKED WITH VALUE NULL`
+    Value(&'a str),
+}
+
+impl<'a> Mask<'a> {
+    pub fn expression(&self) -> &'a str {
+        match self {
+            Mask::Function(expr) | Mask::Value(expr) => expr,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ConditionKind {
+    When,
+    Unless,
+}
+
+/// `... WHEN country <> 'FR'` or `... UNLESS owner = current_user`
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Condition<'a> {
+    pub kind: ConditionKind,
+    pub predicate: &'a str,
+}
+
+/// `... WITH ROW LIMIT 1000 AND STATEMENT TIMEOUT '30s'`
+///
+/// The values are checked by the `limits` module
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Limits<'a> {
+    pub row_limit: Option<&'a str>,
+    pub statement_timeout: Option<&'a str>,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MaskingRule<'a> {
+    /// Columns: `MASKED WITH FUNCTION ...` or `MASKED WITH VALUE ...`
+    MaskedWith {
+        mask: Mask<'a>,
+        condition: Option<Condition<'a>>,
+    },
+    /// Columns: `NOT MASKED`
+    NotMasked,
+    /// Roles: `MASKED` or `MASKED WITH ROW LIMIT ... AND STATEMENT TIMEOUT ...`
+    Masked(Limits<'a>),
+    /// Functions and schemas: `TRUSTED`
+    Trusted,
+    /// Functions: `UNTRUSTED`
+    Untrusted,
+    /// Functions: `FORBIDDEN`
+    Forbidden,
+    /// Tables and databases: `TABLESAMPLE SYSTEM(10)`
+    TableSample(&'a str),
+    /// Tables: `FILTER WITH region = 'EU'`
+    Filter(&'a str),
+    /// Columns (k_anonymity): `INDIRECT IDENTIFIER` or `QUASI IDENTIFIER`
+    IndirectIdentifier,
+}
+
+//----------------------------------------------------------------------------
+// Errors
+//----------------------------------------------------------------------------
+
+/// The position is the number of characters from the start of the label,
+/// starting at 1, just like the Postgres error positions.
+///
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (position {})", self.message, self.position)
+    }
+}
+
+//----------------------------------------------------------------------------
+// Scanner
+//----------------------------------------------------------------------------
+
+struct Scanner<'a> {
+    label: &'a str,
+    /// current offset, in bytes
+    offset: usize,
+}
+
+fn is_word_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c >= 0x80
+}
+
+fn is_word_char(c: u8) -> bool {
+    is_word_start(c) || c.is_ascii_digit() || c == b'$'
+}
+
+impl<'a> Scanner<'a> {
+    fn new(label: &'a str) -> Self {
+        Scanner { label, offset: 0 }
+    }
+
+    fn bytes(&self) -> &'a [u8] {
+        self.label.as_bytes()
+    }
+
+    /// Convert a byte offset into a character position
+    fn position(&self, offset: usize) -> usize {
+        self.label[..offset].chars().count() + 1
+    }
+
+    fn error(&self, message: String, offset: usize) -> ParseError {
+        ParseError {
+            message,
+            position: self.position(offset),
+        }
+    }
+
+    /// Skip the spaces and the comments, returns false if the end of the
+    /// label is reached
+    fn skip_blanks(&mut self) -> bool {
+        let bytes = self.bytes();
+        while self.offset < bytes.len() {
+            let rest = &bytes[self.offset..];
+            if rest[0].is_ascii_whitespace() {
+                self.offset += 1;
+            } else if rest.starts_with(b"--") {
+                self.offset = skip_line_comment(bytes, self.offset);
+            } else if rest.starts_with(b"/*") {
+                self.offset = skip_block_comment(bytes, self.offset);
+            } else {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Read the next token, a token is either a word or any sequence of non
+    /// blank characters
+    fn token(&mut self) -> Option<(usize, &'a str)> {
+        if !self.skip_blanks() {
+            return None;
+        }
+        let bytes = self.bytes();
+        let start = self.offset;
+        let word = is_word_start(bytes[start]);
+        while self.offset < bytes.len() {
+            let c = bytes[self.offset];
+            if (word && !is_word_char(c)) || (!word && c.is_ascii_whitespace()) {
+                break;
+            }
+            self.offset += 1;
+        }
+        Some((start, &self.label[start..self.offset]))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str, after: &str) -> Result<(), ParseError> {
+        match self.token() {
+            Some((_, word)) if word.eq_ignore_ascii_case(keyword) => Ok(()),
+            Some((start, token)) => Err(self.error(
+                format!("syntax error at or near \"{token}\", expected {keyword} after {after}"),
+                start,
+            )),
+            None => Err(self.error(format!("missing {keyword} after {after}"), self.label.len())),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        match self.token() {
+            Some((start, token)) => {
+                Err(self.error(format!("syntax error at or near \"{token}\""), start))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Read a value, either a token or a quoted string without its quotes
+    fn value(&mut self, what: &str) -> Result<&'a str, ParseError> {
+        if !self.skip_blanks() {
+            return Err(self.error(format!("missing {what}"), self.label.len()));
+        }
+        let bytes = self.bytes();
+        let start = self.offset;
+        if bytes[start] != b'\'' {
+            return Ok(self.token().unwrap().1);
+        }
+        self.offset = skip_quoted(bytes, start, b'\'', false);
+        if self.offset - start < 2 || bytes[self.offset - 1] != b'\'' {
+            return Err(self.error("unterminated quoted string".to_string(), start));
+        }
+        Ok(&self.label[start + 1..self.offset - 1])
+    }
+
+    /// Return the rest of the label, without the blanks around it
+    fn expression(&mut self, what: &str) -> Result<(usize, &'a str), ParseError> {
+        if !self.skip_blanks() {
+            return Err(self.error(format!("missing {what}"), self.label.len()));
+        }
+        let start = self.offset;
+        self.offset = self.label.len();
+        let end = expression_end(self.bytes(), start, self.label.len());
+        Ok((start, &self.label[start..end]))
+    }
+}
+
+/// Returns the end of an expression, without the blanks and the line
+/// comments after it
+///
+/// The expressions are embedded in the generated SQL (e.g. in a CAST), so
+/// a trailing `-- comment` would comment out the rest of the query. The
+/// block comments are kept, they can't escape the expression.
+///
+fn expression_end(bytes: &[u8], from: usize, to: usize) -> usize {
+    let bytes = &bytes[..to];
+    let mut end = from;
+    let mut i = from;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let rest = &bytes[i..];
+        if rest.starts_with(b"--") {
+            i = skip_line_comment(bytes, i);
+            continue;
+        } else if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if rest.starts_with(b"/*") {
+            i = skip_block_comment(bytes, i);
+        } else if c == b'\'' {
+            i = skip_quoted(bytes, i, b'\'', false);
+        } else if c == b'"' {
+            i = skip_quoted(bytes, i, b'"', false);
+        } else if c == b'$' {
+            match dollar_quote_tag(bytes, i) {
+                Some(tag) => {
+                    let body = i + tag.len();
+                    i = match bytes[body..].windows(tag.len()).position(|w| w == tag) {
+                        Some(end) => body + end + tag.len(),
+                        None => bytes.len(),
+                    };
+                }
+                None => i += 1,
+            }
+        } else if is_word_start(c) {
+            let start = i;
+            while i < bytes.len() && is_word_char(bytes[i]) {
+                i += 1;
+            }
+            // E'...' strings accept backslash escapes
+            if i - start == 1 && (c == b'E' || c == b'e') && bytes.get(i) == Some(&b'\'') {
+                i = skip_quoted(bytes, i, b'\'', true);
+            }
+        } else {
+            i += 1;
+        }
+        end = i;
+    }
+    end
+}
+
+fn skip_line_comment(bytes: &[u8], offset: usize) -> usize {
+    match bytes[offset..].iter().position(|&c| c == b'\n') {
+        Some(eol) => offset + eol + 1,
+        None => bytes.len(),
+    }
+}
+
+/// Block comments can be nested
+fn skip_block_comment(bytes: &[u8], offset: usize) -> usize {
+    let mut depth = 0;
+    let mut i = offset;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Skip a quoted string or identifier, the quote may be doubled inside the
+/// string. Backslashes are escape characters only in `E'...'` strings
+fn skip_quoted(bytes: &[u8], offset: usize, quote: u8, backslash: bool) -> usize {
+    let mut i = offset + 1;
+    while i < bytes.len() {
+        if backslash && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the tag of a dollar quote (e.g. `$$` or `$body$`) starting at
+/// the offset, if any
+fn dollar_quote_tag(bytes: &[u8], offset: usize) -> Option<&[u8]> {
+    let rest = &bytes[offset..];
+    if rest.len() > 1 && rest[1].is_ascii_digit() {
+        // $1 is a parameter
+        return None;
+    }
+    let end = rest[1..].iter().position(|&c| c == b'$')? + 2;
+    let tag = &rest[..end];
+    if tag[1..end - 1]
+        .iter()
+        .all(|&c| is_word_char(c) && c != b'$')
+    {
+        return Some(tag);
+    }
+    None
+}
+
+/// Find the condition keyword (`WHEN` or `UNLESS`) of a masking rule
+///
+/// Returns the offset of the keyword, the keyword itself and the offset of the
+/// end of the keyword
+///
+fn find_condition(bytes: &[u8], from: usize) -> Option<(usize, ConditionKind, usize)> {
+    let mut parentheses: u32 = 0;
+    let mut cases: u32 = 0;
+    let mut i = from;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let rest = &bytes[i..];
+        if rest.starts_with(b"--") {
+            i = skip_line_comment(bytes, i);
+        } else if rest.starts_with(b"/*") {
+            i = skip_block_comment(bytes, i);
+        } else if c == b'\'' {
+            i = skip_quoted(bytes, i, b'\'', false);
+        } else if c == b'"' {
+            i = skip_quoted(bytes, i, b'"', false);
+        } else if c == b'$' {
+            match dollar_quote_tag(bytes, i) {
+                Some(tag) => {
+                    let body = i + tag.len();
+                    i = match bytes[body..].windows(tag.len()).position(|w| w == tag) {
+                        Some(end) => body + end + tag.len(),
+                        None => bytes.len(),
+                    };
+                }
+                None => i += 1,
+            }
+        } else if c == b'(' || c == b'[' {
+            parentheses += 1;
+            i += 1;
+        } else if c == b')' || c == b']' {
+            parentheses = parentheses.saturating_sub(1);
+            i += 1;
+        } else if is_word_start(c) {
+            let start = i;
+            while i < bytes.len() && is_word_char(bytes[i]) {
+                i += 1;
+            }
+            // E'...' strings accept backslash escapes
+            if i - start == 1 && (c == b'E' || c == b'e') && bytes.get(i) == Some(&b'\'') {
+                i = skip_quoted(bytes, i, b'\'', true);
+                continue;
+            }
+            if parentheses > 0 {
+                continue;
+            }
+            let word = &bytes[start..i];
+            if word.eq_ignore_ascii_case(b"CASE") {
+                cases += 1;
+            } else if word.eq_ignore_ascii_case(b"END") {
+                cases = cases.saturating_sub(1);
+            } else if cases == 0 && word.eq_ignore_ascii_case(b"WHEN") {
+                return Some((start, ConditionKind::When, i));
+            } else if cases == 0 && word.eq_ignore_ascii_case(b"UNLESS") {
+                return Some((start, ConditionKind::Unless, i));
+            }
+        } else if c.is_ascii_digit() {
+            // skip numbers such as `1e10` which are not words
+            while i < bytes.len() && is_word_char(bytes[i]) {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+//----------------------------------------------------------------------------
+// Parser
+//----------------------------------------------------------------------------
+
+/// Parse a security label
+///
+pub fn parse(label: &str) -> Result<MaskingRule<'_>, ParseError> {
+    let mut s = Scanner::new(label);
+    let Some((start, keyword)) = s.token() else {
+        return Err(s.error("the label is empty".to_string(), 0));
+    };
+
+    match keyword.to_ascii_uppercase().as_str() {
+        "MASKED" => {
+            if s.token().is_none() {
+                return Ok(MaskingRule::Masked(Limits::default()));
+            }
+            // Go back and read the WITH keyword
+            s.offset = start + keyword.len();
+            s.expect_keyword("WITH", "MASKED")?;
+            let with = s.offset;
+            match s.token() {
+                Some((_, word))
+                    if word.eq_ignore_ascii_case("ROW")
+                        || word.eq_ignore_ascii_case("STATEMENT") =>
+                {
+                    s.offset = with;
+                    parse_limits(&mut s)
+                }
+                _ => {
+                    s.offset = with;
+                    parse_masked_with(&mut s)
+                }
+            }
+        }
+        "NOT" => {
+            s.expect_keyword("MASKED", "NOT")?;
+            s.expect_end()?;
+            Ok(MaskingRule::NotMasked)
+        }
+        "TRUSTED" => {
+            s.expect_end()?;
+            Ok(MaskingRule::Trusted)
+        }
+        "UNTRUSTED" => {
+            s.expect_end()?;
+            Ok(MaskingRule::Untrusted)
+        }
+        "FORBIDDEN" => {
+            s.expect_end()?;
+            Ok(MaskingRule::Forbidden)
+        }
+        "TABLESAMPLE" => {
+            let (_, ratio) = s.expression("sampling ratio after TABLESAMPLE")?;
+            Ok(MaskingRule::TableSample(ratio))
+        }
+        "FILTER" => {
+            s.expect_keyword("WITH", "FILTER")?;
+            let (_, predicate) = s.expression("predicate after FILTER WITH")?;
+            Ok(MaskingRule::Filter(predicate))
+        }
+        "INDIRECT" | "QUASI" => {
+            s.expect_keyword("IDENTIFIER", &keyword.to_ascii_uppercase())?;
+            s.expect_end()?;
+            Ok(MaskingRule::IndirectIdentifier)
+        }
+        _ => Err(s.error(format!("syntax error at or near \"{keyword}\""), start)),
+    }
+}
+
+/// Parse the end of a `MASKED WITH ROW LIMIT ... AND STATEMENT TIMEOUT ...`
+/// rule, each limit may be declared once and in any order
+fn parse_limits<'a>(s: &mut Scanner<'a>) -> Result<MaskingRule<'a>, ParseError> {
+    let mut limits = Limits::default();
+    let mut after = "MASKED WITH";
+    loop {
+        let (start, name, limit) = match s.token() {
+            Some((start, word)) if word.eq_ignore_ascii_case("ROW") => {
+                s.expect_keyword("LIMIT", "ROW")?;
+                (start, "ROW LIMIT", &mut limits.row_limit)
+            }
+            Some((start, word)) if word.eq_ignore_ascii_case("STATEMENT") => {
+                s.expect_keyword("TIMEOUT", "STATEMENT")?;
+                (start, "STATEMENT TIMEOUT", &mut limits.statement_timeout)
+            }
+            Some((start, token)) => {
+                let expected = format!("expected ROW LIMIT or STATEMENT TIMEOUT after {after}");
+                return Err(s.error(
+                    format!("syntax error at or near \"{token}\", {expected}"),
+                    start,
+                ));
+            }
+            None => {
+                return Err(s.error(
+                    format!("missing ROW LIMIT or STATEMENT TIMEOUT after {after}"),
+                    s.label.len(),
+                ))
+            }
+        };
+        if limit.is_some() {
+            return Err(s.error(format!("{name} is declared twice"), start));
+        }
+        *limit = Some(s.value(&format!("value after {name}"))?);
+        match s.token() {
+            None => return Ok(MaskingRule::Masked(limits)),
+            Some((_, word)) if word.eq_ignore_ascii_case("AND") => after = "AND",
+            Some((start, token)) => {
+                return Err(s.error(format!("syntax error at or near \"{token}\""), start))
+            }
+        }
+    }
+}
+
+/// Parse the end of a `MASKED WITH FUNCTION|VALUE ...` rule
+fn parse_masked_with<'a>(s: &mut Scanner<'a>) -> Result<MaskingRule<'a>, ParseError> {
+    let kind = match s.token() {
+        Some((_, word)) if word.eq_ignore_ascii_case("FUNCTION") => "FUNCTION",
+        Some((_, word)) if word.eq_ignore_ascii_case("VALUE") => "VALUE",
+        Some((start, token)) => {
+            let expected = "expected FUNCTION or VALUE after MASKED WITH";
+            return Err(s.error(
+                format!("syntax error at or near \"{token}\", {expected}"),
+                start,
+            ));
+        }
+        None => {
+            return Err(s.error(
+                "missing FUNCTION or VALUE after MASKED WITH".to_string(),
+                s.label.len(),
+            ))
+        }
+    };
+
+    let (start, _) = s.expression(&format!("masking expression after MASKED WITH {kind}"))?;
+    let bytes = s.bytes();
+
+    let (expression, condition) = match find_condition(bytes, start) {
+        Some((keyword, condition_kind, end)) => {
+            let expression = &s.label[start..expression_end(bytes, start, keyword)];
+            if expression.is_empty() {
+                return Err(s.error(
+                    format!("missing masking expression after MASKED WITH {kind}"),
+                    keyword,
+                ));
+            }
+            let predicate = s.label[end..expression_end(bytes, end, bytes.len())].trim();
+            if predicate.is_empty() {
+                let keyword_str = &s.label[keyword..end];
+                return Err(s.error(
+                    format!(
+                        "missing condition after {}",
+                        keyword_str.to_ascii_uppercase()
+                    ),
+                    s.label.len(),
+                ));
+            }
+            let condition = Condition {
+                kind: condition_kind,
+                predicate,
+            };
+            (expression, Some(condition))
+        }
+        None => (
+            &s.label[start..expression_end(bytes, start, bytes.len())],
+            None,
+        ),
+    };
+
+    let mask = match kind {
+        "FUNCTION" => Mask::Function(expression),
+        _ => Mask::Value(expression),
+    };
+    Ok(MaskingRule::MaskedWith { mask, condition })
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::masking_rule::*;
+
+    fn function(expr: &str) -> MaskingRule<'_> {
+        MaskingRule::MaskedWith {
+            mask: Mask::Function(expr),
+            condition: None,
+        }
+    }
+
+    fn value(expr: &str) -> MaskingRule<'_> {
+        MaskingRule::MaskedWith {
+            mask: Mask::Value(expr),
+            condition: None,
+        }
+    }
+
+    fn position(label: &str) -> usize {
+        parse(label).unwrap_err().position
+    }
+
+    #[test]
+    fn test_parse_function() {
+        assert_eq!(
+            parse("masked WITH function public.foo($$x$$)"),
+            Ok(function("public.foo($$x$$)"))
+        );
+        assert_eq!(
+            parse(" masked  WITH funCTION bar(0,\n        $$y$$) "),
+            Ok(function("bar(0,\n        $$y$$)"))
+        );
+        assert!(parse("MASKED WITH FUNCTION").is_err());
+        assert!(parse("MASKED WITH public.foo()").is_err());
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse("MASKED  WiTH value NULL "), Ok(value("NULL")));
+        assert_eq!(parse("MASKED WITH VALUE $$zero$$"), Ok(value("$$zero$$")));
+        assert!(parse("MASKED WITH VALUE").is_err());
+        assert!(parse("MASKED WITH 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(
+            parse("MASKED WITH FUNCTION anon.partial_email(email) WHEN country <> 'FR'"),
+            Ok(MaskingRule::MaskedWith {
+                mask: Mask::Function("anon.partial_email(email)"),
+                condition: Some(Condition {
+                    kind: ConditionKind::When,
+                    predicate: "country <> 'FR'"
+                })
+            })
+        );
+        assert_eq!(
+            parse("masked with value NULL  unless  owner = current_user "),
+            Ok(MaskingRule::MaskedWith {
+                mask: Mask::Value("NULL"),
+                condition: Some(Condition {
+                    kind: ConditionKind::Unless,
+                    predicate: "owner = current_user"
+                })
+            })
+        );
+        assert!(parse("MASKED WITH VALUE NULL WHEN").is_err());
+        assert!(parse("MASKED WITH FUNCTION WHEN x").is_err());
+        assert!(parse("NOT MASKED WHEN x").is_err());
+    }
+
+    #[test]
+    fn test_parse_trailing_comments() {
+        assert_eq!(
+            parse("MASKED WITH FUNCTION f() -- note"),
+            Ok(function("f()"))
+        );
+        assert_eq!(
+            parse("MASKED WITH FUNCTION f(-- a\n x) -- b\n -- c"),
+            Ok(function("f(-- a\n x)"))
+        );
+        assert_eq!(parse("MASKED WITH VALUE '--' -- note"), Ok(value("'--'")));
+        assert_eq!(
+            parse("MASKED WITH FUNCTION f() -- note\nWHEN x > 0 -- other note"),
+            Ok(MaskingRule::MaskedWith {
+                mask: Mask::Function("f()"),
+                condition: Some(Condition {
+                    kind: ConditionKind::When,
+                    predicate: "x > 0"
+                })
+            })
+        );
+        assert!(parse("MASKED WITH VALUE NULL WHEN -- x > 0").is_err());
+        assert_eq!(
+            parse("FILTER WITH region = 'EU' -- note"),
+            Ok(MaskingRule::Filter("region = 'EU'"))
+        );
+    }
+
+    #[test]
+    fn test_parse_condition_lexical_rules() {
+        // The WHEN keywords below are not conditions
+        assert_eq!(parse("MASKED WITH VALUE 'WHEN'"), Ok(value("'WHEN'")));
+        assert_eq!(
+            parse("MASKED WITH VALUE $tag$ x WHEN y $tag$"),
+            Ok(value("$tag$ x WHEN y $tag$"))
+        );
+        assert_eq!(
+            parse("MASKED WITH VALUE E'\\' WHEN '"),
+            Ok(value("E'\\' WHEN '"))
+        );
+        assert_eq!(parse("MASKED WITH VALUE \"when\""), Ok(value("\"when\"")));
+        assert_eq!(
+            parse("MASKED WITH FUNCTION anon.f(CASE WHEN a THEN 1 END)"),
+            Ok(function("anon.f(CASE WHEN a THEN 1 END)"))
+        );
+        assert_eq!(
+            parse("MASKED WITH VALUE CASE WHEN a THEN 1 ELSE 2 END"),
+            Ok(value("CASE WHEN a THEN 1 ELSE 2 END"))
+        );
+        assert_eq!(
+            parse("MASKED WITH VALUE NULL /* WHEN */"),
+            Ok(value("NULL /* WHEN */"))
+        );
+        // ... but this one is
+        assert_eq!(
+            parse("MASKED WITH VALUE CASE WHEN a THEN 1 END WHEN b"),
+            Ok(MaskingRule::MaskedWith {
+                mask: Mask::Value("CASE WHEN a THEN 1 END"),
+                condition: Some(Condition {
+                    kind: ConditionKind::When,
+                    predicate: "b"
+                })
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_not_masked() {
+        assert_eq!(parse("NOT MASKED"), Ok(MaskingRule::NotMasked));
+        assert_eq!(parse("NOT    MASKED"), Ok(MaskingRule::NotMasked));
+        assert_eq!(parse(" NoT MaSkED "), Ok(MaskingRule::NotMasked));
+        assert!(parse("NOTMASKED").is_err());
+    }
+
+    #[test]
+    fn test_parse_masked() {
+        let masked = Ok(MaskingRule::Masked(Limits::default()));
+        assert_eq!(parse("MASKED"), masked);
+        assert_eq!(parse("  MaSKeD       "), masked);
+        assert_eq!(parse("MASKED -- comment"), masked);
+        assert!(parse("MAKSED").is_err());
+    }
+
+    #[test]
+    fn test_parse_masked_with_limits() {
+        assert_eq!(
+            parse("MASKED WITH ROW LIMIT 1000"),
+            Ok(MaskingRule::Masked(Limits {
+                row_limit: Some("1000"),
+                statement_timeout: None,
+            }))
+        );
+        assert_eq!(
+            parse("masked with statement timeout '5 min'"),
+            Ok(MaskingRule::Masked(Limits {
+                row_limit: None,
+                statement_timeout: Some("5 min"),
+            }))
+        );
+        assert_eq!(
+            parse("MASKED WITH STATEMENT TIMEOUT 30s AND ROW LIMIT 10 -- comment"),
+            Ok(MaskingRule::Masked(Limits {
+                row_limit: Some("10"),
+                statement_timeout: Some("30s"),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_masked_with_limits_errors() {
+        let error = |message: &str, position| {
+            Err(ParseError {
+                message: message.to_string(),
+                position,
+            })
+        };
+        assert_eq!(
+            parse("MASKED WITH ROW LIMIT"),
+            error("missing value after ROW LIMIT", 22)
+        );
+        assert_eq!(
+            parse("MASKED WITH ROW LIMIT 10 AND ROW LIMIT 20"),
+            error("ROW LIMIT is declared twice", 30)
+        );
+        assert_eq!(
+            parse("MASKED WITH ROW LIMIT 10 AND"),
+            error("missing ROW LIMIT or STATEMENT TIMEOUT after AND", 29)
+        );
+        assert_eq!(
+            parse("MASKED WITH ROW LIMIT 10 STATEMENT TIMEOUT 1s"),
+            error("syntax error at or near \"STATEMENT\"", 26)
+        );
+        assert_eq!(
+            parse("MASKED WITH STATEMENT TIMEOUT '1s"),
+            error("unterminated quoted string", 31)
+        );
+        assert!(parse("MASKED WITH ROW 10").is_err());
+    }
+
+    #[test]
+    fn test_parse_trusted() {
+        assert_eq!(parse("TRUSTED"), Ok(MaskingRule::Trusted));
+        assert_eq!(parse("     trusted "), Ok(MaskingRule::Trusted));
+        assert_eq!(parse("UNTRUSTED"), Ok(MaskingRule::Untrusted));
+        assert_eq!(parse("     untrusted "), Ok(MaskingRule::Untrusted));
+        assert!(parse("TRUSTTED").is_err());
+        assert!(parse("UNTRUSTTED").is_err());
+    }
+
+    #[test]
+    fn test_parse_forbidden() {
+        assert_eq!(parse("FORBIDDEN"), Ok(MaskingRule::Forbidden));
+        assert_eq!(parse("  forbidden -- comment"), Ok(MaskingRule::Forbidden));
+        assert!(parse("FORBIDDEN FUNCTION").is_err());
+    }
+
+    #[test]
+    fn test_parse_tablesample() {
+        assert_eq!(
+            parse("TABLESAMPLE SYSTEM(10)"),
+            Ok(MaskingRule::TableSample("SYSTEM(10)"))
+        );
+        assert_eq!(
+            parse(" tablesample  sySTEM(10)"),
+            Ok(MaskingRule::TableSample("sySTEM(10)"))
+        );
+        assert!(parse("TABLESAMPLE").is_err());
+        assert!(parse("TABLE SAMPLE SYSTEM(10)").is_err());
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            parse("FILTER WITH region = 'EU'"),
+            Ok(MaskingRule::Filter("region = 'EU'"))
+        );
+        assert_eq!(
+            parse(" filter  with id > 1000  "),
+            Ok(MaskingRule::Filter("id > 1000"))
+        );
+        assert!(parse("FILTER WITH").is_err());
+        assert!(parse("FILTER id > 1000").is_err());
+    }
+
+    #[test]
+    fn test_parse_indirect_identifier() {
+        assert_eq!(
+            parse("INDIRECT IDENTIFIER"),
+            Ok(MaskingRule::IndirectIdentifier)
+        );
+        assert_eq!(
+            parse(" QuAsI    idenTIFIER  "),
+            Ok(MaskingRule::IndirectIdentifier)
+        );
+        assert!(parse("IDENTIFIER").is_err());
+        assert!(parse("quasi-identifier").is_err());
+    }
+
+    #[test]
+    fn test_parse_error_positions() {
+        assert_eq!(position(""), 1);
+        assert_eq!(position("maskeeeed"), 1);
+        assert_eq!(position("  maskeeeed"), 3);
+        assert_eq!(position("MASKED WIHT VALUE NULL"), 8);
+        assert_eq!(position("MASKED WITH VALEU NULL"), 13);
+        assert_eq!(position("MASKED WITH VALUE"), 18);
+        assert_eq!(position("MASKED WITH VALUE NULL WHEN "), 29);
+        assert_eq!(position("MASKED WITH VALUE WHEN x"), 19);
+        assert_eq!(position("NOT MASKED TWICE"), 12);
+        assert_eq!(position("NOT MASKED, TWICE"), 11);
+        // positions are counted in characters, not in bytes
+        assert_eq!(position("/* é */ TRUSTED éé"), 17);
+    }
+
+    #[test]
+    fn test_parse_error_message() {
+        assert_eq!(
+            parse("MASKED WIHT VALUE NULL").unwrap_err().to_string(),
+            "syntax error at or near \"WIHT\", expected WITH after MASKED (position 8)"
+        );
+        assert_eq!(
+            parse("maskeeeed").unwrap_err().to_string(),
+            "syntax error at or near \"maskeeeed\" (position 1)"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+///
+/// # Preview
+///
+/// Debugging the transparent dynamic masking usually requires to connect
+/// with a masked role. These functions allow a superuser to see how a query
+/// is rewritten for a given role and which rows this role would get.
+///
+/// The masking policy of the role is the one declared with a security label
+/// on the role (or on a role it belongs to). The session tokens and the
+/// session policy rules of the superuser are ignored, and when
+/// `anon.mask_calling_role` is enabled the previewed role is also the calling
+/// role and the session user, see `calling_roles()`. Otherwise the query is
+/// rewritten just like the hooks do, with the row limit, the aggregate-only
+/// restrictions and the inference guard of the role.
+///
+use crate::cache;
+use crate::compat;
+use crate::error;
+use crate::guc;
+use crate::walker;
+use pgrx::direct_function_call;
+use pgrx::list::old_list::PgList;
+use pgrx::prelude::*;
+use std::ffi::CString;
+
+/// The role previewed by a superuser, see `as_role()`
+static mut PREVIEWED_ROLE: pg_sys::Oid = pg_sys::InvalidOid;
+
+/// Returns the role previewed by a superuser, if any
+///
+pub fn previewed_role() -> Option<pg_sys::Oid> {
+    let roleid = unsafe { PREVIEWED_ROLE };
+    (roleid != pg_sys::InvalidOid).then_some(roleid)
+}
+
+/// Returns the calling role and the session user, which may provide the
+/// masking policy when `anon.mask_calling_role` is enabled
+///
+/// While a role is previewed, it replaces both of them: otherwise the
+/// superuser would be considered as the caller of the previewed queries.
+///
+pub fn calling_roles() -> [pg_sys::Oid; 2] {
+    match previewed_role() {
+        Some(roleid) => [roleid, roleid],
+        None => unsafe { [pg_sys::GetOuterUserId(), pg_sys::GetSessionUserId()] },
+    }
+}
+
+/// Run a function with the privileges of a role
+///
+/// The previous user is restored by the transaction abort, just like
+/// `SECURITY DEFINER` functions do
+///
+fn as_role<R>(roleid: pg_sys::Oid, f: impl FnOnce() -> R) -> R {
+    if !unsafe { pg_sys::superuser() } {
+        error::insufficient_privilege("only a superuser can preview a masked role".to_string())
+            .ereport();
+    }
+    let mut save_userid = pg_sys::InvalidOid;
+    let mut save_sec_context: i32 = 0;
+    unsafe {
+        pg_sys::GetUserIdAndSecContext(&mut save_userid, &mut save_sec_context);
+        pg_sys::SetUserIdAndSecContext(
+            roleid,
+            save_sec_context | pg_sys::SECURITY_LOCAL_USERID_CHANGE as i32,
+        );
+    }
+    let save_previewed_role = unsafe { PREVIEWED_ROLE };
+    unsafe { PREVIEWED_ROLE = roleid };
+    let result = PgTryBuilder::new(std::panic::AssertUnwindSafe(f))
+        .finally(|| unsafe { PREVIEWED_ROLE = save_previewed_role })
+        .execute();
+    unsafe {
+        pg_sys::SetUserIdAndSecContext(save_userid, save_sec_context);
+    }
+    result
+}
+
+/// Parse and analyze a single statement
+///
+/// The post_parse_analyze hook is not called, the query is not masked
+///
+fn analyze(query: &str) -> PgBox<pg_sys::Query> {
+    let query_c_string = CString::new(query).unwrap_or_default();
+    unsafe {
+        let raw_stmts =
+            PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(query_c_string.as_ptr()));
+        if raw_stmts.len() != 1 {
+            error::feature_not_supported("Previewing several statements").ereport();
+        }
+        let pstate = pg_sys::make_parsestate(std::ptr::null_mut());
+        (*pstate).p_sourcetext = query_c_string.as_ptr();
+        let analyzed = pg_sys::transformTopLevelStmt(pstate, raw_stmts.get_ptr(0).unwrap());
+        pg_sys::free_parsestate(pstate);
+        PgBox::from_pg(analyzed)
+    }
+}
+
+/// Returns the query rewritten with the masking policy of a role
+///
+/// The query is returned as is when the role is not masked
+///
+pub fn rewrite_query(query: &str, roleid: pg_sys::Oid) -> String {
+    let query = as_role(roleid, || {
+        let query = analyze(query);
+        if query.commandType == pg_sys::CmdType::CMD_UTILITY {
+            error::feature_not_supported("Previewing a utility statement").ereport();
+        }
+        if let Some(policy) = cache::masking_policy(roleid) {
+            let mut walker = walker::TreeWalker::new(policy);
+            unsafe {
+                walker.rewrite(&query);
+            }
+        }
+        query
+    });
+    unsafe { compat::get_querydef(query.as_ptr()) }.unwrap_or_else(|| {
+        error::feature_not_supported("anon.rewrite_query() with this Postgres version").ereport();
+        unreachable!()
+    })
+}
+
+/// Execute a query with the privileges of a role and returns the rows as
+/// JSON objects
+///
+/// The query is masked by the dynamic masking engine, as if the role had
+/// sent it. The query is executed in read-only mode.
+///
+pub fn simulate(query: &str, roleid: pg_sys::Oid) -> Vec<String> {
+    if !guc::ANON_TRANSPARENT_DYNAMIC_MASKING.get() {
+        error::feature_not_enabled(
+            "Transparent Dynamic Masking",
+            Some("Check the anon.transparent_dynamic_masking parameter".to_string()),
+        )
+        .ereport();
+    }
+    let query_c_string = CString::new(query).unwrap_or_default();
+    as_role(roleid, || unsafe {
+        pg_sys::SPI_connect();
+        if pg_sys::SPI_execute(query_c_string.as_ptr(), true, 0) < 0 {
+            error::internal("SPI_execute failed").ereport();
+        }
+        let tuptable = pg_sys::SPI_tuptable;
+        let mut rows = Vec::new();
+        if !tuptable.is_null() {
+            // The rows are converted into composite values, their record
+            // type must be registered
+            let tupdesc = pg_sys::BlessTupleDesc((*tuptable).tupdesc);
+            for i in 0..pg_sys::SPI_processed as usize {
+                let tuple = *(*tuptable).vals.add(i);
+                let row = pg_sys::heap_copy_tuple_as_datum(tuple, tupdesc);
+                if let Some(json) = direct_function_call::<String>(row_to_json, &[Some(row)]) {
+                    rows.push(json);
+                }
+            }
+        }
+        pg_sys::SPI_finish();
+        rows
+    })
+}
+
+fn row_to_json(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    unsafe { pg_sys::row_to_json(fcinfo) }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fixture;
+    use crate::preview::*;
+
+    fn create_person() -> pg_sys::Oid {
+        fixture::create_table_person();
+        Spi::run(
+            "
+            SECURITY LABEL FOR anon ON TABLE person IS NULL;
+            INSERT INTO person VALUES ('Kyle', 'Reese');
+            ",
+        )
+        .unwrap();
+        fixture::create_masked_role()
+    }
+
+    #[cfg(not(any(feature = "pg13", feature = "pg14")))]
+    #[pg_test]
+    fn test_rewrite_query() {
+        let batman = create_person();
+        let sql = "SELECT lastname FROM person";
+        let rewritten = rewrite_query(sql, batman);
+        assert!(rewritten.contains("NULL::text AS lastname"));
+
+        let unmasked = Spi::get_one::<pg_sys::Oid>("SELECT CURRENT_USER::REGROLE::OID")
+            .unwrap()
+            .unwrap();
+        let rewritten = rewrite_query(sql, unmasked);
+        assert!(!rewritten.contains("NULL::text"));
+    }
+
+    #[cfg(not(any(feature = "pg13", feature = "pg14")))]
+    #[pg_test]
+    fn test_rewrite_query_limits() {
+        let batman = create_person();
+        Spi::run(
+            "
+            SECURITY LABEL FOR anon ON ROLE batman IS 'MASKED WITH ROW LIMIT 10';
+            SET anon.aggregate_only_policies TO 'anon:3';
+            ",
+        )
+        .unwrap();
+        let rewritten = rewrite_query("SELECT count(*) FROM person", batman);
+        assert!(rewritten.contains("count(*) >= 3"));
+        assert!(rewritten.contains("LIMIT '10'::bigint"));
+    }
+
+    #[pg_test(error = "Anon: role is restricted to aggregated queries")]
+    fn test_rewrite_query_aggregate_only() {
+        let batman = create_person();
+        Spi::run("SET anon.aggregate_only_policies TO 'anon:3'").unwrap();
+        rewrite_query("SELECT * FROM person", batman);
+    }
+
+    #[pg_test]
+    fn test_simulate_ignores_session() {
+        create_person();
+        let bruce = fixture::create_unmasked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET anon.mask_calling_role TO TRUE;
+            GRANT USAGE ON SCHEMA public TO bruce;
+            GRANT SELECT ON person TO bruce;
+            ",
+        )
+        .unwrap();
+        // This rule masks the session of the superuser, not the previewed
+        // role
+        Spi::run("INSERT INTO anon.session_policy_rules(policy) VALUES ('anon')").unwrap();
+        Spi::run("SELECT 1").unwrap();
+        assert_eq!(previewed_role(), None);
+        let rows = simulate("SELECT lastname FROM person ORDER BY lastname", bruce);
+        assert_eq!(
+            rows,
+            vec!["{\"lastname\":\"Connor\"}", "{\"lastname\":\"Reese\"}"]
+        );
+        assert_eq!(previewed_role(), None);
+    }
+
+    #[pg_test(error = "Anon: Previewing several statements is not supported")]
+    fn test_rewrite_query_several_statements() {
+        let batman = create_person();
+        rewrite_query("SELECT 1; SELECT 2", batman);
+    }
+
+    #[pg_test]
+    fn test_simulate() {
+        let batman = create_person();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT SELECT ON person TO batman;
+            ",
+        )
+        .unwrap();
+        let rows = simulate("SELECT * FROM person ORDER BY firstname", batman);
+        assert_eq!(
+            rows,
+            vec![
+                "{\"firstname\":\"Kyle\",\"lastname\":null}",
+                "{\"firstname\":\"Sarah\",\"lastname\":null}"
+            ]
+        );
+        // The current user is restored
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT CURRENT_USER = SESSION_USER"),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test(error = "permission denied for table person")]
+    fn test_simulate_privileges() {
+        let batman = create_person();
+        Spi::run("SET anon.transparent_dynamic_masking TO TRUE").unwrap();
+        simulate("SELECT * FROM person", batman);
+    }
+}
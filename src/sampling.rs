@@ -1,8 +1,14 @@
 ///
 /// # Sampling
 ///
+use crate::compat;
+use crate::error;
+use crate::input;
 use crate::masking;
-use crate::re;
+use crate::masking_rule;
+use crate::masking_rule::MaskingRule;
+use c_str_macro::c_str;
+use pgrx::list::old_list::PgList;
 use pgrx::prelude::*;
 
 pub fn get_ratio(relid: pg_sys::Oid, policy: &str) -> Result<&str, masking::Reason> {
@@ -12,7 +18,7 @@ pub fn get_ratio(relid: pg_sys::Oid, policy: &str) -> Result<&str, masking::Reas
 fn get_current_database_ratio(policy: &str) -> Result<&str, masking::Reason> {
     let current_db_id = unsafe { pg_sys::MyDatabaseId };
     let seclabel = masking::rule_on_database(current_db_id, policy)?;
-    let Some(ratio) = re::capture_tablesample(seclabel) else {
+    let Ok(MaskingRule::TableSample(ratio)) = masking_rule::parse(seclabel) else {
         return Err(masking::Reason::InvalidInput);
     };
     Ok(ratio)
@@ -20,12 +26,119 @@ fn get_current_database_ratio(policy: &str) -> Result<&str, masking::Reason> {
 
 pub fn get_table_ratio(relid: pg_sys::Oid, policy: &str) -> Result<&str, masking::Reason> {
     let seclabel = masking::rule_on_table(relid, policy)?;
-    let Some(ratio) = re::capture_tablesample(seclabel) else {
+    let Ok(MaskingRule::TableSample(ratio)) = masking_rule::parse(seclabel) else {
         return Err(masking::Reason::InvalidInput);
     };
     Ok(ratio)
 }
 
+/// Build the TableSampleClause node of a sampling ratio
+///
+/// This is the equivalent of the `transformRangeTableSample()` function which
+/// is private in the Postgres parser. The clause can then be attached to the
+/// Range Table Entry of the masked relation instead of embedding the
+/// `TABLESAMPLE` text inside the masking subquery.
+///
+/// * pstate is the parse state of the masking subquery
+/// * ratio is the sampling ratio, e.g. `BERNOULLI(10) REPEATABLE(42)`
+///
+pub fn tablesample_clause(
+    pstate: *mut pg_sys::ParseState,
+    ratio: &str,
+) -> PgBox<pg_sys::TableSampleClause> {
+    let rts = match input::parse_tablesample(ratio) {
+        Ok(rts) => rts,
+        Err(e) => {
+            error::invalid_tablesample(ratio, Some(e)).ereport();
+            unreachable!()
+        }
+    };
+
+    // The sampling method is a function returning a `tsm_handler`
+    let mut argtypes = [pg_sys::INTERNALOID];
+    let handler = unsafe { pg_sys::LookupFuncName(rts.method, 1, argtypes.as_mut_ptr(), true) };
+    if handler == pg_sys::InvalidOid
+        || unsafe { pg_sys::get_func_rettype(handler) } != pg_sys::TSM_HANDLEROID
+    {
+        error::invalid_tablesample(ratio, Some("the sampling method does not exist".into()))
+            .ereport();
+    }
+
+    // This is the equivalent of the `GetTsmRoutine()` function
+    let tsm = unsafe {
+        PgBox::from_pg(
+            pg_sys::OidFunctionCall1Coll(handler, pg_sys::InvalidOid, pg_sys::Datum::from(0))
+                .cast_mut_ptr::<compat::TsmRoutine>(),
+        )
+    };
+
+    let args = unsafe { PgList::<pg_sys::Node>::from_pg(rts.args) };
+    let parameter_types = unsafe { PgList::<pg_sys::Oid>::from_pg(tsm.parameterTypes) };
+    if args.len() != parameter_types.len() {
+        error::invalid_tablesample(
+            ratio,
+            Some(format!(
+                "the sampling method requires {} argument(s)",
+                parameter_types.len()
+            )),
+        )
+        .ereport();
+    }
+
+    let mut fargs = PgList::<pg_sys::Node>::new();
+    for (arg, argtype) in args.iter_ptr().zip(parameter_types.iter_oid()) {
+        fargs.push(transform_argument(
+            pstate,
+            arg,
+            argtype,
+            c_str!("TABLESAMPLE"),
+        ));
+    }
+
+    let mut clause = unsafe {
+        PgBox::<pg_sys::TableSampleClause>::alloc_node(pg_sys::NodeTag::T_TableSampleClause)
+    };
+    clause.tsmhandler = handler;
+    clause.args = fargs.into_pg();
+    clause.repeatable = std::ptr::null_mut();
+
+    if !rts.repeatable.is_null() {
+        if !tsm.repeatable_across_queries {
+            error::invalid_tablesample(
+                ratio,
+                Some("the sampling method does not support REPEATABLE".into()),
+            )
+            .ereport();
+        }
+        clause.repeatable = transform_argument(
+            pstate,
+            rts.repeatable,
+            pg_sys::FLOAT8OID,
+            c_str!("REPEATABLE"),
+        ) as *mut pg_sys::Expr;
+    }
+
+    unsafe { PgBox::from_pg(clause.into_pg()) }
+}
+
+/// Transform a raw argument of the TABLESAMPLE clause into an expression of
+/// the expected type
+///
+fn transform_argument(
+    pstate: *mut pg_sys::ParseState,
+    arg: *mut pg_sys::Node,
+    argtype: pg_sys::Oid,
+    context: &std::ffi::CStr,
+) -> *mut pg_sys::Node {
+    unsafe {
+        let expr =
+            pg_sys::transformExpr(pstate, arg, pg_sys::ParseExprKind::EXPR_KIND_FROM_FUNCTION);
+        let expr = pg_sys::coerce_to_specific_type(pstate, expr, argtype, context.as_ptr());
+        pg_sys::assign_expr_collations(pstate, expr);
+        expr
+    }
+}
+
 //----------------------------------------------------------------------------
 // Tests
 //----------------------------------------------------------------------------
@@ -78,6 +191,33 @@ mod tests {
         assert!(get_table_ratio(invalid, ANON_DEFAULT_MASKING_POLICY).is_err());
     }
 
+    #[pg_test]
+    fn test_tablesample_clause() {
+        let pstate = unsafe { pg_sys::make_parsestate(std::ptr::null_mut()) };
+        let clause = tablesample_clause(pstate, "BERNOULLI(10)");
+        assert_ne!(clause.tsmhandler, pg_sys::InvalidOid);
+        assert_eq!(
+            1,
+            unsafe { PgList::<pg_sys::Node>::from_pg(clause.args) }.len()
+        );
+        assert!(clause.repeatable.is_null());
+
+        let clause = tablesample_clause(pstate, "SYSTEM(33) REPEATABLE(42)");
+        assert!(!clause.repeatable.is_null());
+    }
+
+    #[pg_test(error = "Anon: `TABLESAMPLE FOO(10)` is not a valid sampling ratio")]
+    fn test_tablesample_clause_unknown_method() {
+        let pstate = unsafe { pg_sys::make_parsestate(std::ptr::null_mut()) };
+        tablesample_clause(pstate, "FOO(10)");
+    }
+
+    #[pg_test(error = "Anon: `TABLESAMPLE SYSTEM(10, 20)` is not a valid sampling ratio")]
+    fn test_tablesample_clause_invalid_arguments() {
+        let pstate = unsafe { pg_sys::make_parsestate(std::ptr::null_mut()) };
+        tablesample_clause(pstate, "SYSTEM(10, 20)");
+    }
+
     #[pg_test]
     fn test_get_table_ratio_none() {
         let relid = fixture::create_table_location();
@@ -0,0 +1,390 @@
+///
+/// # Session
+///
+/// The masking policy of a role is usually declared with a security label on
+/// the role. The same role may connect from different applications that
+/// don't require the same masking policy. In that case, the policy can be
+/// selected by the session:
+///
+/// * a signed token in the `anon.session_token` parameter
+/// * the rules declared in the `anon.session_policy_rules` table, matching
+///   the session user, the `application_name`, the client address or the
+///   database
+///
+/// Both are resolved once, with the first query of the session, and the rules
+/// again when they are modified. See `hooks::current_masking_policy()` for
+/// the precedence rules.
+///
+use crate::compat;
+use crate::error;
+use crate::guc;
+use crate::masking;
+use crate::ANON;
+use c_str_macro::c_str;
+use hmac::{Hmac, KeyInit, Mac};
+use pgrx::pg_sys::ffi::pg_guard_ffi_boundary;
+use pgrx::prelude::*;
+use sha2::Sha256;
+use std::ffi::CStr;
+
+/// The masking policies selected by the session
+///
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SessionPolicies {
+    /// The policy of a valid session token
+    pub token: Option<String>,
+    /// The policy of the first matching rule
+    pub rule: Option<String>,
+}
+
+static mut SESSION_POLICIES: Option<SessionPolicies> = None;
+
+/// The rules table read by the cached policies
+static mut RULES_RELID: pg_sys::Oid = pg_sys::InvalidOid;
+
+/// This counter is incremented each time the cached policies are invalidated
+static mut INVALIDATIONS: u64 = 0;
+
+/// The rules are read with a SQL query, which is also masked...
+static mut RESOLVING: bool = false;
+
+/// Returns the masking policies selected by the session
+///
+/// The policies are not cached until the extension is created, and they are
+/// resolved again when the rules are modified, see `invalidate()`.
+///
+pub fn policies() -> SessionPolicies {
+    unsafe {
+        if let Some(policies) = (*std::ptr::addr_of!(SESSION_POLICIES)).clone() {
+            return policies;
+        }
+        if RESOLVING {
+            return SessionPolicies::default();
+        }
+        RESOLVING = true;
+    }
+
+    let invalidations = unsafe { INVALIDATIONS };
+    let relid = rules_relid();
+    let policies = PgTryBuilder::new(|| SessionPolicies {
+        token: token_policy(),
+        rule: relid.and_then(|_| rule_policy()),
+    })
+    .finally(|| unsafe { RESOLVING = false })
+    .execute();
+
+    if let Some(relid) = relid {
+        unsafe {
+            if invalidations == INVALIDATIONS {
+                SESSION_POLICIES = Some(policies.clone());
+                RULES_RELID = relid;
+            }
+        }
+    }
+    policies
+}
+
+/// Forget the cached policies when the rules table is modified, or when all
+/// the relations are invalidated (relid is InvalidOid)
+///
+pub fn invalidate(relid: pg_sys::Oid) {
+    unsafe {
+        if relid == pg_sys::InvalidOid || relid == RULES_RELID {
+            INVALIDATIONS += 1;
+            SESSION_POLICIES = None;
+        }
+    }
+}
+
+/// Tell all the backends that the rules were modified
+///
+/// The rows of a table have no invalidation message, the signal is a
+/// relcache invalidation of the rules table, sent by a trigger when the
+/// transaction is committed.
+///
+pub fn invalidate_rules() {
+    if let Some(relid) = rules_relid() {
+        unsafe { pg_guard_ffi_boundary(|| compat::CacheInvalidateRelcacheByRelid(relid)) }
+    }
+}
+
+/// Returns the policy of the session token, if the token is valid
+///
+/// The token can only be set when the connection is opened, see
+/// `anon.session_token`. An invalid or expired token is ignored with a
+/// warning.
+///
+fn token_policy() -> Option<String> {
+    let token = guc::ANON_SESSION_TOKEN.get()?.to_str().ok()?.to_string();
+    let secret = guc::ANON_SESSION_TOKEN_SECRET
+        .get()?
+        .to_str()
+        .ok()?
+        .to_string();
+    if token.is_empty() || secret.is_empty() {
+        return None;
+    }
+
+    let now = unix_time(unsafe { pg_sys::GetCurrentTimestamp() });
+    let Some(policy) = verify(&token, &session_user_name(), &secret, now) else {
+        error::invalid_parameter_value("anon.session_token", "***").warning();
+        return None;
+    };
+    registered_policy(policy)
+}
+
+/// Returns the policy of a valid token if it is declared in
+/// `anon.masking_policies`
+///
+/// A token can be signed for any policy name, but an unknown policy has no
+/// masking rules and the role would not be masked at all.
+///
+fn registered_policy(policy: String) -> Option<String> {
+    if !masking::list_masking_policies().contains(&policy.as_str()) {
+        error::invalid_parameter_value("anon.session_token", &policy).warning();
+        return None;
+    }
+    Some(policy)
+}
+
+/// Returns the policy of a token if its signature is valid and if it has not
+/// expired
+///
+/// A token is `policy:expiry:signature` where the expiry is a Unix time and
+/// the signature is the HMAC-SHA256 of `policy:username:expiry` with the
+/// `anon.session_token_secret` key.
+///
+fn verify(token: &str, username: &str, secret: &str, now: i64) -> Option<String> {
+    let (payload, signature) = token.rsplit_once(':')?;
+    let (policy, expiry) = payload.rsplit_once(':')?;
+    let expiry = expiry.parse::<i64>().ok()?;
+    if !constant_time_eq(signature, &sign(policy, username, expiry, secret)) || expiry <= now {
+        return None;
+    }
+    Some(policy.to_string())
+}
+
+/// Returns the oid of the rules table
+///
+/// The rules are declared in the extension, which may not be created in
+/// this database
+///
+fn rules_relid() -> Option<pg_sys::Oid> {
+    let anon_nsp = unsafe { pg_sys::get_namespace_oid(ANON.as_ptr(), true) };
+    if anon_nsp == pg_sys::InvalidOid {
+        return None;
+    }
+    let relid =
+        unsafe { pg_sys::get_relname_relid(c_str!("session_policy_rules").as_ptr(), anon_nsp) };
+    (relid != pg_sys::InvalidOid).then_some(relid)
+}
+
+/// Returns the policy of the first session policy rule matching the session
+///
+fn rule_policy() -> Option<String> {
+    Spi::get_one::<String>("SELECT anon.match_session_policy_rules()")
+        .ok()
+        .flatten()
+}
+
+/// Returns a session token selecting a masking policy for a user until a
+/// given time
+///
+pub fn token(policy: &str, username: &str, expires_at: pg_sys::TimestampTz) -> String {
+    let secret = guc::ANON_SESSION_TOKEN_SECRET
+        .get()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    if secret.is_empty() {
+        error::invalid_parameter_value("anon.session_token_secret", "").ereport();
+    }
+    let expiry = unix_time(expires_at);
+    format!(
+        "{policy}:{expiry}:{}",
+        sign(policy, username, expiry, &secret)
+    )
+}
+
+/// Returns the signature of a session token
+///
+fn sign(policy: &str, username: &str, expiry: i64, secret: &str) -> String {
+    hmac_sha256(
+        secret.as_bytes(),
+        format!("{policy}:{username}:{expiry}").as_bytes(),
+    )
+}
+
+/// Returns the hexadecimal HMAC-SHA256 of a message
+///
+fn hmac_sha256(key: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(message);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Converts a Postgres timestamp into a Unix time, in seconds
+///
+fn unix_time(timestamp: pg_sys::TimestampTz) -> i64 {
+    let epoch_offset = (pg_sys::POSTGRES_EPOCH_JDATE - pg_sys::UNIX_EPOCH_JDATE) as i64
+        * pg_sys::SECS_PER_DAY as i64;
+    timestamp.div_euclid(1_000_000) + epoch_offset
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn session_user_name() -> String {
+    unsafe {
+        let name = pg_sys::GetUserNameFromId(pg_sys::GetSessionUserId(), false);
+        CStr::from_ptr(name).to_string_lossy().to_string()
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::session::*;
+
+    #[pg_test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[pg_test]
+    fn test_sign() {
+        assert_ne!(
+            sign("analytics", "alice", 0, "secret"),
+            sign("analytics", "bob", 0, "secret")
+        );
+        assert_ne!(
+            sign("analytics", "alice", 0, "secret"),
+            sign("anon", "alice", 0, "secret")
+        );
+        assert_ne!(
+            sign("analytics", "alice", 0, "secret"),
+            sign("analytics", "alice", 1, "secret")
+        );
+    }
+
+    #[pg_test]
+    fn test_unix_time() {
+        assert_eq!(unix_time(0), 946684800);
+        assert_eq!(unix_time(-1), 946684799);
+    }
+
+    #[pg_test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "abcd"));
+    }
+
+    #[pg_test(error = "Anon: `` is not a valid value for anon.session_token_secret")]
+    fn test_token_without_secret() {
+        token("analytics", "alice", 0);
+    }
+
+    #[pg_test]
+    fn test_token() {
+        let signature = sign("analytics", "alice", 946688400, "secret");
+        Spi::run("SET anon.session_token_secret TO 'secret'").unwrap();
+        // 2000-01-01 01:00:00 UTC
+        assert_eq!(
+            token("analytics", "alice", 3_600_000_000),
+            format!("analytics:946688400:{signature}")
+        );
+    }
+
+    #[pg_test]
+    fn test_verify() {
+        let token = format!(
+            "analytics:100:{}",
+            sign("analytics", "alice", 100, "secret")
+        );
+        assert_eq!(
+            verify(&token, "alice", "secret", 99),
+            Some("analytics".to_string())
+        );
+        // Expired
+        assert_eq!(verify(&token, "alice", "secret", 100), None);
+        // Another user, another secret
+        assert_eq!(verify(&token, "bob", "secret", 99), None);
+        assert_eq!(verify(&token, "alice", "public", 99), None);
+        // The policy and the expiry are signed
+        let forged = token.replacen("analytics", "anon", 1);
+        assert_eq!(verify(&forged, "alice", "secret", 99), None);
+        let forged = token.replacen(":100:", ":200:", 1);
+        assert_eq!(verify(&forged, "alice", "secret", 150), None);
+        // Malformed
+        assert_eq!(verify("analytics", "alice", "secret", 99), None);
+        assert_eq!(verify("analytics:abc:def", "alice", "secret", 99), None);
+    }
+
+    #[pg_test]
+    fn test_rule_policy() {
+        assert_eq!(rule_policy(), None);
+        Spi::run(
+            "
+            INSERT INTO anon.session_policy_rules(policy, application_name)
+            VALUES ('devtests', 'nobody'), ('analytics', '%');
+        ",
+        )
+        .unwrap();
+        assert_eq!(rule_policy(), Some("analytics".to_string()));
+    }
+
+    #[pg_test]
+    fn test_registered_policy() {
+        assert_eq!(
+            registered_policy("anon".to_string()),
+            Some("anon".to_string())
+        );
+        assert_eq!(registered_policy("billing".to_string()), None);
+        Spi::run("SET anon.masking_policies TO 'billing'").unwrap();
+        assert_eq!(
+            registered_policy("billing".to_string()),
+            Some("billing".to_string())
+        );
+    }
+
+    #[pg_test]
+    fn test_policies_invalidated() {
+        invalidate(pg_sys::InvalidOid);
+        assert_eq!(policies().rule, None);
+        Spi::run(
+            "
+            INSERT INTO anon.session_policy_rules(policy, application_name)
+            VALUES ('analytics', '%');
+        ",
+        )
+        .unwrap();
+        // The invalidation message is processed at the end of the command
+        Spi::run("SELECT 1").unwrap();
+        assert_eq!(policies().rule, Some("analytics".to_string()));
+    }
+}
@@ -2,6 +2,7 @@
 /// # Static Masking
 ///
 use crate::error;
+use crate::filtering;
 use crate::guc;
 use crate::log;
 use crate::masking;
@@ -87,6 +88,14 @@ pub fn anonymize_column(relid: pg_sys::Oid, colname: String, policy: String) ->
         );
     }
 
+    // Same thing for the filter rules
+    if filtering::get_filter(relid, &policy).is_ok() {
+        notice!(
+            "The FILTER rule will be ignored.
+            Only anonymize_table() and anonymize_database() can apply filter rules"
+        );
+    }
+
     let tablename = utils::get_relation_qualified_name(relid)?;
 
     let Some(assign) = column_assignment(relid, colname.clone(), policy) else {
@@ -148,6 +157,16 @@ pub fn anonymize_table(relid: pg_sys::Oid, policy: String) -> Option<bool> {
             DROP TABLE {swap_table};
         "
         )
+    } else if let Ok(predicate) = filtering::get_filter(relid, &p) {
+        // Remove the rows that the masked roles are not allowed to see
+        // and then mask the remaining ones, if needed
+        let delete = format!("DELETE FROM {tablename} WHERE ({predicate}) IS NOT TRUE;");
+        match table_assignments(relid, policy) {
+            Some(masking_assignments) => {
+                format!("{delete} UPDATE {tablename} SET {masking_assignments};")
+            }
+            None => delete,
+        }
     } else {
         // For compatibility with version 1, instead of returning `Some(false)`
         // we return None/NULL when no rule is found for the table
@@ -232,6 +251,28 @@ mod tests {
         );
     }
 
+    #[pg_test]
+    fn test_anonymize_column_conditional() {
+        let anon = ANON_DEFAULT_MASKING_POLICY.to_string();
+        let relid = fixture::create_table_person();
+        Spi::run(
+            "
+            INSERT INTO person VALUES ('John','Connor');
+            SECURITY LABEL FOR anon ON COLUMN person.firstname
+              IS 'MASKED WITH VALUE ''xxx'' WHEN firstname = ''John''';
+        ",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(true),
+            anonymize_column(relid, "firstname".to_string(), anon)
+        );
+        let firstnames = Spi::get_one::<String>(
+            "SELECT string_agg(firstname, ',' ORDER BY firstname) FROM person",
+        );
+        assert_eq!(firstnames, Ok(Some("Sarah,xxx".to_string())));
+    }
+
     #[pg_test(error = "Anon: Static Masking is not enabled")]
     fn test_anonymize_column_not_enabled() {
         let anon = ANON_DEFAULT_MASKING_POLICY.to_string();
@@ -283,6 +324,24 @@ mod tests {
         assert_eq!(Some(true), anonymize_table(relid, anon.clone()));
     }
 
+    #[pg_test]
+    fn test_anonymize_table_filter() {
+        let anon = ANON_DEFAULT_MASKING_POLICY.to_string();
+        let relid = fixture::create_table_person();
+        Spi::run(
+            "
+            INSERT INTO person VALUES ('John','Connor');
+            SECURITY LABEL FOR anon ON TABLE person IS 'FILTER WITH firstname = ''John''';
+        ",
+        )
+        .unwrap();
+        assert_eq!(Some(true), anonymize_table(relid, anon));
+        let firstname = Spi::get_one::<String>("SELECT string_agg(firstname, ',') FROM person");
+        assert_eq!(firstname, Ok(Some("John".to_string())));
+        let lastname = Spi::get_one::<String>("SELECT lastname FROM person");
+        assert_eq!(lastname, Ok(None));
+    }
+
     #[pg_test(error = "Anon: Static Masking is not enabled")]
     fn test_anonymize_table_not_enabled() {
         let anon = ANON_DEFAULT_MASKING_POLICY.to_string();
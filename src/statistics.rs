@@ -0,0 +1,99 @@
+///
+/// # Statistics
+///
+/// The statistics collected by ANALYZE contain authentic values of the
+/// columns: the most common values, the histogram bounds, etc.
+///
+/// The masked roles are not allowed to read the statistics catalogs and the
+/// statistics views only show the relations that have no masking rule.
+///
+use crate::compat;
+use crate::guc;
+use c_str_macro::c_str;
+use pgrx::prelude::*;
+
+/// Returns true if the relation is a catalog containing statistics
+///
+pub fn is_statistics_catalog(relid: pg_sys::Oid) -> bool {
+    relid == pg_sys::StatisticRelationId || relid == compat::StatisticExtDataRelationId
+}
+
+/// Returns true if the relation is a view showing statistics
+///
+pub fn is_statistics_view(relid: pg_sys::Oid) -> bool {
+    if unsafe { pg_sys::get_rel_relkind(relid) } != pg_sys::RELKIND_VIEW as std::os::raw::c_char {
+        return false;
+    }
+    // These views are based on `pg_statistic` and `pg_statistic_ext_data`,
+    // they all have a `schemaname` and a `tablename` column
+    let views = [
+        c_str!("pg_stats"),
+        c_str!("pg_stats_ext"),
+        c_str!("pg_stats_ext_exprs"),
+    ];
+    let pg_catalog = pg_sys::Oid::from(pg_sys::PG_CATALOG_NAMESPACE);
+    views
+        .iter()
+        .any(|view| unsafe { pg_sys::get_relname_relid(view.as_ptr(), pg_catalog) } == relid)
+}
+
+/// Returns the predicate applied to a statistics view
+///
+/// The statistics of a relation are hidden if the relation has at least one
+/// security label in the masking policy. With privacy by default, all the
+/// statistics are hidden.
+///
+pub fn predicate(policy: &str) -> String {
+    if guc::ANON_PRIVACY_BY_DEFAULT.get() {
+        return "FALSE".to_string();
+    }
+    format!(
+        "NOT EXISTS (
+            SELECT FROM pg_catalog.pg_seclabel sl
+            JOIN pg_catalog.pg_class c ON c.oid = sl.objoid
+            JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+            WHERE sl.classoid = 'pg_catalog.pg_class'::pg_catalog.regclass
+            AND sl.provider = {}
+            AND n.nspname = schemaname
+            AND c.relname = tablename
+        )",
+        quote_literal(policy)
+    )
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::statistics::*;
+
+    fn relid(name: &str) -> pg_sys::Oid {
+        Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{name}'::REGCLASS::OID"))
+            .unwrap()
+            .expect("should be an OID")
+    }
+
+    #[pg_test]
+    fn test_is_statistics_catalog() {
+        assert!(is_statistics_catalog(relid("pg_catalog.pg_statistic")));
+        assert!(is_statistics_catalog(relid(
+            "pg_catalog.pg_statistic_ext_data"
+        )));
+        assert!(!is_statistics_catalog(relid("pg_catalog.pg_class")));
+    }
+
+    #[pg_test]
+    fn test_is_statistics_view() {
+        assert!(is_statistics_view(relid("pg_catalog.pg_stats")));
+        assert!(is_statistics_view(relid("pg_catalog.pg_stats_ext")));
+        assert!(!is_statistics_view(relid("pg_catalog.pg_tables")));
+        assert!(!is_statistics_view(relid("pg_catalog.pg_statistic")));
+    }
+}
@@ -0,0 +1,243 @@
+///
+/// # Statistics
+///
+/// The activity of the dynamic masking engine is counted in shared memory,
+/// per database, per masking policy and per masked relation. The counters
+/// are exposed by the `anon.stat_masking` view, just like the
+/// `pg_stat_statements` view.
+///
+/// The shared memory can only be allocated when the extension is loaded with
+/// `shared_preload_libraries`, otherwise the statistics are disabled.
+///
+use crate::error;
+use heapless::FnvIndexMap;
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, PgLwLock, PgSharedMemoryInitialization};
+use std::panic::UnwindSafe;
+use std::time::Instant;
+
+/// The maximum number of entries, it must be a power of 2. When the area is
+/// full, the new entries are not counted.
+const MAX_ENTRIES: usize = 1024;
+
+const POLICY_LEN: usize = pg_sys::NAMEDATALEN as usize;
+
+/// The relid is `InvalidOid` for the counters of the whole policy
+///
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    dbid: u32,
+    relid: u32,
+    policy: [u8; POLICY_LEN],
+}
+
+#[derive(Copy, Clone, Default)]
+struct Counters {
+    queries: i64,
+    copies: i64,
+    rejected: i64,
+    /// in milliseconds
+    total_time: f64,
+}
+
+static STATS: PgLwLock<FnvIndexMap<Key, Counters, MAX_ENTRIES>> =
+    PgLwLock::new(c"anon_stat_masking");
+
+/// The shared memory is allocated by the postmaster, this flag is inherited
+/// by the backends
+static mut ENABLED: bool = false;
+
+/// The kind of statement that is counted
+///
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Counter {
+    /// A query rewritten by `rewrite_walker()`
+    Query,
+    /// A `COPY` statement rewritten by `pa_rewrite_utility()`
+    Copy,
+    /// A statement rejected by the masking engine
+    Rejected,
+}
+
+/// Request the shared memory, this is called by `_PG_init()`
+///
+pub fn init() {
+    unsafe {
+        if !pg_sys::process_shared_preload_libraries_in_progress {
+            return;
+        }
+        pg_shmem_init!(STATS);
+        ENABLED = true;
+    }
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+fn key(relid: pg_sys::Oid, policy: &str) -> Key {
+    let mut name = [0u8; POLICY_LEN];
+    let bytes = policy.as_bytes();
+    let len = bytes.len().min(POLICY_LEN - 1);
+    name[..len].copy_from_slice(&bytes[..len]);
+    Key {
+        dbid: unsafe { pg_sys::MyDatabaseId }.to_u32(),
+        relid: relid.to_u32(),
+        policy: name,
+    }
+}
+
+/// Run a rewrite function and count the statement
+///
+/// The statement is counted for the policy and for each masked relation it
+/// reads. A statement that doesn't read any masked relation is not counted.
+/// When the function raises an error, the statement is counted as rejected
+/// and the error is raised again.
+///
+/// * policy is the masking policy applied to the statement
+/// * counter is the kind of statement
+/// * rewrite is the rewrite function
+/// * relids returns the masked relations from the result of the function
+///
+pub fn track<R, F, G>(policy: &str, counter: Counter, rewrite: F, relids: G) -> R
+where
+    F: FnOnce() -> R + UnwindSafe,
+    G: FnOnce(&R) -> Vec<pg_sys::Oid>,
+{
+    if !is_enabled() {
+        return rewrite();
+    }
+    let start = Instant::now();
+    let result = PgTryBuilder::new(rewrite)
+        .catch_others(|e| {
+            add(policy, &[], Counter::Rejected, start);
+            e.rethrow()
+        })
+        .execute();
+    let relids = relids(&result);
+    if !relids.is_empty() {
+        add(policy, &relids, counter, start);
+    }
+    result
+}
+
+fn add(policy: &str, relids: &[pg_sys::Oid], counter: Counter, start: Instant) {
+    let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+    let mut stats = STATS.exclusive();
+    for relid in std::iter::once(pg_sys::InvalidOid).chain(relids.iter().copied()) {
+        let key = key(relid, policy);
+        if !stats.contains_key(&key) && stats.insert(key, Counters::default()).is_err() {
+            continue;
+        }
+        let counters = stats.get_mut(&key).unwrap();
+        match counter {
+            Counter::Query => counters.queries += 1,
+            Counter::Copy => counters.copies += 1,
+            Counter::Rejected => counters.rejected += 1,
+        }
+        counters.total_time += elapsed;
+    }
+}
+
+fn check_enabled() {
+    if !is_enabled() {
+        error::feature_not_enabled(
+            "anon.stat_masking",
+            Some("The extension must be loaded with shared_preload_libraries".to_string()),
+        )
+        .ereport();
+    }
+}
+
+/// Returns the content of the `anon.stat_masking` view
+///
+#[allow(clippy::type_complexity)]
+pub fn entries() -> Vec<(pg_sys::Oid, String, Option<pg_sys::Oid>, i64, i64, i64, f64)> {
+    check_enabled();
+    STATS
+        .share()
+        .iter()
+        .map(|(key, counters)| {
+            let len = key
+                .policy
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(POLICY_LEN);
+            let relid = pg_sys::Oid::from(key.relid);
+            (
+                pg_sys::Oid::from(key.dbid),
+                String::from_utf8_lossy(&key.policy[..len]).to_string(),
+                (relid != pg_sys::InvalidOid).then_some(relid),
+                counters.queries,
+                counters.copies,
+                counters.rejected,
+                counters.total_time,
+            )
+        })
+        .collect()
+}
+
+/// Discard all the statistics
+///
+pub fn reset() {
+    check_enabled();
+    STATS.exclusive().clear();
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fixture;
+    use crate::stats::*;
+
+    // The statistics are shared by the tests running in parallel, each test
+    // only checks the counters of its own relations
+
+    fn counters(relid: pg_sys::Oid) -> Option<(i64, i64, i64)> {
+        entries()
+            .into_iter()
+            .find(|e| e.2 == Some(relid))
+            .map(|e| (e.3, e.4, e.5))
+    }
+
+    #[pg_test]
+    fn test_key() {
+        let k = key(pg_sys::Oid::from(42), "anon");
+        assert_eq!(k.relid, 42);
+        assert_eq!(&k.policy[..5], b"anon\0");
+        let k = key(pg_sys::InvalidOid, &"x".repeat(100));
+        assert_eq!(k.policy[POLICY_LEN - 1], 0);
+    }
+
+    #[pg_test]
+    fn test_track() {
+        let relid = fixture::create_table_person();
+        assert!(is_enabled());
+        assert_eq!(counters(relid), None);
+
+        track("anon", Counter::Query, || (), |_| vec![relid]);
+        track("anon", Counter::Query, || (), |_| vec![relid]);
+        track("anon", Counter::Copy, || (), |_| vec![relid]);
+        // nothing was masked
+        track("anon", Counter::Copy, || (), |_| vec![]);
+        assert_eq!(counters(relid), Some((2, 1, 0)));
+
+        // The other tests don't reset the statistics
+        reset();
+        assert_eq!(counters(relid), None);
+    }
+
+    #[pg_test(error = "Anon: role is masked")]
+    fn test_track_rejected() {
+        track(
+            "anon",
+            Counter::Query,
+            || error::insufficient_privilege("role is masked".to_string()).ereport(),
+            |_| vec![],
+        );
+    }
+}
@@ -0,0 +1,211 @@
+///
+/// # Unmasking
+///
+/// A superuser may reveal the authentic data of a table to a masked role for
+/// a limited period of time with `anon.unmask_temporarily()`. The exemptions
+/// are stored in the `anon.unmasking` table, see `sql/unmasking.sql`.
+///
+/// The masked relations are not replaced by their masking subquery while the
+/// exemption is valid. Instead a guard is added to the relation so that a
+/// cached plan can't read the authentic data after the expiry.
+///
+/// Each execution of a statement reading an unmasked relation is recorded in
+/// the `anon.unmasking_audit` table by the guard and copied in the server
+/// log, see `audit::report_unmasked_access()`. The masked role can't roll
+/// back the copy.
+///
+use crate::audit;
+use crate::cache;
+use crate::error;
+use crate::input;
+use crate::utils;
+use pgrx::prelude::*;
+use pgrx::void_mut_ptr;
+
+/// A valid exemption
+///
+#[derive(Clone, Debug)]
+pub struct Exemption {
+    pub expires_at: String,
+    pub reason: String,
+}
+
+/// Reads the valid exemption of a role on a relation in the `anon.unmasking`
+/// table, see `cache::unmasking_exemption()`
+///
+pub fn exemption(roleid: pg_sys::Oid, relid: pg_sys::Oid) -> Option<Exemption> {
+    match Spi::get_two_with_args::<String, String>(
+        "SELECT expires_at::TEXT, reason FROM anon.get_unmasking($1, $2)",
+        &[roleid.into(), relid.into()],
+    ) {
+        Ok((Some(expires_at), Some(reason))) => Some(Exemption { expires_at, reason }),
+        _ => None,
+    }
+}
+
+/// Returns true if a relation is temporarily unmasked for a role
+///
+pub fn is_unmasked(roleid: pg_sys::Oid, relid: pg_sys::Oid) -> bool {
+    cache::unmasking_exemption(roleid, relid).is_some()
+}
+
+/// The guard of an unmasked relation, see `unmask_relation()`
+///
+/// Record the access in the audit table, or raise an error when the
+/// exemption has expired since the statement was planned
+///
+/// The guard only checks the exemption of the current user, otherwise any
+/// role could forge the audit records of another role or find out whether
+/// it has a valid exemption
+///
+pub fn check_unmasking(roleid: pg_sys::Oid, relid: pg_sys::Oid) -> bool {
+    if roleid != unsafe { pg_sys::GetUserId() } {
+        error::insufficient_privilege(
+            "the temporary unmasking can only be checked for the current user".to_string(),
+        )
+        .ereport();
+    }
+    let Some(exemption) = cache::unmasking_exemption(roleid, relid) else {
+        let relname = utils::get_relation_qualified_name(relid).unwrap_or_default();
+        error::unmasking_expired(&relname).ereport();
+        unreachable!()
+    };
+    audit::report_unmasked_access(roleid, relid, &exemption);
+    true
+}
+
+/// Reveal the authentic data of a relation to the current user
+///
+/// The relation is guarded by `anon.check_unmasking()`. The guard is a
+/// security qual wrapped in a scalar sublink so that the planner evaluates
+/// it once for each execution. The query must be flagged with `hasSubLinks`.
+///
+pub unsafe fn unmask_relation(rte: *mut pg_sys::RangeTblEntry) {
+    let roleid = pg_sys::GetUserId();
+    let relid = (*rte).relid;
+
+    let guard = format!(
+        "(SELECT anon.check_unmasking({}::pg_catalog.oid, {}::pg_catalog.oid))",
+        roleid.to_u32(),
+        relid.to_u32()
+    );
+    let raw_expr = match input::parse_expression(&guard) {
+        Ok(raw_expr) => raw_expr,
+        Err(e) => {
+            error::internal(&e).ereport();
+            unreachable!()
+        }
+    };
+    let pstate = pg_sys::make_parsestate(std::ptr::null_mut());
+    let qual = pg_sys::transformExpr(
+        pstate,
+        raw_expr.as_ptr(),
+        pg_sys::ParseExprKind::EXPR_KIND_WHERE,
+    );
+    pg_sys::assign_expr_collations(pstate, qual);
+    pg_sys::free_parsestate(pstate);
+
+    (*rte).securityQuals = pg_sys::lappend((*rte).securityQuals, qual as void_mut_ptr);
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fixture;
+    use crate::unmasking::*;
+
+    #[pg_test]
+    fn test_is_unmasked() {
+        let relid = fixture::create_table_person();
+        let batman = fixture::create_masked_role();
+        assert!(!is_unmasked(batman, relid));
+        Spi::run("SELECT anon.unmask_temporarily('batman', 'person', '1 hour', 'incident #42')")
+            .unwrap();
+        assert!(is_unmasked(batman, relid));
+        assert!(!is_unmasked(batman, fixture::create_table_call()));
+
+        // The exemption is ignored once expired
+        Spi::run("UPDATE anon.unmasking SET expires_at = now() - '1 second'::INTERVAL").unwrap();
+        assert!(!is_unmasked(batman, relid));
+    }
+
+    #[pg_test]
+    fn test_is_unmasked_member() {
+        let relid = fixture::create_table_person();
+        fixture::create_masked_role();
+        let bruce = fixture::create_unmasked_role();
+        Spi::run("GRANT batman TO bruce").unwrap();
+        Spi::run("SELECT anon.unmask_temporarily('batman', 'person', '1 hour', 'incident #42')")
+            .unwrap();
+        assert!(is_unmasked(bruce, relid));
+    }
+
+    #[pg_test]
+    fn test_exemption_another_session_user() {
+        let relid = fixture::create_table_person();
+        let batman = fixture::create_masked_role();
+        Spi::run("SELECT anon.unmask_temporarily('batman', 'person', '1 hour', 'incident #42')")
+            .unwrap();
+        assert!(exemption(batman, relid).is_some());
+
+        // The exemptions of the other roles are not visible
+        Spi::run("CREATE ROLE robin; SET SESSION AUTHORIZATION robin").unwrap();
+        assert!(exemption(batman, relid).is_none());
+        Spi::run("RESET SESSION AUTHORIZATION").unwrap();
+    }
+
+    #[pg_test(error = "A reason is required to unmask a table")]
+    fn test_unmask_temporarily_without_reason() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run("SELECT anon.unmask_temporarily('batman', 'person', '1 hour', ' ')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_unmask_relation() {
+        let relid = fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "SELECT anon.unmask_temporarily(current_user::REGROLE, 'person', '1 hour', 'test')",
+        )
+        .unwrap();
+        let mut rte =
+            unsafe { PgBox::<pg_sys::RangeTblEntry>::alloc_node(pg_sys::NodeTag::T_RangeTblEntry) };
+        rte.rtekind = pg_sys::RTEKind::RTE_RELATION;
+        rte.relid = relid;
+        unsafe { unmask_relation(rte.as_ptr()) };
+        assert!(!rte.securityQuals.is_null());
+    }
+
+    #[pg_test]
+    fn test_check_unmasking() {
+        let relid = fixture::create_table_person();
+        Spi::run(
+            "SELECT anon.unmask_temporarily(current_user::REGROLE, 'person', '1 hour', 'test')",
+        )
+        .unwrap();
+        assert!(check_unmasking(unsafe { pg_sys::GetUserId() }, relid));
+        let accesses =
+            Spi::get_one::<i64>("SELECT count(*) FROM anon.unmasking_audit WHERE event = 'ACCESS'");
+        assert_eq!(accesses, Ok(Some(1)));
+    }
+
+    #[pg_test(error = "Anon: the temporary unmasking of public.person has expired")]
+    fn test_check_unmasking_expired() {
+        let relid = fixture::create_table_person();
+        check_unmasking(unsafe { pg_sys::GetUserId() }, relid);
+    }
+
+    #[pg_test(error = "Anon: the temporary unmasking can only be checked for the current user")]
+    fn test_check_unmasking_another_role() {
+        let relid = fixture::create_table_person();
+        let batman = fixture::create_masked_role();
+        Spi::run("SELECT anon.unmask_temporarily('batman', 'person', '1 hour', 'incident #42')")
+            .unwrap();
+        check_unmasking(batman, relid);
+    }
+}
@@ -4,6 +4,7 @@
 use crate::compat;
 use crate::error;
 use crate::macros;
+use pgrx::pg_sys::panic::CaughtError;
 use pgrx::prelude::*;
 use std::ffi::c_char;
 use std::ffi::CStr;
@@ -161,6 +162,26 @@ pub fn get_relation_qualified_name(relid: pg_sys::Oid) -> Option<String> {
     ))
 }
 
+/// Return the qualified name of a function
+///
+pub fn get_function_qualified_name(funcid: pg_sys::Oid) -> Option<String> {
+    let funcname_ptr = unsafe { pg_sys::get_func_name(funcid) };
+    if funcname_ptr.is_null() {
+        return None;
+    }
+
+    let namespace_ptr = unsafe { pg_sys::get_namespace_name(pg_sys::get_func_namespace(funcid)) };
+    if namespace_ptr.is_null() {
+        return None;
+    }
+
+    Some(format!(
+        "{}.{}",
+        quote_identifier(namespace_ptr),
+        quote_identifier(funcname_ptr)
+    ))
+}
+
 /// Check if a relation belongs in the `anon` namespace
 ///
 pub fn is_anon_relation_oid(relid: pg_sys::Oid) -> bool {
@@ -168,6 +189,136 @@ pub fn is_anon_relation_oid(relid: pg_sys::Oid) -> bool {
     unsafe { pg_sys::get_rel_namespace(relid) == pg_sys::get_namespace_oid(ANON.as_ptr(), false) }
 }
 
+/// Returns all the roles that a given role belongs to, directly or indirectly,
+/// grouped by membership depth.
+///
+/// The first group contains only the role itself, the second group contains
+/// the roles directly granted to it, the third group contains the roles
+/// granted to those roles, etc.
+///
+/// This is similar to the `roles_is_member_of()` function from acl.c, which
+/// is not exported, with the `ROLERECURSE_PRIVS` mode: only the memberships
+/// that inherit the privileges of the granted role are followed. A role that
+/// was granted a masked role `WITH INHERIT FALSE`, only to `SET ROLE` to it,
+/// is not masked until it does. Since PG16, this also excludes the ADMIN-only
+/// grant of a role to the CREATEROLE user that created it.
+///
+pub fn roles_is_member_of(roleid: pg_sys::Oid) -> Vec<Vec<pg_sys::Oid>> {
+    let mut visited = vec![roleid];
+    let mut levels = vec![];
+    let mut current = vec![roleid];
+
+    while !current.is_empty() {
+        let mut next = vec![];
+        for memberid in &current {
+            for grantedid in get_granted_roles(*memberid) {
+                // A role may be granted through multiple paths
+                if !visited.contains(&grantedid) {
+                    visited.push(grantedid);
+                    next.push(grantedid);
+                }
+            }
+        }
+        levels.push(current);
+        current = next;
+    }
+    levels
+}
+
+/// Returns the roles directly granted to a given role, whose privileges are
+/// inherited, see `compat::membership_is_inherited()`
+///
+fn get_granted_roles(memberid: pg_sys::Oid) -> Vec<pg_sys::Oid> {
+    let cache_id = pg_sys::SysCacheIdentifier::AUTHMEMMEMROLE;
+
+    // Read the pg_auth_members entries of the role from the Postgres cache
+    let catlist = unsafe {
+        PgBox::from_pg(pg_sys::SearchSysCacheList(
+            cache_id.try_into().unwrap(),
+            1,
+            pg_sys::Datum::from(memberid),
+            pg_sys::Datum::from(0),
+            pg_sys::Datum::from(0),
+        ))
+    };
+
+    // transform the members array into a proper rust slice
+    let members = unsafe { catlist.members.as_slice(catlist.n_members as usize) };
+
+    let mut result = vec![];
+    for m in members {
+        let mut catctup = unsafe { **m as pg_sys::CatCTup };
+        let tuple = &mut catctup.tuple as *mut pg_sys::HeapTupleData;
+        if !unsafe { compat::membership_is_inherited(memberid, tuple) } {
+            continue;
+        }
+        let mut isnull = false;
+        let datum = unsafe {
+            pg_sys::SysCacheGetAttr(
+                cache_id.try_into().unwrap(),
+                tuple,
+                compat::Anum_pg_auth_members_roleid,
+                &mut isnull,
+            )
+        };
+        if let Some(grantedid) = unsafe { pg_sys::Oid::from_datum(datum, isnull) } {
+            result.push(grantedid);
+        }
+    }
+
+    // Release the cache
+    unsafe {
+        pg_sys::ReleaseCatCacheList(catlist.as_ptr());
+    }
+
+    result
+}
+
+/// Return the message of an error caught by a `PgTryBuilder`
+///
+pub fn caught_error_message(e: CaughtError) -> String {
+    match e {
+        CaughtError::PostgresError(report)
+        | CaughtError::ErrorReport(report)
+        | CaughtError::RustPanic {
+            ereport: report, ..
+        } => report.message().to_string(),
+    }
+}
+
+/// Run a function in an internal subtransaction and return the message of
+/// the error it raises, if any
+///
+/// When the error is caught, the subtransaction is rolled back so that the
+/// locks, the buffer pins and the memory acquired by the function are
+/// released, just like a PL/pgSQL exception block does.
+///
+pub fn try_in_subtransaction<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    unsafe {
+        let old_context = pg_sys::CurrentMemoryContext;
+        let old_owner = pg_sys::CurrentResourceOwner;
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        // The result must be allocated in the memory context of the caller
+        pg_sys::MemoryContextSwitchTo(old_context);
+
+        PgTryBuilder::new(std::panic::AssertUnwindSafe(|| {
+            let result = f();
+            pg_sys::ReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(old_context);
+            pg_sys::CurrentResourceOwner = old_owner;
+            Ok(result)
+        }))
+        .catch_others(|e| {
+            pg_sys::MemoryContextSwitchTo(old_context);
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            pg_sys::MemoryContextSwitchTo(old_context);
+            pg_sys::CurrentResourceOwner = old_owner;
+            Err(caught_error_message(e))
+        })
+        .execute()
+    }
+}
+
 /// Return the quoted name of a string
 /// if a schema is named `WEIRD_schema`, its quoted name is `"WEIRD_schema"`
 ///
@@ -238,6 +389,21 @@ mod tests {
         get_function_schema("foo".to_string());
     }
 
+    #[pg_test]
+    fn test_try_in_subtransaction() {
+        assert_eq!(try_in_subtransaction(|| 42), Ok(42));
+        let result = try_in_subtransaction(|| {
+            Spi::run("CREATE TABLE rolled_back(id INT)").unwrap();
+            Spi::run("SELECT 1/0").unwrap();
+        });
+        assert_eq!(result, Err("division by zero".to_string()));
+        // The transaction is still usable and the subtransaction is rolled back
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT to_regclass('rolled_back') IS NULL"),
+            Ok(Some(true))
+        );
+    }
+
     #[pg_test]
     fn test_get_relation_qualified_name_invalid_oid() {
         assert!(get_relation_qualified_name(pg_sys::InvalidOid).is_none());
@@ -272,6 +438,53 @@ mod tests {
         assert!(is_anon_relation_oid(last_name_relid));
     }
 
+    #[pg_test]
+    fn test_roles_is_member_of() {
+        Spi::run(
+            "
+            CREATE ROLE justice_league;
+            CREATE ROLE wayne_enterprises;
+            CREATE ROLE batman IN ROLE justice_league, wayne_enterprises;
+            CREATE ROLE robin IN ROLE batman, justice_league;
+        ",
+        )
+        .unwrap();
+        let oid = |r: &str| {
+            Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{r}'::REGROLE::OID"))
+                .unwrap()
+                .expect("should be an OID")
+        };
+        // justice_league is granted twice but it appears only once
+        let levels = roles_is_member_of(oid("robin"));
+        assert_eq!(3, levels.len());
+        assert_eq!(vec![oid("robin")], levels[0]);
+        assert_eq!(2, levels[1].len());
+        assert!(levels[1].contains(&oid("batman")));
+        assert!(levels[1].contains(&oid("justice_league")));
+        assert_eq!(vec![oid("wayne_enterprises")], levels[2]);
+    }
+
+    #[pg_test]
+    fn test_roles_is_member_of_noinherit() {
+        Spi::run(
+            "
+            CREATE ROLE justice_league;
+            CREATE ROLE batman NOINHERIT IN ROLE justice_league;
+        ",
+        )
+        .unwrap();
+        let batman = Spi::get_one::<pg_sys::Oid>("SELECT 'batman'::REGROLE::OID")
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![vec![batman]], roles_is_member_of(batman));
+    }
+
+    #[pg_test]
+    fn test_roles_is_member_of_no_membership() {
+        let bruce = fixture::create_unmasked_role();
+        assert_eq!(vec![vec![bruce]], roles_is_member_of(bruce));
+    }
+
     #[pg_test]
     fn test_quote_identifier() {
         let schema_c_string = CString::new("WEIRD_schema").unwrap();
@@ -7,15 +7,23 @@
 /// ZomboDB is the main inspiration for this module
 /// https://github.com/zombodb/zombodb/blob/v3000.2.5/src/walker/mod.rs
 ///
+use crate::aggregation;
+use crate::audit;
+use crate::cache;
 use crate::compat;
 use crate::error;
 use crate::input;
+use crate::limits;
 use crate::log;
 use crate::masking;
+use crate::sampling;
+use crate::statistics;
+use crate::unmasking;
 use crate::utils;
+use pgrx::list::old_list::PgList;
+use pgrx::pg_sys::ffi::pg_guard_ffi_boundary;
 use pgrx::*;
-use std::ffi::c_char;
-use std::ffi::CString;
+use std::ffi::CStr;
 
 ///
 /// TreeWalker is the context object that will passed along at each stage
@@ -25,6 +33,21 @@ use std::ffi::CString;
 pub struct TreeWalker {
     policy: String,
     pub reason: Option<input::Reason>,
+    /// The masked relations written by a write-through policy, they must not
+    /// be replaced by their masking subquery
+    result_rtes: Vec<*mut pg_sys::RangeTblEntry>,
+    /// The minimum group size of an aggregate-only policy
+    threshold: Option<i64>,
+    /// The query reads a masked relation or a relation with indirect
+    /// identifiers
+    reads_protected_relation: bool,
+    /// The masked relations read by the query, see `audit::report()`. The
+    /// columns and the sampling ratio are collected only when the audit log
+    /// is enabled.
+    audited_relations: Vec<audit::Relation>,
+    /// The outer queries of the query being rewritten, the innermost last.
+    /// They are needed to resolve the references to their CTEs.
+    outer_queries: Vec<*mut pg_sys::Query>,
 }
 
 impl TreeWalker {
@@ -32,9 +55,101 @@ impl TreeWalker {
         TreeWalker {
             policy,
             reason: None,
+            result_rtes: Vec::new(),
+            threshold: None,
+            reads_protected_relation: false,
+            audited_relations: Vec::new(),
+            outer_queries: Vec::new(),
         }
     }
 
+    /// Report the rewritten statement in the server log, if needed
+    ///
+    /// * stmt is the statement that was rewritten
+    /// * query_id is the queryid of the statement
+    ///
+    pub fn audit(&self, stmt: *mut pg_sys::Node, query_id: u64) {
+        audit::report(stmt, query_id, &self.policy, &self.audited_relations);
+    }
+
+    /// Returns the masked relations read by the query
+    ///
+    pub fn masked_relations(&self) -> Vec<pg_sys::Oid> {
+        let mut relids: Vec<pg_sys::Oid> = Vec::new();
+        for r in &self.audited_relations {
+            if !relids.contains(&r.relid) {
+                relids.push(r.relid);
+            }
+        }
+        relids
+    }
+
+    /// Add a masked relation to the audit record of the query
+    ///
+    /// A relation read several times by the query is reported once
+    ///
+    unsafe fn audit_relation(
+        &mut self,
+        relid: pg_sys::Oid,
+        msq_query: &PgBox<pg_sys::Query>,
+        access: audit::Access,
+    ) {
+        if self
+            .audited_relations
+            .iter()
+            .any(|r| r.relid == relid && r.access == access)
+        {
+            return;
+        }
+        if audit::verbosity() == audit::Verbosity::Off {
+            self.audited_relations.push(audit::Relation {
+                relid,
+                access,
+                sampling: None,
+                masked_columns: Vec::new(),
+                unmasked_columns: Vec::new(),
+            });
+            return;
+        }
+
+        let (targets, _) = flatten_masking_subquery(msq_query);
+        let targets = PgList::<pg_sys::TargetEntry>::from_pg(targets);
+
+        // The relation is already locked by the query
+        let relation = PgBox::from_pg(pg_sys::relation_open(relid, pg_sys::NoLock as i32));
+        let reldesc = PgBox::from_pg(relation.rd_att);
+        let attrs = reldesc.attrs.as_slice(reldesc.natts.try_into().unwrap());
+        let mut masked_columns = Vec::new();
+        let mut unmasked_columns = Vec::new();
+        for a in attrs {
+            if a.attisdropped {
+                continue;
+            }
+            let name = name_data_to_str(&a.attname).to_string();
+            if access != audit::Access::Unmasked && is_masked_target(&targets, a.attnum) {
+                masked_columns.push(name);
+            } else {
+                unmasked_columns.push(name);
+            }
+        }
+        pg_sys::relation_close(relation.as_ptr(), pg_sys::NoLock as i32);
+
+        // The write-through relations are not sampled
+        let sampling = match access {
+            audit::Access::Masked => sampling::get_ratio(relid, &self.policy)
+                .ok()
+                .map(|r| r.to_string()),
+            _ => None,
+        };
+        self.audited_relations.push(audit::Relation {
+            relid,
+            access,
+            sampling,
+            masked_columns,
+            unmasked_columns,
+        });
+    }
+
     pub unsafe fn is_untrusted(&mut self, node: &PgBox<pg_sys::Node>) -> bool {
         // Calling raw_expression_tree_walker() directly here would skip the
         // first node of the tree... Instead we call the walker function
@@ -42,16 +157,31 @@ impl TreeWalker {
         is_untrusted_walker(node.as_ptr(), self as *mut TreeWalker as void_mut_ptr)
     }
 
+    pub unsafe fn has_sublink(&mut self, node: &PgBox<pg_sys::Node>) -> bool {
+        has_sublink_walker(node.as_ptr(), self as *mut TreeWalker as void_mut_ptr)
+    }
+
     pub unsafe fn rewrite(&mut self, query: &PgBox<pg_sys::Query>) -> bool {
         if query.is_null() {
             return false;
         }
-        pg_sys::query_tree_walker(
-            query.as_ptr(),
-            Some(rewrite_walker),
-            self as *mut TreeWalker as void_mut_ptr,
-            pg_sys::QTW_EXAMINE_RTES as i32,
-        )
+        self.threshold = aggregation::get_threshold(&self.policy);
+        let found = rewrite_query(query.as_ptr(), self as *mut TreeWalker as void_mut_ptr);
+
+        // Only the top-level query must be aggregated
+        if let Some(k) = self.threshold {
+            if self.reads_protected_relation {
+                aggregation::restrict_to_aggregates(query.as_ptr(), k);
+            }
+        }
+
+        // Only the rows returned by the top-level query are limited
+        if self.reads_protected_relation {
+            if let Some(n) = limits::current_limits(&self.policy).row_limit {
+                limits::limit_rows(query.as_ptr(), n);
+            }
+        }
+        found
     }
 }
 
@@ -111,6 +241,25 @@ extern "C-unwind" fn is_untrusted_walker(
     unsafe { pg_sys::raw_expression_tree_walker(node, Some(is_untrusted_walker), context_ptr) }
 }
 
+/// Recursive walk through a Raw Expression and search for a subquery
+///
+#[pg_guard]
+extern "C-unwind" fn has_sublink_walker(
+    node: *mut pg_sys::Node,
+    context_ptr: *mut ::core::ffi::c_void,
+) -> bool {
+    if node.is_null() {
+        return false;
+    }
+
+    // Returning true will stop the tree walker right away
+    if unsafe { pgrx::is_a(node, pg_sys::NodeTag::T_SubLink) } {
+        return true;
+    }
+
+    unsafe { pg_sys::raw_expression_tree_walker(node, Some(has_sublink_walker), context_ptr) }
+}
+
 /// Resurively walk through a Query tree and replace each masked relation with
 /// its "Masking SubQuery" (msq)
 ///
@@ -123,14 +272,24 @@ unsafe extern "C-unwind" fn rewrite_walker(
         return false;
     }
 
-    let context = PgBox::<TreeWalker>::from_pg(context_ptr as *mut TreeWalker);
+    let mut context = PgBox::<TreeWalker>::from_pg(context_ptr as *mut TreeWalker);
     let policy = context.policy.clone();
 
     if is_a(node, pg_sys::NodeTag::T_RangeTblEntry) {
         // The node is a Range Table Entry
-        let mut rte = PgBox::from_pg(node as *mut pg_sys::RangeTblEntry);
+        let rte = PgBox::from_pg(node as *mut pg_sys::RangeTblEntry);
         log::debug1!("rte= {:?}", rte);
 
+        // The statistics contain authentic values of the masked relations
+        if statistics::is_statistics_catalog(rte.relid) {
+            error::insufficient_privilege("role is masked".to_string()).ereport();
+        }
+        if statistics::is_statistics_view(rte.relid) {
+            let subquery = masking::statistics_subquery(rte.relid, &policy);
+            replace_relation(rte, subquery);
+            return false;
+        }
+
         // We do not mask catalog relations
         if compat::IsCatalogRelationOid(rte.relid) {
             return false;
@@ -146,91 +305,39 @@ unsafe extern "C-unwind" fn rewrite_walker(
             return false;
         }
 
-        // Create the Masking Sub Query (msq) that will replace the relation
-        let msq_sql = masking::subquery(rte.relid, policy);
-
-        // This table is not masked, skip to the next node
-        if msq_sql.is_none() {
+        // This relation is written through, see `mask_result_relation()`
+        if context.result_rtes.contains(&rte.as_ptr()) {
             return false;
         }
 
-        log::debug1!("msq_sql= {}", msq_sql.clone().unwrap());
-
-        // Create the Raw Statement from the SQL subquery
-        let msq_raw_stmt = masking::parse_subquery(msq_sql.clone().unwrap());
-        log::debug1!("msq_raw_stmt= {:?}", *msq_raw_stmt);
-
-        // Create the Parse State with the SQL subquery within
-        let mut msq_pstate = unsafe {
-            PgBox::from_pg(pg_sys::make_parsestate(std::ptr::null_mut::<
-                pg_sys::ParseState,
-            >()))
-        };
-        let msq_sql_c_string = CString::new(msq_sql.clone().unwrap().as_str()).unwrap();
-        let msq_sql_ptr = msq_sql_c_string.as_c_str().as_ptr() as *const c_char;
-        msq_pstate.p_sourcetext = msq_sql_ptr;
-
-        // Create the Query object
-        // Calling parse_analyze_varparams(...) would trigger the
-        // post_parse_analyze hook again and we'd stuck in an infinite loop
-        let mut msq_query = unsafe {
-            PgBox::from_pg(pg_sys::transformTopLevelStmt(
-                msq_pstate.as_ptr(),
-                msq_raw_stmt.as_ptr(),
-            ))
-        };
-
-        //
-        // QSRC_PARSER is not used anymore by Postgres.
-        // This is convenient because we can use it as a marker to signal
-        // that this query was generated by the extension, and thus it should
-        // NOT be masked otherwise we'd trapped in an infinite loop
-        //
-        msq_query.querySource = pg_sys::QuerySource::QSRC_PARSER;
-        log::debug1!("new_subquery= {:?}", *msq_query);
-
+        // Get the Masking Sub Query (msq) that will replace the relation
         //
-        // Somehow later in the process, the optimizer will run a callback
-        // named `pullup_replace_vars_callback` on each Range_Table_Entry
-        // variable and use the original attribute number (attnum/resno) in
-        // order to find the attribute.
-        // Since we replace the original table with a masking subquery, there
-        // may be some inconsistencies when the original tables contains a
-        // dropped column.
-        // For instance, if a table has 4 columns a,b,c,d and the c column was
-        // dropped, then the attribute numbers of a,b,d would be 1,2,4.
-        // But in the masking subquery the attribute numbers would be 1,2,3
-        // This would lead to an error during the optimizer stage.
+        // The target list of the msq contains the dropped columns as NULL
+        // placeholders so the attribute numbers (attnum/resno) of the original
+        // relation are preserved. This is required by the optimizer which
+        // will use them later in `pullup_replace_vars_callback`
         //
-        // We avoid this issue by assigning the attribute numbers of the
-        // original table upon the columns of the masking subquery
-        //
-        let original_attnums = utils::get_column_numbers(rte.relid).unwrap();
-        let target_list = PgList::<pg_sys::TargetEntry>::from_pg(msq_query.targetList);
+        let Some(msq_query) = cache::masking_subquery(rte.relid, policy) else {
+            if context.threshold.is_some() && aggregation::has_indirect_identifiers(rte.relid) {
+                context.reads_protected_relation = true;
+            }
+            // This table is not masked, skip to the next node
+            return false;
+        };
 
-        for (i, target_ptr) in target_list.iter_ptr().enumerate() {
-            let mut target = PgBox::<pg_sys::TargetEntry>::from_pg(target_ptr);
-            target.resno = original_attnums[i];
-            target.into_pg();
+        // This table is temporarily unmasked for the current user
+        if unmasking::is_unmasked(pg_sys::GetUserId(), rte.relid) {
+            unmasking::unmask_relation(rte.as_ptr());
+            context.audit_relation(rte.relid, &msq_query, audit::Access::Unmasked);
+            return false;
         }
+        context.reads_protected_relation = true;
+        context.audit_relation(rte.relid, &msq_query, audit::Access::Masked);
+        log::debug1!("msq_query= {:?}", *msq_query);
 
         // Do the substitution
         //pg_sys::AcquireRewriteLocks(msq_query.as_ptr(), true, false);
-        rte.rtekind = pg_sys::RTEKind::RTE_SUBQUERY;
-        rte.subquery = msq_query.as_ptr();
-        rte.relid = pg_sys::InvalidOid;
-        rte.relkind = 0;
-        compat::rte_perminfo_index_disable!(rte);
-
-        // We must set `rte.inh` to false, otherwise the volatile functions
-        // are not executed
-        rte.inh = false;
-
-        // TODO apply the table sampling ratio
-        // rte.tablesample = ....;
-
-        // Return the modified RTE to Postgres
-        rte.into_pg();
+        replace_relation(rte, msq_query);
 
         return false;
     } else if is_a(node, pg_sys::NodeTag::T_Query) {
@@ -242,12 +349,7 @@ unsafe extern "C-unwind" fn rewrite_walker(
             return false;
         }
         // Continue parsing the tree
-        return pg_sys::query_tree_walker(
-            node as *mut pg_sys::Query,
-            Some(rewrite_walker),
-            context_ptr,
-            pg_sys::QTW_EXAMINE_RTES as i32,
-        );
+        return rewrite_query(node as *mut pg_sys::Query, context_ptr);
     } else if is_a(node, pg_sys::NodeTag::T_InsertStmt)
         || is_a(node, pg_sys::NodeTag::T_DeleteStmt)
         || is_a(node, pg_sys::NodeTag::T_UpdateStmt)
@@ -259,9 +361,752 @@ unsafe extern "C-unwind" fn rewrite_walker(
         error::insufficient_privilege("role is masked".to_string()).ereport();
     }
 
+    // The functions called by the node, including the operators, the
+    // aggregates and the coercions, must not be forbidden for this policy
+    // and must not read a masked relation in a pre-parsed body
+    pg_sys::check_functions_in_node(node, Some(function_checker), context_ptr);
+
     pg_sys::expression_tree_walker(node, Some(rewrite_walker), context_ptr)
 }
 
+/// Replace a relation with a subquery
+///
+unsafe fn replace_relation(mut rte: PgBox<pg_sys::RangeTblEntry>, subquery: PgBox<pg_sys::Query>) {
+    rte.rtekind = pg_sys::RTEKind::RTE_SUBQUERY;
+    rte.subquery = subquery.as_ptr();
+    rte.relid = pg_sys::InvalidOid;
+    rte.relkind = 0;
+    compat::rte_perminfo_index_disable!(rte);
+
+    // We must set `rte.inh` to false, otherwise the volatile functions
+    // are not executed
+    rte.inh = false;
+
+    // Return the modified RTE to Postgres
+    rte.into_pg();
+}
+
+/// Raise an error if a function is forbidden for the masking policy or if
+/// it is a SQL function reading a masked relation
+///
+/// See `masking::is_forbidden_function()` and `check_sql_function()`
+///
+#[pg_guard]
+unsafe extern "C-unwind" fn function_checker(
+    funcid: pg_sys::Oid,
+    context_ptr: void_mut_ptr,
+) -> bool {
+    let context = PgBox::<TreeWalker>::from_pg(context_ptr as *mut TreeWalker);
+    if cache::is_forbidden_function(funcid, &context.policy) {
+        let funcname =
+            utils::get_function_qualified_name(funcid).unwrap_or_else(|| funcid.to_string());
+        error::insufficient_privilege(format!("function {funcname} is forbidden")).ereport();
+    }
+    check_sql_function(funcid, context.policy.clone());
+    false
+}
+
+/// The context of `sql_function_walker()`
+///
+struct SqlFunctionWalker {
+    policy: String,
+    /// The functions already checked, to avoid infinite recursion
+    visited: Vec<pg_sys::Oid>,
+    /// The first masked relation found in the function bodies
+    masked_relid: Option<pg_sys::Oid>,
+}
+
+/// Raise an error if a SQL function with a pre-parsed body reads a masked
+/// relation
+///
+/// The body of a SQL function is usually parsed when the function is called
+/// and it is masked just like any other query. But since PG14, the body of a
+/// function declared with `BEGIN ATOMIC` or `RETURN` is parsed when the
+/// function is created and it is never masked. We can't rewrite the stored
+/// query tree, so the call is refused.
+///
+unsafe fn check_sql_function(funcid: pg_sys::Oid, policy: String) {
+    if let Some(relid) = cache::sql_function_masked_relation(funcid, &policy) {
+        error::insufficient_privilege(format!(
+            "function {} reads the masked relation {}",
+            utils::get_function_qualified_name(funcid).unwrap_or_default(),
+            utils::get_relation_qualified_name(relid).unwrap_or_default()
+        ))
+        .ereport();
+    }
+}
+
+/// Returns the first masked relation read by a SQL function with a
+/// pre-parsed body, including in the SQL functions it calls
+///
+pub unsafe fn sql_function_masked_relation(
+    funcid: pg_sys::Oid,
+    policy: String,
+) -> Option<pg_sys::Oid> {
+    let mut context = SqlFunctionWalker {
+        policy,
+        visited: vec![funcid],
+        masked_relid: None,
+    };
+    let body = sql_function_body(funcid);
+    sql_function_walker(body, &mut context as *mut SqlFunctionWalker as void_mut_ptr);
+    context.masked_relid
+}
+
+/// Returns the pre-parsed body of a SQL function or NULL
+///
+unsafe fn sql_function_body(funcid: pg_sys::Oid) -> *mut pg_sys::Node {
+    let proctup = pg_sys::SearchSysCache1(
+        pg_sys::SysCacheIdentifier::PROCOID as i32,
+        pg_sys::Datum::from(funcid),
+    );
+    if proctup.is_null() {
+        return std::ptr::null_mut();
+    }
+    let procform = PgBox::from_pg(pg_sys::heap_tuple_get_struct::<pg_sys::FormData_pg_proc>(
+        proctup,
+    ));
+    let body = if procform.prolang == compat::SQLlanguageId {
+        compat::sql_function_body(proctup)
+    } else {
+        std::ptr::null_mut()
+    };
+    pg_sys::ReleaseSysCache(proctup);
+    body
+}
+
+/// Recursively walk through the body of a SQL function and search for a
+/// masked relation, including in the SQL functions it calls
+///
+#[pg_guard]
+unsafe extern "C-unwind" fn sql_function_walker(
+    node: *mut pg_sys::Node,
+    context_ptr: void_mut_ptr,
+) -> bool {
+    if node.is_null() {
+        return false;
+    }
+
+    let mut context = PgBox::<SqlFunctionWalker>::from_pg(context_ptr as *mut SqlFunctionWalker);
+
+    if is_a(node, pg_sys::NodeTag::T_Query) {
+        return pg_sys::query_tree_walker(
+            node as *mut pg_sys::Query,
+            Some(sql_function_walker),
+            context_ptr,
+            pg_sys::QTW_EXAMINE_RTES as i32,
+        );
+    } else if is_a(node, pg_sys::NodeTag::T_RangeTblEntry) {
+        let rte = PgBox::from_pg(node as *mut pg_sys::RangeTblEntry);
+        let relid = rte.relid;
+        if rte.rtekind == pg_sys::RTEKind::RTE_RELATION
+            && !compat::IsCatalogRelationOid(relid)
+            && !utils::is_anon_relation_oid(relid)
+            && cache::masking_subquery(relid, context.policy.clone()).is_some()
+        {
+            context.masked_relid = Some(relid);
+            return true;
+        }
+        return false;
+    }
+
+    if pg_sys::check_functions_in_node(node, Some(sql_function_checker), context_ptr) {
+        return true;
+    }
+
+    pg_sys::expression_tree_walker(node, Some(sql_function_walker), context_ptr)
+}
+
+/// Walk through the body of a function called by a SQL function body
+///
+#[pg_guard]
+unsafe extern "C-unwind" fn sql_function_checker(
+    funcid: pg_sys::Oid,
+    context_ptr: void_mut_ptr,
+) -> bool {
+    let mut context = PgBox::<SqlFunctionWalker>::from_pg(context_ptr as *mut SqlFunctionWalker);
+    if context.visited.contains(&funcid) {
+        return false;
+    }
+    context.visited.push(funcid);
+    sql_function_walker(sql_function_body(funcid), context_ptr)
+}
+
+/// Walk through a Query tree and mask its relations
+///
+/// When the query writes into a masked relation, the relation is kept only
+/// if the masking policy is a write-through policy.
+///
+unsafe fn rewrite_query(query: *mut pg_sys::Query, context_ptr: void_mut_ptr) -> bool {
+    let mut context = PgBox::<TreeWalker>::from_pg(context_ptr as *mut TreeWalker);
+    let command_type = (*query).commandType;
+    let result_relation = (*query).resultRelation;
+
+    let mut queries = context.outer_queries.clone();
+    queries.push(query);
+    check_inference(&queries, &context.policy);
+
+    if (command_type == pg_sys::CmdType::CMD_INSERT
+        || command_type == pg_sys::CmdType::CMD_UPDATE
+        || command_type == pg_sys::CmdType::CMD_DELETE
+        || compat::is_merge(command_type))
+        && result_relation > 0
+    {
+        let rte =
+            pg_sys::list_nth((*query).rtable, result_relation - 1) as *mut pg_sys::RangeTblEntry;
+        let relid = (*rte).relid;
+        if !compat::IsCatalogRelationOid(relid) && !utils::is_anon_relation_oid(relid) {
+            if let Some(msq_query) = cache::masking_subquery(relid, context.policy.clone()) {
+                if !masking::is_write_through_policy(&context.policy) {
+                    error::insufficient_privilege("role is masked".to_string()).ereport();
+                }
+                context.audit_relation(relid, &msq_query, audit::Access::WriteThrough);
+                mask_result_relation(query, rte, msq_query);
+                context.result_rtes.push(rte);
+                context.reads_protected_relation = true;
+            }
+        }
+    }
+
+    context.outer_queries.push(query);
+    let found = pg_sys::query_tree_walker(
+        query,
+        Some(rewrite_walker),
+        context_ptr,
+        pg_sys::QTW_EXAMINE_RTES as i32,
+    );
+    context.outer_queries.pop();
+
+    // The guards of the unmasked relations are sublinks, see
+    // `unmasking::unmask_relation()`
+    if PgList::<pg_sys::RangeTblEntry>::from_pg((*query).rtable)
+        .iter_ptr()
+        .any(|rte| !(*rte).securityQuals.is_null())
+    {
+        (*query).hasSubLinks = true;
+    }
+    found
+}
+
+/// Let a masked role write into a masked relation
+///
+/// The relation is not replaced by its masking subquery. Instead, the columns
+/// read by the query (in the WHERE clause, the SET clause, the RETURNING list
+/// or the MERGE actions) are replaced by their masking expressions and the
+/// row filter of the relation is added to the WHERE clause (or to the
+/// conditions of the MERGE actions). The new rows must match the row filter.
+/// Writing into a masked column is not allowed.
+///
+/// The tablesample ratio is ignored.
+///
+unsafe fn mask_result_relation(
+    query: *mut pg_sys::Query,
+    rte: *mut pg_sys::RangeTblEntry,
+    msq_query: PgBox<pg_sys::Query>,
+) {
+    let mut query = PgBox::from_pg(query);
+    let varno = query.resultRelation;
+
+    // The masking expressions and the row filter are attached to the result
+    // relation instead of the relation of the masking subquery
+    let (targets, filter) = flatten_masking_subquery(&msq_query);
+    pg_guard_ffi_boundary(|| {
+        compat::ChangeVarNodes(targets as *mut pg_sys::Node, 1, varno, 0);
+        compat::ChangeVarNodes(filter, 1, varno, 0);
+    });
+
+    check_written_columns(query.targetList, targets);
+    let on_conflict_update = !query.onConflict.is_null()
+        && (*query.onConflict).action == pg_sys::OnConflictAction::ONCONFLICT_UPDATE;
+    if on_conflict_update {
+        check_written_columns((*query.onConflict).onConflictSet, targets);
+    }
+    let merge_actions = compat::merge_actions(query.as_ptr());
+    for action in &merge_actions {
+        check_written_columns(*action.target_list, targets);
+    }
+
+    let mut has_sublinks = query.hasSubLinks;
+    let mut replace_vars = |node: *mut pg_sys::Node| {
+        pg_guard_ffi_boundary(|| {
+            compat::ReplaceVarsFromTargetList(
+                node,
+                varno,
+                0,
+                rte,
+                targets,
+                compat::REPLACEVARS_REPORT_ERROR,
+                0,
+                &mut has_sublinks,
+            )
+        })
+    };
+    query.jointree = replace_vars(query.jointree as *mut pg_sys::Node) as *mut pg_sys::FromExpr;
+    query.targetList = replace_vars(query.targetList as *mut pg_sys::Node) as *mut pg_sys::List;
+    query.returningList =
+        replace_vars(query.returningList as *mut pg_sys::Node) as *mut pg_sys::List;
+    if on_conflict_update {
+        let mut on_conflict = PgBox::from_pg(query.onConflict);
+        on_conflict.onConflictSet =
+            replace_vars(on_conflict.onConflictSet as *mut pg_sys::Node) as *mut pg_sys::List;
+        on_conflict.onConflictWhere = replace_vars(on_conflict.onConflictWhere);
+        on_conflict.onConflictWhere = pg_sys::make_and_qual(on_conflict.onConflictWhere, filter);
+    }
+    if let Some(join_condition) = compat::merge_join_condition(query.as_ptr()) {
+        *join_condition = replace_vars(*join_condition);
+    }
+    for action in &merge_actions {
+        *action.qual = replace_vars(*action.qual);
+        *action.target_list =
+            replace_vars(*action.target_list as *mut pg_sys::Node) as *mut pg_sys::List;
+
+        // Adding the filter to the join condition would turn the hidden rows
+        // into unmatched rows, so it is added to each action instead
+        if action.on_target_row {
+            let filter = pg_sys::copyObjectImpl(filter as *const std::ffi::c_void);
+            *action.qual = pg_sys::make_and_qual(*action.qual, filter as *mut pg_sys::Node);
+        }
+    }
+    query.hasSubLinks = has_sublinks;
+
+    // The masked role can't write the rows it can't see, the new rows are
+    // checked just like with a row-level security policy
+    if !filter.is_null() {
+        let command_type = query.commandType;
+        let inserts = command_type == pg_sys::CmdType::CMD_INSERT || compat::is_merge(command_type);
+        let updates = command_type == pg_sys::CmdType::CMD_UPDATE
+            || on_conflict_update
+            || compat::is_merge(command_type);
+        if inserts {
+            add_filter_check(
+                &mut query,
+                rte,
+                pg_sys::WCOKind::WCO_RLS_INSERT_CHECK,
+                filter,
+            );
+        }
+        if updates {
+            add_filter_check(
+                &mut query,
+                rte,
+                pg_sys::WCOKind::WCO_RLS_UPDATE_CHECK,
+                filter,
+            );
+        }
+    }
+
+    // The masked role can't modify the rows it can't see
+    if query.commandType == pg_sys::CmdType::CMD_UPDATE
+        || query.commandType == pg_sys::CmdType::CMD_DELETE
+    {
+        let mut jointree = PgBox::from_pg(query.jointree);
+        jointree.quals = pg_sys::make_and_qual(jointree.quals, filter);
+    }
+}
+
+/// Check that the new rows of the result relation match its row filter
+///
+unsafe fn add_filter_check(
+    query: &mut PgBox<pg_sys::Query>,
+    rte: *mut pg_sys::RangeTblEntry,
+    kind: pg_sys::WCOKind::Type,
+    filter: *mut pg_sys::Node,
+) {
+    let mut wco = PgBox::<pg_sys::WithCheckOption>::alloc_node(pg_sys::NodeTag::T_WithCheckOption);
+    wco.kind = kind;
+    wco.relname = pg_sys::get_rel_name((*rte).relid);
+    wco.polname = std::ptr::null_mut();
+    wco.qual = pg_sys::copyObjectImpl(filter as *const std::ffi::c_void) as *mut pg_sys::Node;
+    wco.cascaded = false;
+    if pg_guard_ffi_boundary(|| compat::checkExprHasSubLink(wco.qual)) {
+        query.hasSubLinks = true;
+    }
+    query.withCheckOptions = pg_sys::lappend(query.withCheckOptions, wco.into_pg() as void_mut_ptr);
+}
+
+/// Returns the masking expressions of a masking subquery and its row filter
+///
+/// When the relation has generated columns, the masking subquery has two
+/// levels and the second level is merged into the first one.
+///
+unsafe fn flatten_masking_subquery(
+    msq_query: &PgBox<pg_sys::Query>,
+) -> (*mut pg_sys::List, *mut pg_sys::Node) {
+    let rte = pg_sys::list_nth(msq_query.rtable, 0) as *mut pg_sys::RangeTblEntry;
+    if (*rte).rtekind != pg_sys::RTEKind::RTE_SUBQUERY {
+        return (msq_query.targetList, (*msq_query.jointree).quals);
+    }
+
+    let inner_query = PgBox::from_pg((*rte).subquery);
+    let mut has_sublinks = false;
+    let targets = pg_guard_ffi_boundary(|| {
+        compat::ReplaceVarsFromTargetList(
+            msq_query.targetList as *mut pg_sys::Node,
+            1,
+            0,
+            rte,
+            inner_query.targetList,
+            compat::REPLACEVARS_REPORT_ERROR,
+            0,
+            &mut has_sublinks,
+        )
+    });
+    (targets as *mut pg_sys::List, (*inner_query.jointree).quals)
+}
+
+/// Raise an error if a target list writes into a masked column
+///
+/// A column is not masked when its masking expression is the column itself
+///
+unsafe fn check_written_columns(target_list: *mut pg_sys::List, targets: *mut pg_sys::List) {
+    let written = PgList::<pg_sys::TargetEntry>::from_pg(target_list);
+    let targets = PgList::<pg_sys::TargetEntry>::from_pg(targets);
+    for tle in written.iter_ptr() {
+        if (*tle).resjunk {
+            continue;
+        }
+        if is_masked_target(&targets, (*tle).resno) {
+            let attname = CStr::from_ptr((*tle).resname).to_string_lossy();
+            error::insufficient_privilege(format!("column {attname} is masked")).ereport();
+        }
+    }
+}
+
+/// Returns true if the masking expression of a column is not the column
+/// itself
+///
+unsafe fn is_masked_target(targets: &PgList<pg_sys::TargetEntry>, attnum: i16) -> bool {
+    let Some(target) = targets.get_ptr(attnum as usize - 1) else {
+        return false;
+    };
+    let expr = (*target).expr as *mut pg_sys::Node;
+    !is_a(expr, pg_sys::NodeTag::T_Var) || (*(expr as *mut pg_sys::Var)).varattno != attnum
+}
+
+/// The context of `inference_walker()`
+///
+struct InferenceWalker {
+    policy: String,
+    /// The query where the clause is declared and its outer queries, the
+    /// innermost last
+    queries: Vec<*mut pg_sys::Query>,
+    /// The depth of the sublinks inside the clause
+    levelsup: u32,
+    /// The first masked column found in the clause
+    column: Option<(pg_sys::Oid, i16)>,
+}
+
+/// Report the masked columns used to filter, join, group or sort the rows
+///
+/// Even when the masked values are not revealed, a masked role may infer the
+/// authentic values by running the same query with different predicates.
+/// The report depends on the inference guard of the policy, see
+/// `masking::inference_guard()`
+///
+/// The columns are followed through the subqueries, the joins, the CTEs and
+/// the views. The clauses of the views are checked too: they are evaluated on
+/// the authentic values, even when the view is owned by another role.
+///
+/// Arguments:
+/// * `queries` is the query and its outer queries, the innermost last
+/// * `policy` is the masking policy of the role
+///
+unsafe fn check_inference(queries: &[*mut pg_sys::Query], policy: &str) {
+    let query = *queries.last().unwrap();
+    let guard = masking::inference_guard(policy);
+    if guard == masking::InferenceGuard::Off {
+        return;
+    }
+
+    let mut clauses: Vec<(&str, *mut pg_sys::Node)> = Vec::new();
+    collect_join_quals((*query).jointree as *mut pg_sys::Node, &mut clauses);
+    clauses.push(("HAVING", (*query).havingQual));
+    for (clause, list) in [
+        ("GROUP BY", (*query).groupClause),
+        ("ORDER BY", (*query).sortClause),
+    ] {
+        for sgc in PgList::<pg_sys::SortGroupClause>::from_pg(list).iter_ptr() {
+            let tle = pg_sys::get_sortgroupref_tle((*sgc).tleSortGroupRef, (*query).targetList);
+            clauses.push((clause, (*tle).expr as *mut pg_sys::Node));
+        }
+    }
+
+    for (clause, node) in clauses {
+        let Some((relid, attnum)) = find_masked_column(node, queries, policy) else {
+            continue;
+        };
+        report_inference(guard, relid, attnum, clause);
+        return;
+    }
+
+    for rte in PgList::<pg_sys::RangeTblEntry>::from_pg((*query).rtable).iter_ptr() {
+        if is_view(rte) {
+            with_view_query((*rte).relid, |view_query| {
+                let mut context = ViewWalker {
+                    policy: policy.to_string(),
+                    queries: vec![],
+                };
+                view_walker(
+                    view_query as *mut pg_sys::Node,
+                    &mut context as *mut ViewWalker as void_mut_ptr,
+                );
+            });
+        }
+    }
+}
+
+/// Report a masked column used in a clause, according to the inference guard
+///
+unsafe fn report_inference(
+    guard: masking::InferenceGuard,
+    relid: pg_sys::Oid,
+    attnum: i16,
+    clause: &str,
+) {
+    let relname = utils::get_relation_qualified_name(relid).unwrap_or_default();
+    let attname = CStr::from_ptr(pg_sys::get_attname(relid, attnum, false)).to_string_lossy();
+    let report = error::insufficient_privilege(format!(
+        "the masked column {relname}.{attname} is used in a {clause} clause"
+    ));
+    match guard {
+        masking::InferenceGuard::Error => report.ereport(),
+        masking::InferenceGuard::Warning => report.warning(),
+        masking::InferenceGuard::Audit => report.audit(),
+        masking::InferenceGuard::Off => (),
+    }
+}
+
+/// Returns true if a range table entry is a view read by the query
+///
+/// Until PG15, the definition of a view contains two entries referencing the
+/// view itself, `OLD` and `NEW`. They are not in the FROM clause.
+///
+unsafe fn is_view(rte: *mut pg_sys::RangeTblEntry) -> bool {
+    (*rte).rtekind == pg_sys::RTEKind::RTE_RELATION
+        && (*rte).relkind as u8 == pg_sys::RELKIND_VIEW
+        && (*rte).inFromCl
+}
+
+/// Run a function on the definition of a view
+///
+/// The query tree belongs to the relcache entry of the view, it must not be
+/// modified
+///
+unsafe fn with_view_query<R>(relid: pg_sys::Oid, f: impl FnOnce(*mut pg_sys::Query) -> R) -> R {
+    let relation = pg_sys::relation_open(relid, pg_sys::AccessShareLock as i32);
+    let result = f(pg_sys::get_view_query(relation));
+    pg_sys::relation_close(relation, pg_sys::NoLock as i32);
+    result
+}
+
+/// The context of `view_walker()`
+///
+struct ViewWalker {
+    policy: String,
+    /// The outer queries of the current query, the innermost last
+    queries: Vec<*mut pg_sys::Query>,
+}
+
+/// Check the clauses of a view definition, including its subqueries, its
+/// CTEs and its sublinks
+///
+/// The views are not rewritten by the masking engine, so `check_inference()`
+/// is not called on their queries otherwise
+///
+#[pg_guard]
+unsafe extern "C-unwind" fn view_walker(
+    node: *mut pg_sys::Node,
+    context_ptr: void_mut_ptr,
+) -> bool {
+    if node.is_null() {
+        return false;
+    }
+    let context = &mut *(context_ptr as *mut ViewWalker);
+    if is_a(node, pg_sys::NodeTag::T_Query) {
+        let query = node as *mut pg_sys::Query;
+        context.queries.push(query);
+        check_inference(&context.queries, &context.policy);
+        let found = pg_sys::query_tree_walker(query, Some(view_walker), context_ptr, 0);
+        context.queries.pop();
+        return found;
+    }
+    pg_sys::expression_tree_walker(node, Some(view_walker), context_ptr)
+}
+
+/// Collect the WHERE clause and the JOIN conditions of a jointree
+///
+unsafe fn collect_join_quals(
+    node: *mut pg_sys::Node,
+    clauses: &mut Vec<(&str, *mut pg_sys::Node)>,
+) {
+    if node.is_null() {
+        return;
+    }
+    if is_a(node, pg_sys::NodeTag::T_FromExpr) {
+        let from = node as *mut pg_sys::FromExpr;
+        for item in PgList::<pg_sys::Node>::from_pg((*from).fromlist).iter_ptr() {
+            collect_join_quals(item, clauses);
+        }
+        clauses.push(("WHERE", (*from).quals));
+    } else if is_a(node, pg_sys::NodeTag::T_JoinExpr) {
+        let join = node as *mut pg_sys::JoinExpr;
+        collect_join_quals((*join).larg, clauses);
+        collect_join_quals((*join).rarg, clauses);
+        clauses.push(("JOIN", (*join).quals));
+    }
+}
+
+/// Returns the first masked column referenced by an expression
+///
+unsafe fn find_masked_column(
+    node: *mut pg_sys::Node,
+    queries: &[*mut pg_sys::Query],
+    policy: &str,
+) -> Option<(pg_sys::Oid, i16)> {
+    let mut context = InferenceWalker {
+        policy: policy.to_string(),
+        queries: queries.to_vec(),
+        levelsup: 0,
+        column: None,
+    };
+    inference_walker(node, &mut context as *mut InferenceWalker as void_mut_ptr);
+    context.column
+}
+
+/// Returns the masked column behind a Var, if any
+///
+/// The columns of the subqueries, the joins, the CTEs and the views are
+/// followed down to the relations. A whole-row Var is masked if one of the
+/// columns is masked.
+///
+/// `queries` is the query of the Var and its outer queries, the innermost
+/// last
+///
+unsafe fn masked_column(
+    var: *mut pg_sys::Var,
+    queries: &[*mut pg_sys::Query],
+    policy: &str,
+) -> Option<(pg_sys::Oid, i16)> {
+    let query = *queries.last()?;
+    let rte = PgList::<pg_sys::RangeTblEntry>::from_pg((*query).rtable)
+        .get_ptr((*var).varno as usize - 1)?;
+    let attnum = (*var).varattno;
+    if attnum < 0 {
+        return None;
+    }
+
+    match (*rte).rtekind {
+        pg_sys::RTEKind::RTE_RELATION => {
+            let relid = (*rte).relid;
+            if compat::IsCatalogRelationOid(relid) || utils::is_anon_relation_oid(relid) {
+                return None;
+            }
+            let Some(msq_query) = cache::masking_subquery(relid, policy.to_string()) else {
+                // The view is not masked, its columns are read on the
+                // relations of its definition
+                if (*rte).relkind as u8 == pg_sys::RELKIND_VIEW {
+                    return with_view_query(relid, |view_query| {
+                        masked_target((*view_query).targetList, attnum, &[view_query], policy)
+                    });
+                }
+                return None;
+            };
+            if unmasking::is_unmasked(pg_sys::GetUserId(), relid) {
+                return None;
+            }
+            let (targets, _) = flatten_masking_subquery(&msq_query);
+            let targets = PgList::<pg_sys::TargetEntry>::from_pg(targets);
+            if attnum > 0 {
+                return is_masked_target(&targets, attnum).then_some((relid, attnum));
+            }
+            utils::get_column_numbers(relid)?
+                .into_iter()
+                .find(|&a| is_masked_target(&targets, a))
+                .map(|a| (relid, a))
+        }
+        pg_sys::RTEKind::RTE_SUBQUERY => {
+            let subquery = (*rte).subquery;
+            let queries = [queries, &[subquery]].concat();
+            masked_target((*subquery).targetList, attnum, &queries, policy)
+        }
+        pg_sys::RTEKind::RTE_JOIN => PgList::<pg_sys::Node>::from_pg((*rte).joinaliasvars)
+            .iter_ptr()
+            .enumerate()
+            .filter(|(i, _)| attnum == 0 || *i == attnum as usize - 1)
+            .find_map(|(_, alias)| find_masked_column(alias, queries, policy)),
+        pg_sys::RTEKind::RTE_CTE => {
+            // The CTE is declared by the query `ctelevelsup` levels up
+            let level = queries.len().checked_sub((*rte).ctelevelsup as usize + 1)?;
+            let ctename = CStr::from_ptr((*rte).ctename);
+            let cte = PgList::<pg_sys::CommonTableExpr>::from_pg((*queries[level]).cteList)
+                .iter_ptr()
+                .find(|&cte| CStr::from_ptr((*cte).ctename) == ctename)?;
+            let ctequery = (*cte).ctequery as *mut pg_sys::Query;
+            // The columns of a data-modifying CTE are its RETURNING list
+            let targets = if (*ctequery).commandType == pg_sys::CmdType::CMD_SELECT {
+                (*ctequery).targetList
+            } else {
+                (*ctequery).returningList
+            };
+            let queries = [&queries[..=level], &[ctequery]].concat();
+            masked_target(targets, attnum, &queries, policy)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the masked column behind a column of a subquery, a CTE or a view
+///
+/// The whole row of the subquery (`attnum` = 0) is masked if one of its
+/// columns is masked
+///
+/// `queries` is the subquery and its outer queries, the innermost last
+///
+unsafe fn masked_target(
+    targets: *mut pg_sys::List,
+    attnum: i16,
+    queries: &[*mut pg_sys::Query],
+    policy: &str,
+) -> Option<(pg_sys::Oid, i16)> {
+    PgList::<pg_sys::TargetEntry>::from_pg(targets)
+        .iter_ptr()
+        .filter(|&tle| !(*tle).resjunk && (attnum == 0 || (*tle).resno == attnum))
+        .find_map(|tle| find_masked_column((*tle).expr as *mut pg_sys::Node, queries, policy))
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn inference_walker(
+    node: *mut pg_sys::Node,
+    context_ptr: void_mut_ptr,
+) -> bool {
+    if node.is_null() {
+        return false;
+    }
+
+    let context = &mut *(context_ptr as *mut InferenceWalker);
+
+    if is_a(node, pg_sys::NodeTag::T_Var) {
+        let var = node as *mut pg_sys::Var;
+        if (*var).varlevelsup == context.levelsup {
+            context.column = masked_column(var, &context.queries, &context.policy);
+        }
+        return context.column.is_some();
+    } else if is_a(node, pg_sys::NodeTag::T_Query) {
+        // The Vars of a sublink may reference the query of the clause
+        context.levelsup += 1;
+        let found = pg_sys::query_tree_walker(
+            node as *mut pg_sys::Query,
+            Some(inference_walker),
+            context_ptr,
+            0,
+        );
+        context.levelsup -= 1;
+        return found;
+    }
+
+    pg_sys::expression_tree_walker(node, Some(inference_walker), context_ptr)
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
@@ -317,6 +1162,15 @@ mod tests {
         assert_eq!(walker.reason, Some(input::Reason::FunctionUntrusted));
     }
 
+    #[pg_test]
+    fn test_has_sublink() {
+        let mut walker = TreeWalker::new(String::from("anon"));
+        let pred = input::parse_expression("a = 1 AND b IN (SELECT 1)").unwrap();
+        assert!(unsafe { walker.has_sublink(&pred) });
+        let pred = input::parse_expression("a = 1 AND b IN (1,2)").unwrap();
+        assert!(!unsafe { walker.has_sublink(&pred) });
+    }
+
     #[pg_test]
     fn test_rewrite_null() {
         let policy = "anon";
@@ -324,4 +1178,283 @@ mod tests {
         let null_query = pgrx::PgBox::<Query>::null();
         assert!(!unsafe { walker.rewrite(&null_query) });
     }
+
+    fn grant_person_to_batman(write_through: bool) {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(&format!(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET anon.write_through_policies TO '{}';
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT ALL ON person TO batman;
+            SET ROLE batman;
+        ",
+            if write_through { "anon" } else { "" }
+        ))
+        .unwrap();
+    }
+
+    #[pg_test(error = "Anon: function public.belt is forbidden")]
+    fn test_rewrite_forbidden_function_label() {
+        fixture::create_masking_functions();
+        Spi::run("SECURITY LABEL FOR anon ON FUNCTION public.belt() IS 'FORBIDDEN'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run("SELECT firstname FROM person WHERE public.belt() = 'x'").unwrap();
+    }
+
+    #[pg_test(error = "Anon: function pg_catalog.pg_read_file is forbidden")]
+    fn test_rewrite_forbidden_function_guc() {
+        Spi::run("SET anon.forbidden_functions TO 'pg_catalog.pg_read_file'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run("SELECT pg_catalog.pg_read_file('postgresql.conf')").unwrap();
+    }
+
+    #[pg_test(error = "Anon: the masked column public.person.lastname is used in a WHERE clause")]
+    fn test_rewrite_inference_guard() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run("SELECT firstname FROM person ORDER BY firstname").unwrap();
+        Spi::run("SELECT count(*) FROM person p WHERE p.lastname LIKE 'C%'").unwrap();
+    }
+
+    #[pg_test(
+        error = "Anon: the masked column public.person.lastname is used in a GROUP BY clause"
+    )]
+    fn test_rewrite_inference_guard_subquery() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run("SELECT n, count(*) FROM (SELECT lastname AS n FROM person) s GROUP BY n")
+            .unwrap();
+    }
+
+    #[pg_test(error = "Anon: the masked column public.person.lastname is used in a WHERE clause")]
+    fn test_rewrite_inference_guard_cte() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run(
+            "
+            WITH s AS (SELECT firstname FROM person)
+            SELECT count(*) FROM s WHERE firstname LIKE 'S%'
+            ",
+        )
+        .unwrap();
+        Spi::run(
+            "
+            WITH s AS (SELECT lastname FROM person)
+            SELECT count(*) FROM s WHERE lastname LIKE 'C%'
+            ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "Anon: the masked column public.person.lastname is used in a WHERE clause")]
+    fn test_rewrite_inference_guard_outer_cte() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run(
+            "
+            WITH s AS (SELECT lastname FROM person)
+            SELECT 1 WHERE EXISTS (SELECT 1 FROM s WHERE lastname LIKE 'C%')
+            ",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "Anon: the masked column public.person.lastname is used in a WHERE clause")]
+    fn test_rewrite_inference_guard_whole_row() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run("SELECT count(*) FROM (SELECT lastname FROM person) s WHERE s::TEXT LIKE '%C%'")
+            .unwrap();
+    }
+
+    #[pg_test(error = "Anon: the masked column public.person.lastname is used in a WHERE clause")]
+    fn test_rewrite_inference_guard_view_column() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run(
+            "
+            RESET ROLE;
+            CREATE VIEW people AS SELECT firstname, lastname FROM person;
+            GRANT SELECT ON people TO batman;
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        Spi::run("SELECT count(*) FROM people WHERE firstname LIKE 'S%'").unwrap();
+        Spi::run("SELECT count(*) FROM people WHERE lastname LIKE 'C%'").unwrap();
+    }
+
+    #[pg_test(error = "Anon: the masked column public.person.lastname is used in a WHERE clause")]
+    fn test_rewrite_inference_guard_view_clause() {
+        Spi::run("SET anon.inference_guard TO 'anon:error'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run(
+            "
+            RESET ROLE;
+            CREATE VIEW connors AS SELECT firstname FROM person WHERE lastname LIKE 'C%';
+            GRANT SELECT ON connors TO batman;
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        Spi::run("SELECT count(*) FROM connors").unwrap();
+    }
+
+    #[pg_test]
+    fn test_rewrite_inference_guard_warning() {
+        Spi::run("SET anon.inference_guard TO 'anon:warning'").unwrap();
+        grant_person_to_batman(false);
+        Spi::run("SELECT count(*) FROM person WHERE lastname LIKE 'C%'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_rewrite_unmasked_relation() {
+        grant_person_to_batman(false);
+        Spi::run(
+            "
+            RESET ROLE;
+            SELECT anon.unmask_temporarily('batman', 'person', '1 hour', 'incident #42');
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        let lastname = Spi::get_one::<String>("SELECT lastname FROM person");
+        assert_eq!(lastname, Ok(Some("Connor".to_string())));
+    }
+
+    #[pg_test]
+    fn test_audit_relation() {
+        let relid = fixture::create_table_person();
+        let msq_query = cache::masking_subquery(relid, "anon".to_string()).unwrap();
+        let mut walker = TreeWalker::new(String::from("anon"));
+        unsafe { walker.audit_relation(relid, &msq_query, audit::Access::Masked) };
+        assert_eq!(walker.masked_relations(), vec![relid]);
+        assert!(walker.audited_relations[0].unmasked_columns.is_empty());
+
+        let mut walker = TreeWalker::new(String::from("anon"));
+        Spi::run("SET anon.audit_log TO 'terse'").unwrap();
+        unsafe {
+            walker.audit_relation(relid, &msq_query, audit::Access::Masked);
+            walker.audit_relation(relid, &msq_query, audit::Access::Masked);
+            walker.audit_relation(relid, &msq_query, audit::Access::Unmasked);
+        }
+        assert_eq!(walker.audited_relations.len(), 2);
+        let masked = &walker.audited_relations[0];
+        assert_eq!(masked.masked_columns, vec!["lastname"]);
+        assert_eq!(masked.unmasked_columns, vec!["firstname"]);
+        assert_eq!(masked.sampling, None);
+        let unmasked = &walker.audited_relations[1];
+        assert!(unmasked.masked_columns.is_empty());
+        assert_eq!(unmasked.unmasked_columns, vec!["firstname", "lastname"]);
+    }
+
+    #[pg_test(error = "Anon: role is masked")]
+    fn test_rewrite_write_refused() {
+        grant_person_to_batman(false);
+        Spi::run("UPDATE person SET firstname = 'John'").unwrap();
+    }
+
+    #[pg_test]
+    fn test_rewrite_write_through() {
+        grant_person_to_batman(true);
+        // The WHERE clause and the RETURNING list read the masked values
+        Spi::run("UPDATE person SET firstname = 'John' WHERE lastname = 'Connor'").unwrap();
+        let lastname = Spi::connect_mut(|client| {
+            client
+                .update(
+                    "UPDATE person SET firstname = 'John' WHERE lastname IS NULL RETURNING lastname",
+                    None,
+                    &[],
+                )
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap()
+        });
+        assert_eq!(lastname, None);
+        Spi::run("INSERT INTO person(firstname) VALUES ('Kyle')").unwrap();
+        Spi::run("DELETE FROM person WHERE firstname = 'Kyle'").unwrap();
+
+        Spi::run("RESET ROLE").unwrap();
+        let person = Spi::get_two::<String, String>("SELECT firstname::TEXT, lastname FROM person");
+        assert_eq!(
+            person.unwrap(),
+            (Some("John".to_string()), Some("Connor".to_string()))
+        );
+    }
+
+    #[cfg(not(feature = "pg13"))]
+    #[pg_test(error = "Anon: function public.lastnames reads the masked relation public.person")]
+    fn test_rewrite_sql_function_body() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            CREATE FUNCTION public.lastnames() RETURNS SETOF TEXT
+            LANGUAGE SQL
+            BEGIN ATOMIC
+              SELECT lastname FROM public.person;
+            END;
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT SELECT ON person TO batman;
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        Spi::run("SELECT * FROM public.lastnames()").unwrap();
+    }
+
+    #[cfg(not(feature = "pg13"))]
+    #[pg_test(error = "Anon: function public.is_lastname reads the masked relation public.person")]
+    fn test_rewrite_sql_function_body_operator() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SET anon.transparent_dynamic_masking TO TRUE;
+            CREATE FUNCTION public.is_lastname(TEXT, BOOLEAN) RETURNS BOOLEAN
+            LANGUAGE SQL
+            RETURN $1 IN (SELECT lastname FROM public.person);
+            CREATE OPERATOR public.=== (
+              LEFTARG = TEXT,
+              RIGHTARG = BOOLEAN,
+              FUNCTION = public.is_lastname
+            );
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT SELECT ON person TO batman;
+            SET ROLE batman;
+        ",
+        )
+        .unwrap();
+        Spi::run("SELECT 'Connor' OPERATOR(public.===) TRUE").unwrap();
+    }
+
+    #[pg_test(error = "new row violates row-level security policy for table \"person\"")]
+    fn test_rewrite_write_through_filter_check() {
+        fixture::create_table_person();
+        fixture::create_masked_role();
+        Spi::run(
+            "
+            SECURITY LABEL FOR anon ON TABLE person IS 'FILTER WITH firstname <> ''Kyle''';
+            SET anon.transparent_dynamic_masking TO TRUE;
+            SET anon.write_through_policies TO 'anon';
+            GRANT USAGE ON SCHEMA public TO batman;
+            GRANT ALL ON person TO batman;
+            SET ROLE batman;
+            INSERT INTO person(firstname) VALUES ('John');
+            UPDATE person SET firstname = 'Sarah J.' WHERE firstname = 'Sarah';
+        ",
+        )
+        .unwrap();
+        Spi::run("INSERT INTO person(firstname) VALUES ('Kyle')").unwrap();
+    }
+
+    #[pg_test(error = "Anon: column lastname is masked")]
+    fn test_rewrite_write_through_masked_column() {
+        grant_person_to_batman(true);
+        Spi::run("UPDATE person SET lastname = 'Reese'").unwrap();
+    }
 }
@@ -0,0 +1,99 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SET anon.aggregate_only_policies TO 'anon:3';
+
+CREATE TABLE employee (
+  id INT,
+  department TEXT,
+  zipcode TEXT,
+  salary INT
+);
+
+INSERT INTO employee VALUES
+(1, 'sales', '75001', 3000),
+(2, 'sales', '75002', 3200),
+(3, 'sales', '75002', 3400),
+(4, 'it', '75001', 4000),
+(5, 'it', '75003', 4200),
+(6, 'hr', '75001', 3800);
+
+SECURITY LABEL FOR anon ON COLUMN employee.salary
+  IS 'MASKED WITH VALUE 1000';
+
+CREATE TABLE visit (
+  zipcode TEXT,
+  age INT
+);
+
+INSERT INTO visit VALUES
+('75001', 31),
+('75001', 45),
+('75001', 52),
+('75002', 28);
+
+SECURITY LABEL FOR k_anonymity ON COLUMN visit.zipcode
+  IS 'INDIRECT IDENTIFIER';
+
+CREATE TABLE product (
+  id INT,
+  name TEXT
+);
+
+INSERT INTO product VALUES
+(1, 'chair'),
+(2, 'table');
+
+CREATE ROLE analyst;
+
+SECURITY LABEL FOR anon ON ROLE analyst IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO analyst;
+GRANT SELECT ON ALL TABLES IN SCHEMA public TO analyst;
+
+SET ROLE analyst;
+
+-- The groups with less than 3 rows are suppressed
+SELECT department, count(*), avg(salary)
+FROM employee
+GROUP BY department
+ORDER BY department;
+
+SELECT count(*) FROM employee WHERE id = 1;
+
+-- The relations with indirect identifiers are protected too
+SELECT zipcode, count(*)
+FROM visit
+GROUP BY zipcode
+ORDER BY zipcode;
+
+-- The other relations are not restricted
+SELECT * FROM product ORDER BY id;
+
+-- The individual rows are not revealed
+SAVEPOINT error_select_rows;
+SELECT * FROM employee;
+ROLLBACK TO error_select_rows;
+
+SAVEPOINT error_subquery;
+SELECT count(*), (SELECT max(zipcode) FROM visit) FROM product;
+ROLLBACK TO error_subquery;
+
+SAVEPOINT error_min;
+SELECT department, min(salary) FROM employee GROUP BY department;
+ROLLBACK TO error_min;
+
+SAVEPOINT error_window_function;
+SELECT id, count(*) OVER () FROM employee;
+ROLLBACK TO error_window_function;
+
+SAVEPOINT error_array_agg;
+SELECT department, array_agg(zipcode) FROM employee GROUP BY department;
+ROLLBACK TO error_array_agg;
+
+RESET ROLE;
+
+ROLLBACK;
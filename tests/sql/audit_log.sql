@@ -0,0 +1,49 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com'),
+(2, 'bob@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE support;
+
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO support;
+GRANT SELECT ON customer TO support;
+
+-- The verbosity is checked
+SAVEPOINT error_invalid_verbosity;
+SET anon.audit_log TO 'loud';
+ROLLBACK TO error_invalid_verbosity;
+
+-- The records are written in the server log, the client doesn't see them
+SET anon.audit_log TO 'terse';
+
+SET ROLE support;
+SELECT * FROM customer ORDER BY id;
+RESET ROLE;
+
+SET anon.audit_log TO 'verbose';
+
+SET ROLE support;
+SELECT email FROM customer WHERE id = 1;
+
+-- A masked role can't disable the audit log
+SAVEPOINT error_disable_audit_log;
+SET anon.audit_log TO 'off';
+ROLLBACK TO error_disable_audit_log;
+RESET ROLE;
+
+ROLLBACK;
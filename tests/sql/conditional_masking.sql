@@ -0,0 +1,56 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+CREATE TABLE account (
+  id INT,
+  login TEXT,
+  country TEXT,
+  email TEXT
+);
+
+INSERT INTO account VALUES
+  ( 1, 'admin', 'FR', 'admin@example.com'),
+  ( 2, 'alice', 'US', 'alice@example.com'),
+  ( 3, 'bob', NULL, 'bob@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN account.login
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$ UNLESS id = 1';
+
+SECURITY LABEL FOR anon ON COLUMN account.email
+  IS 'MASKED WITH FUNCTION anon.partial_email(email) WHEN country <> $$FR$$';
+
+-- Subqueries are not allowed
+SAVEPOINT sublink;
+SECURITY LABEL FOR anon ON COLUMN account.country
+  IS 'MASKED WITH VALUE NULL WHEN id IN (SELECT 1)';
+ROLLBACK TO sublink;
+
+-- Untrusted functions are not allowed
+SAVEPOINT untrusted;
+SECURITY LABEL FOR anon ON COLUMN account.country
+  IS 'MASKED WITH VALUE NULL WHEN public.elevate()';
+ROLLBACK TO untrusted;
+
+CREATE ROLE jack;
+
+GRANT SELECT ON TABLE account TO jack;
+
+SET anon.transparent_dynamic_masking TO true;
+
+SECURITY LABEL FOR anon ON ROLE jack IS 'MASKED';
+
+SET ROLE jack;
+
+-- The predicate is evaluated for each row
+-- When the predicate is NULL, the value is masked
+SELECT id, login, email FROM account ORDER BY id;
+
+RESET ROLE;
+
+-- Static Masking
+SELECT anon.anonymize_table('account');
+
+SELECT id, login, email FROM account ORDER BY id;
+
+ROLLBACK;
@@ -11,8 +11,10 @@ COMMENT ON COLUMN people.name
 IS 'MASKED WITH FUNCTION anon.fake_first_name()';
 
 --
+SET anon.strict_mode TO FALSE;
 SECURITY LABEL FOR anon ON COLUMN people.age
 IS 'MASKED WITH FUNCTION anon.random_date()';
+RESET anon.strict_mode;
 
 -- main syntax
 COMMENT ON COLUMN people.zipcode
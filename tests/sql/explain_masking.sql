@@ -0,0 +1,32 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+CREATE TABLE player (
+  id INT,
+  name TEXT,
+  points INT DEFAULT 0,
+  country TEXT
+);
+
+SECURITY LABEL FOR anon ON COLUMN player.name
+  IS 'MASKED WITH FUNCTION anon.dummy_last_name()';
+
+SECURITY LABEL FOR anon ON COLUMN player.country
+  IS 'MASKED WITH VALUE NULL WHEN id > 10';
+
+SECURITY LABEL FOR devtests ON COLUMN player.name
+  IS 'MASKED WITH VALUE $$x$$';
+
+SELECT * FROM anon.explain_masking('player'::REGCLASS);
+
+SELECT attname, kind, expression
+FROM anon.explain_masking('player'::REGCLASS, 'devtests');
+
+-- The columns without a rule are masked with their default value or NULL
+SET anon.privacy_by_default TO TRUE;
+
+SELECT attname, kind, expression
+FROM anon.explain_masking('player'::REGCLASS);
+
+ROLLBACK;
@@ -0,0 +1,72 @@
+-- This test relies on the following configuration
+--
+-- ALTER DATABASE contrib_regression
+--   SET anon.masking_policies = 'devtests, analytics';
+--
+
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon CASCADE;
+
+CREATE TABLE orders (
+  id INT,
+  region TEXT,
+  customer TEXT
+);
+
+INSERT INTO orders VALUES
+  ( 1, 'EU', 'Alice'),
+  ( 2, 'US', 'Bob'),
+  ( 3, 'EU', 'Carol'),
+  ( 4, NULL, 'Dave');
+
+-- Subqueries are not allowed
+SAVEPOINT sublink;
+SECURITY LABEL FOR anon ON TABLE orders
+  IS 'FILTER WITH id IN (SELECT 1)';
+ROLLBACK TO sublink;
+
+-- Untrusted functions are not allowed
+SAVEPOINT untrusted;
+SECURITY LABEL FOR anon ON TABLE orders
+  IS 'FILTER WITH public.elevate()';
+ROLLBACK TO untrusted;
+
+-- The filter can be different for each masking policy
+SECURITY LABEL FOR anon ON TABLE orders
+  IS 'FILTER WITH region = ''EU''';
+
+SECURITY LABEL FOR devtests ON TABLE orders
+  IS 'FILTER WITH id > 2';
+
+SECURITY LABEL FOR devtests ON COLUMN orders.customer
+  IS 'MASKED WITH VALUE ''CONFIDENTIAL''';
+
+CREATE ROLE eu_auditor;
+CREATE ROLE tester;
+
+GRANT SELECT ON TABLE orders TO eu_auditor, tester;
+
+SECURITY LABEL FOR anon ON ROLE eu_auditor IS 'MASKED';
+SECURITY LABEL FOR devtests ON ROLE tester IS 'MASKED';
+
+SET anon.transparent_dynamic_masking TO true;
+
+SET ROLE eu_auditor;
+
+SELECT * FROM orders ORDER BY id;
+
+RESET ROLE;
+
+SET ROLE tester;
+
+SELECT * FROM orders ORDER BY id;
+
+RESET ROLE;
+
+-- Static Masking
+SELECT anon.anonymize_table('orders'::REGCLASS, 'devtests');
+
+SELECT * FROM orders ORDER BY id;
+
+ROLLBACK;
@@ -0,0 +1,59 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com'),
+(2, 'bob@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE support;
+
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO support;
+GRANT SELECT ON customer TO support;
+
+SET ROLE support;
+SELECT * FROM customer ORDER BY id;
+SELECT * FROM customer ORDER BY id;
+RESET ROLE;
+
+-- The second query reused the masking subquery and the policy of the role
+SELECT cache, hits > 0 AS used
+FROM anon.cache_stats()
+WHERE cache IN ('subqueries', 'roles')
+ORDER BY cache;
+
+-- The masking rule is modified
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$REDACTED$$';
+
+SET ROLE support;
+SELECT * FROM customer ORDER BY id;
+RESET ROLE;
+
+-- The table is modified
+ALTER TABLE customer ADD COLUMN vip BOOLEAN DEFAULT FALSE;
+
+SET ROLE support;
+SELECT * FROM customer ORDER BY id;
+RESET ROLE;
+
+-- The role is not masked anymore
+SECURITY LABEL FOR anon ON ROLE support IS NULL;
+
+SET ROLE support;
+SELECT * FROM customer ORDER BY id;
+RESET ROLE;
+
+ROLLBACK;
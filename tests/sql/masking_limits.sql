@@ -0,0 +1,68 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer
+SELECT i, 'user' || i || '@example.com'
+FROM generate_series(1, 5) AS i;
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE support;
+
+-- The limits are checked when the label is declared
+SAVEPOINT error_invalid_row_limit;
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED WITH ROW LIMIT -1';
+ROLLBACK TO error_invalid_row_limit;
+
+SAVEPOINT error_invalid_statement_timeout;
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED WITH STATEMENT TIMEOUT 1parsec';
+ROLLBACK TO error_invalid_statement_timeout;
+
+SAVEPOINT error_missing_and;
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED WITH ROW LIMIT 3 STATEMENT TIMEOUT 1s';
+ROLLBACK TO error_missing_and;
+
+SECURITY LABEL FOR anon ON ROLE support
+  IS 'MASKED WITH ROW LIMIT 3 AND STATEMENT TIMEOUT 500ms';
+
+GRANT USAGE ON SCHEMA public TO support;
+GRANT SELECT ON customer TO support;
+
+SET ROLE support;
+
+-- A LIMIT clause is added
+SELECT * FROM customer ORDER BY id;
+
+-- A smaller limit is kept
+SELECT * FROM customer ORDER BY id LIMIT 2;
+
+-- Only the rows returned are limited
+SELECT count(*) FROM customer;
+
+-- The statement is canceled after the timeout
+SAVEPOINT error_statement_timeout;
+SELECT count(*), pg_catalog.pg_sleep(10) FROM customer;
+ROLLBACK TO error_statement_timeout;
+
+-- The statements that don't read a masked table are not limited
+SELECT pg_catalog.pg_sleep(1);
+
+RESET ROLE;
+
+-- The members of the masked role inherit its limits
+CREATE ROLE intern IN ROLE support;
+
+SET ROLE intern;
+SELECT id FROM customer ORDER BY id;
+RESET ROLE;
+
+ROLLBACK;
@@ -0,0 +1,96 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT PRIMARY KEY,
+  email TEXT,
+  status TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com', 'open'),
+(2, 'bob@example.com', 'open');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE TABLE inbox (
+  id INT,
+  email TEXT,
+  status TEXT
+);
+
+CREATE ROLE support;
+
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO support;
+GRANT ALL ON ALL TABLES IN SCHEMA public TO support;
+
+SET ROLE support;
+
+-- The source relation is masked
+MERGE INTO inbox i
+USING customer c ON i.id = c.id
+WHEN NOT MATCHED THEN
+  INSERT VALUES (c.id, c.email, c.status);
+
+SELECT * FROM inbox ORDER BY id;
+
+-- The target relation is masked and the role is read-only
+SAVEPOINT error_merge_into_masked_table;
+MERGE INTO customer c
+USING inbox i ON c.id = i.id
+WHEN MATCHED THEN
+  UPDATE SET status = 'closed';
+ROLLBACK TO error_merge_into_masked_table;
+
+RESET ROLE;
+
+SET anon.write_through_policies TO 'anon';
+
+SET ROLE support;
+
+-- The masked columns can't be written
+SAVEPOINT error_merge_into_masked_column;
+MERGE INTO customer c
+USING inbox i ON c.id = i.id
+WHEN MATCHED THEN
+  UPDATE SET email = i.email;
+ROLLBACK TO error_merge_into_masked_column;
+
+SAVEPOINT error_merge_insert_masked_column;
+MERGE INTO customer c
+USING (VALUES (3, 'eve@example.com')) AS v(id, email) ON c.id = v.id
+WHEN NOT MATCHED THEN
+  INSERT VALUES (v.id, v.email, 'open');
+ROLLBACK TO error_merge_insert_masked_column;
+
+-- The conditions of the actions read the masked values
+MERGE INTO customer c
+USING inbox i ON c.id = i.id
+WHEN MATCHED AND c.email = 'alice@example.com' THEN
+  UPDATE SET status = 'spam'
+WHEN MATCHED AND c.id = 1 THEN
+  UPDATE SET status = 'closed';
+
+-- The join condition reads the masked values
+MERGE INTO customer c
+USING (VALUES ('bob@example.com')) AS v(email) ON c.email = v.email
+WHEN MATCHED THEN
+  DELETE;
+
+-- The unmasked columns can be inserted
+MERGE INTO customer c
+USING (VALUES (3)) AS v(id) ON c.id = v.id
+WHEN NOT MATCHED THEN
+  INSERT (id, status) VALUES (v.id, 'new');
+
+RESET ROLE;
+
+SELECT * FROM customer ORDER BY id;
+
+ROLLBACK;
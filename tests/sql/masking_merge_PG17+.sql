@@ -0,0 +1,53 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SET anon.write_through_policies TO 'anon';
+
+CREATE TABLE customer (
+  id INT PRIMARY KEY,
+  email TEXT,
+  status TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com', 'open'),
+(2, 'bob@example.com', 'open'),
+(3, 'eve@example.com', 'open');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+SECURITY LABEL FOR anon ON TABLE customer
+  IS 'FILTER WITH id < 3';
+
+CREATE ROLE support;
+
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO support;
+GRANT ALL ON ALL TABLES IN SCHEMA public TO support;
+
+SET ROLE support;
+
+-- The RETURNING list reads the masked values
+MERGE INTO customer c
+USING (VALUES (1)) AS v(id) ON c.id = v.id
+WHEN MATCHED THEN
+  UPDATE SET status = 'closed'
+RETURNING c.id, c.email, c.status;
+
+-- The rows hidden by the filter are not modified
+MERGE INTO customer c
+USING (VALUES (1)) AS v(id) ON c.id = v.id
+WHEN NOT MATCHED BY SOURCE THEN
+  UPDATE SET status = 'archived'
+RETURNING c.id, c.email, c.status;
+
+RESET ROLE;
+
+SELECT * FROM customer ORDER BY id;
+
+ROLLBACK;
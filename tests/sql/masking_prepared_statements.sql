@@ -32,6 +32,58 @@ PREPARE max_value AS
 
 EXECUTE max_value;
 
+-- The cached plan is invalidated when a masking rule is modified
+RESET ROLE;
+
+SECURITY LABEL FOR anon ON COLUMN t.n
+  IS 'masked with VALUE 1';
+
+SET ROLE dumper;
+
+EXECUTE max_value;
+
+-- The cached plan is invalidated when the role is unmasked
+RESET ROLE;
+
+SECURITY LABEL FOR anon ON ROLE dumper IS NULL;
+
+SET ROLE dumper;
+
+EXECUTE max_value;
+
 DEALLOCATE PREPARE max_value;
 
+-- The cached plan is invalidated when a function is forbidden
+RESET ROLE;
+
+SECURITY LABEL FOR anon ON ROLE dumper IS 'masked';
+
+CREATE FUNCTION public.add_one(i INT) RETURNS INT
+LANGUAGE SQL AS $$ SELECT i + 1 $$;
+
+SET ROLE dumper;
+
+PREPARE max_value_plus_one AS
+  SELECT public.add_one(n)
+  FROM t
+  ORDER BY n DESC
+  LIMIT 1
+;
+
+EXECUTE max_value_plus_one;
+
+RESET ROLE;
+
+SECURITY LABEL FOR anon ON FUNCTION public.add_one(INT) IS 'FORBIDDEN';
+
+SET ROLE dumper;
+
+SAVEPOINT forbidden;
+
+EXECUTE max_value_plus_one;
+
+ROLLBACK TO SAVEPOINT forbidden;
+
+DEALLOCATE PREPARE max_value_plus_one;
+
 ROLLBACK;
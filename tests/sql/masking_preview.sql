@@ -0,0 +1,58 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com'),
+(2, 'bob@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE support;
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+CREATE ROLE analyst;
+
+GRANT USAGE ON SCHEMA public TO support, analyst;
+GRANT SELECT ON customer TO support, analyst;
+
+CREATE ROLE intern;
+SECURITY LABEL FOR anon ON ROLE intern IS 'MASKED';
+
+-- The transparent dynamic masking is required
+SAVEPOINT error_not_enabled;
+SELECT * FROM anon.simulate('SELECT * FROM customer', 'support');
+ROLLBACK TO error_not_enabled;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+-- The masked role gets the masked rows
+SELECT * FROM anon.simulate('SELECT * FROM customer ORDER BY id', 'support');
+
+-- The unmasked role gets the authentic rows
+SELECT * FROM anon.simulate('SELECT * FROM customer ORDER BY id', 'analyst');
+
+-- The current user is restored
+SELECT CURRENT_USER = SESSION_USER;
+
+-- The privileges of the role are checked
+SAVEPOINT error_permission_denied;
+SELECT * FROM anon.simulate('SELECT * FROM customer', 'intern');
+ROLLBACK TO error_permission_denied;
+
+-- Only a superuser can preview a masked role
+SET ROLE analyst;
+
+SAVEPOINT error_not_superuser;
+SELECT * FROM anon.simulate('SELECT * FROM customer', 'support');
+ROLLBACK TO error_not_superuser;
+
+RESET ROLE;
+
+ROLLBACK;
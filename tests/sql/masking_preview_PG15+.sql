@@ -0,0 +1,42 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE support;
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+CREATE ROLE analyst;
+
+-- The masked table is replaced by its masking subquery
+SELECT anon.rewrite_query('SELECT * FROM customer ORDER BY id', 'support');
+
+-- The query is not rewritten for an unmasked role
+SELECT anon.rewrite_query('SELECT * FROM customer ORDER BY id', 'analyst');
+
+-- Only one statement can be previewed
+SAVEPOINT error_several_statements;
+SELECT anon.rewrite_query('SELECT 1; SELECT 2', 'support');
+ROLLBACK TO error_several_statements;
+
+SAVEPOINT error_utility_statement;
+SELECT anon.rewrite_query('TRUNCATE customer', 'support');
+ROLLBACK TO error_utility_statement;
+
+-- Only a superuser can preview a masked role
+SET ROLE analyst;
+
+SAVEPOINT error_not_superuser;
+SELECT anon.rewrite_query('SELECT * FROM customer', 'support');
+ROLLBACK TO error_not_superuser;
+
+RESET ROLE;
+
+ROLLBACK;
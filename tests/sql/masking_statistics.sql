@@ -0,0 +1,68 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT,
+  zipcode TEXT
+);
+
+INSERT INTO customer
+SELECT i, 'user' || i % 5 || '@example.com', (i % 10)::TEXT
+FROM generate_series(1,100) i;
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE TABLE product (
+  id INT,
+  name TEXT
+);
+
+INSERT INTO product
+SELECT i, 'product ' || i
+FROM generate_series(1,10) i;
+
+ANALYZE customer;
+ANALYZE product;
+
+CREATE ROLE analyst;
+
+SECURITY LABEL FOR anon ON ROLE analyst IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO analyst;
+GRANT SELECT ON ALL TABLES IN SCHEMA public TO analyst;
+
+SELECT tablename, attname
+FROM pg_stats
+WHERE schemaname = 'public'
+ORDER BY tablename, attname;
+
+SET ROLE analyst;
+
+-- The statistics of the masked tables are hidden
+SELECT tablename, attname
+FROM pg_stats
+WHERE schemaname = 'public'
+ORDER BY tablename, attname;
+
+-- The most common values are not revealed
+SELECT count(*)
+FROM pg_catalog.pg_stats
+WHERE most_common_vals::TEXT LIKE '%@example.com%';
+
+-- The statistics catalogs are not readable
+SAVEPOINT error_read_pg_statistic;
+SELECT count(*) FROM pg_catalog.pg_statistic;
+ROLLBACK TO error_read_pg_statistic;
+
+SAVEPOINT error_read_pg_statistic_ext_data;
+SELECT count(*) FROM pg_catalog.pg_statistic_ext_data;
+ROLLBACK TO error_read_pg_statistic_ext_data;
+
+RESET ROLE;
+
+ROLLBACK;
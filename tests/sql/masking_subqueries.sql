@@ -0,0 +1,48 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+-- The unqualified functions are not trusted
+SET anon.restrict_to_trusted_schemas TO FALSE;
+
+CREATE SCHEMA one;
+CREATE SCHEMA two;
+
+CREATE FUNCTION one.mask() RETURNS TEXT LANGUAGE SQL AS $$ SELECT 'one' $$;
+CREATE FUNCTION two.mask() RETURNS TEXT LANGUAGE SQL AS $$ SELECT 'two' $$;
+
+CREATE TABLE account (
+  id INT,
+  login TEXT
+);
+
+INSERT INTO account VALUES
+(1, 'alice'),
+(2, 'bob');
+
+SECURITY LABEL FOR anon ON COLUMN account.login
+  IS 'MASKED WITH FUNCTION mask()';
+
+CREATE ROLE auditor;
+
+SECURITY LABEL FOR anon ON ROLE auditor IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public, one, two TO auditor;
+GRANT SELECT ON account TO auditor;
+
+-- The masking function is resolved with the search path of the query
+SET ROLE auditor;
+SET search_path TO one, public;
+SELECT * FROM account ORDER BY id;
+
+SET search_path TO two, public;
+SELECT * FROM account ORDER BY id;
+
+-- The cached subquery is used again
+SET search_path TO one, public;
+SELECT * FROM account ORDER BY id;
+RESET ROLE;
+
+ROLLBACK;
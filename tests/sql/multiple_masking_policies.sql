@@ -93,5 +93,30 @@ SELECT highest_score = 69 FROM nba.player WHERE id = 5;
 
 RESET ROLE;
 
+-- Alice is member of the "analysts" group. She inherits the masking policy
+-- of the group
+
+CREATE ROLE analysts;
+
+GRANT USAGE ON SCHEMA nba TO analysts;
+GRANT SELECT ON ALL TABLES IN SCHEMA nba TO analysts;
+
+SECURITY LABEL FOR analytics ON ROLE analysts IS 'MASKED';
+
+CREATE ROLE alice LOGIN IN ROLE analysts;
+
+SELECT anon.get_masking_policy('alice'::REGROLE);
+
+SET ROLE alice;
+
+SELECT name IS NULL FROM nba.player WHERE id = 5;
+
+RESET ROLE;
+
+-- A label on the role itself takes precedence over the inherited labels
+
+SECURITY LABEL FOR devtests ON ROLE alice IS 'MASKED';
+
+SELECT anon.get_masking_policy('alice'::REGROLE);
 
 ROLLBACK;
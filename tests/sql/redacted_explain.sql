@@ -0,0 +1,55 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com'),
+(2, 'bob@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE support;
+
+SECURITY LABEL FOR anon ON ROLE support IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO support;
+GRANT SELECT ON customer TO support;
+
+-- EXPLAIN is not allowed by default
+SET ROLE support;
+SAVEPOINT error_explain;
+EXPLAIN (COSTS OFF) SELECT * FROM customer WHERE id = 1;
+ROLLBACK TO error_explain;
+RESET ROLE;
+
+SET anon.redacted_explain TO TRUE;
+
+SET ROLE support;
+
+-- The expressions are removed from the plan
+EXPLAIN (COSTS OFF) SELECT * FROM customer WHERE id = 1;
+
+-- The query is masked before being planned
+EXPLAIN (COSTS OFF) SELECT * FROM customer WHERE email = 'alice@example.com';
+
+-- EXPLAIN ANALYZE would run the query
+SAVEPOINT error_explain_analyze;
+EXPLAIN ANALYZE SELECT * FROM customer;
+ROLLBACK TO error_explain_analyze;
+
+-- Only the SELECT statements can be explained
+SAVEPOINT error_explain_delete;
+EXPLAIN (COSTS OFF) DELETE FROM customer;
+ROLLBACK TO error_explain_delete;
+
+RESET ROLE;
+
+ROLLBACK;
@@ -68,6 +68,16 @@ SET ROLE jimmy;
 SELECT count(*) < 100 FROM hundred;
 RESET ROLE;
 
+-- With a REPEATABLE seed, each scan returns the same sample
+SECURITY LABEL FOR anon ON TABLE hundred
+IS 'TABLESAMPLE BERNOULLI(50) REPEATABLE(42)';
+
+SET ROLE jimmy;
+SELECT (SELECT count(*) FROM hundred) = (SELECT count(*) FROM hundred);
+RESET ROLE;
+
+SECURITY LABEL FOR anon ON TABLE hundred IS NULL;
+
 SET anon.transparent_dynamic_masking TO false;
 
 --
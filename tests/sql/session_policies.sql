@@ -0,0 +1,124 @@
+-- This test relies on the following configuration
+--
+-- ALTER DATABASE contrib_regression
+--   SET anon.masking_policies = 'devtests, analytics';
+--
+-- The session policy is resolved once per session, so this test opens a new
+-- session for each case and it can't run inside a transaction.
+--
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer VALUES (1, 'alice@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+SECURITY LABEL FOR devtests ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$DEVTESTS$$';
+
+-- The dashboard sessions are masked
+INSERT INTO anon.session_policy_rules(policy, application_name)
+VALUES ('anon', 'dashboard%');
+
+\c -reuse-previous=on "application_name=dashboard-1"
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SELECT anon.session_masking_policy();
+
+SELECT * FROM customer;
+
+-- The policy is resolved once per session
+SET application_name TO 'billing';
+
+SELECT * FROM customer;
+
+-- The billing sessions are not masked
+\c -reuse-previous=on "application_name=billing"
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SELECT anon.session_masking_policy() IS NULL;
+
+SELECT * FROM customer;
+
+-- The policy is resolved again when the rules are modified
+INSERT INTO anon.session_policy_rules(policy, application_name)
+VALUES ('anon', 'billing');
+
+SELECT anon.session_masking_policy();
+
+DELETE FROM anon.session_policy_rules WHERE application_name = 'billing';
+
+SELECT anon.session_masking_policy() IS NULL;
+
+-- The session tokens are signed by a superuser
+\c -reuse-previous=on "application_name=signing"
+
+SET anon.session_token_secret TO 'secret';
+
+SELECT anon.sign_session_token('devtests', session_user) AS token \gset
+
+SELECT anon.sign_session_token('devtests', session_user, now() - '1 second'::INTERVAL)
+  AS expired_token \gset
+
+SELECT anon.sign_session_token('billing', session_user) AS unknown_token \gset
+
+-- An invalid session token is ignored
+\c -reuse-previous=on "application_name=dashboard-2 options=-canon.session_token=devtests:0:not_a_signature"
+
+SET anon.session_token_secret TO 'secret';
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SELECT anon.session_masking_policy();
+
+-- An expired session token is ignored
+\set conninfo 'application_name=dashboard-3 options=-canon.session_token=' :expired_token
+
+\c -reuse-previous=on :conninfo
+
+SET anon.session_token_secret TO 'secret';
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SELECT anon.session_masking_policy();
+
+-- A session token selecting an unknown policy is ignored
+\set conninfo 'application_name=dashboard-5 options=-canon.session_token=' :unknown_token
+
+\c -reuse-previous=on :conninfo
+
+SET anon.session_token_secret TO 'secret';
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SELECT anon.session_masking_policy();
+
+-- A signed session token comes first
+\set conninfo 'application_name=dashboard-4 options=-canon.session_token=' :token
+
+\c -reuse-previous=on :conninfo
+
+SET anon.session_token_secret TO 'secret';
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+SELECT anon.session_masking_policy();
+
+SELECT * FROM customer;
+
+-- The session token can only be set when the connection is opened
+SET anon.session_token TO 'anon:0:not_a_signature';
+
+\c -reuse-previous=on "application_name=pg_regress"
+
+DROP TABLE customer;
+
+DROP EXTENSION anon;
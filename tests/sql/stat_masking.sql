@@ -0,0 +1,30 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+-- The statistics are restricted to the superusers
+CREATE ROLE analyst;
+
+SET ROLE analyst;
+
+SAVEPOINT error_select_stat_masking;
+SELECT * FROM anon.stat_masking;
+ROLLBACK TO error_select_stat_masking;
+
+SAVEPOINT error_stat_masking_reset;
+SELECT anon.stat_masking_reset();
+ROLLBACK TO error_stat_masking_reset;
+
+RESET ROLE;
+
+-- The statistics are allocated in shared memory, they are not available
+-- when the extension is loaded with session_preload_libraries
+SAVEPOINT error_not_enabled;
+SELECT * FROM anon.stat_masking;
+ROLLBACK TO error_not_enabled;
+
+SAVEPOINT error_reset_not_enabled;
+SELECT anon.stat_masking_reset();
+ROLLBACK TO error_reset_not_enabled;
+
+ROLLBACK;
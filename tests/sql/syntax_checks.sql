@@ -9,8 +9,10 @@ SECURITY LABEL FOR anon ON COLUMN people.name
 IS 'MaSKeD WiTH FuNCTioN anon.fake_last_name()';
 
 -- This is valid
+SET anon.strict_mode TO FALSE;
 SECURITY LABEL FOR anon ON COLUMN people.age
 IS 'MASKED     WITH    FUNCTION      anon.random_date()';
+RESET anon.strict_mode;
 
 -- This is correct
 SECURITY LABEL FOR anon ON ROLE batman IS 'MasKeD';
@@ -18,6 +20,24 @@ SECURITY LABEL FOR anon ON ROLE batman IS 'MasKeD';
 -- This is not valid
 SECURITY LABEL FOR anon ON ROLE batman IS 'maskeeeed';
 
+-- Comments are allowed between the keywords
+SECURITY LABEL FOR anon ON COLUMN people.zipcode
+IS 'MASKED /* zipcode */ WITH VALUE NULL';
+
+-- The error points at the invalid token
+SECURITY LABEL FOR anon ON COLUMN people.zipcode
+IS 'MASKED WIHT VALUE NULL';
+
+SECURITY LABEL FOR anon ON COLUMN people.zipcode
+IS 'MASKED WITH VALUE NULL WHEN';
+
+-- The mask must be cast into the type of the column when strict mode is on
+SECURITY LABEL FOR anon ON COLUMN people.age
+IS 'MASKED WITH FUNCTION anon.random_date()';
+
+-- The condition must be a boolean
+SECURITY LABEL FOR anon ON COLUMN people.zipcode
+IS 'MASKED WITH VALUE NULL WHEN 1';
 
 -- Clean up
 DROP TABLE people CASCADE;
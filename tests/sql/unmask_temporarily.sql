@@ -0,0 +1,63 @@
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS anon;
+
+SET anon.transparent_dynamic_masking TO TRUE;
+
+CREATE TABLE customer (
+  id INT,
+  email TEXT
+);
+
+INSERT INTO customer VALUES
+(1, 'alice@example.com'),
+(2, 'bob@example.com');
+
+SECURITY LABEL FOR anon ON COLUMN customer.email
+  IS 'MASKED WITH VALUE $$CONFIDENTIAL$$';
+
+CREATE ROLE oncall;
+
+SECURITY LABEL FOR anon ON ROLE oncall IS 'MASKED';
+
+GRANT USAGE ON SCHEMA public TO oncall;
+GRANT SELECT ON ALL TABLES IN SCHEMA public TO oncall;
+
+-- A reason is required
+SAVEPOINT error_no_reason;
+SELECT anon.unmask_temporarily('oncall', 'customer', '1 hour', '');
+ROLLBACK TO error_no_reason;
+
+-- The masked role can't unmask a table
+SET ROLE oncall;
+SAVEPOINT error_unmask_itself;
+SELECT anon.unmask_temporarily('oncall', 'customer', '1 hour', 'incident #42');
+ROLLBACK TO error_unmask_itself;
+RESET ROLE;
+
+SELECT anon.unmask_temporarily('oncall', 'customer', '1 hour', 'incident #42') > now();
+
+-- The authentic data is revealed
+SET ROLE oncall;
+SELECT * FROM customer ORDER BY id;
+PREPARE customer_emails AS SELECT email FROM customer ORDER BY id;
+EXECUTE customer_emails;
+RESET ROLE;
+
+-- The exemption expires
+UPDATE anon.unmasking SET expires_at = now() - '1 second'::INTERVAL;
+
+SET ROLE oncall;
+SELECT * FROM customer ORDER BY id;
+
+-- The plans built before the expiry are rejected
+SAVEPOINT error_expired_plan;
+EXECUTE customer_emails;
+ROLLBACK TO error_expired_plan;
+RESET ROLE;
+
+SELECT event, role_name, table_name, reason, query
+FROM anon.unmasking_audit
+ORDER BY event_time;
+
+ROLLBACK;